@@ -22,73 +22,544 @@
 //! ```
 //!
 
-use std::f64::consts::{E, PI};
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
 
 const EARTH_RADIUS: f64 = 6378137.0;
 const EARTH_CIRCUMFERENCE: f64 = 2. * PI * EARTH_RADIUS;
+pub(crate) const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+/// Default width and height of a rendered tile in pixels
+pub const TILE_SIZE: u32 = 256;
+
+/// The deepest zoom level considered a valid tile
+pub const MAX_ZOOM: u32 = 30;
+
+/// WMS protocol version, which determines the axis order of geographic bboxes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WmsVersion {
+    /// lon,lat axis order
+    V1_1_1,
+    /// lat,lon axis order, as mandated by EPSG:4326
+    V1_3_0,
+}
+
+/// Error parsing a Bing-style quadkey
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuadkeyError {
+    /// The quadkey contained a character other than '0'..='3'
+    InvalidDigit(char),
+    /// The quadkey is longer than `MAX_ZOOM` digits
+    TooLong(usize),
+}
+
+impl fmt::Display for QuadkeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuadkeyError::InvalidDigit(c) => {
+                write!(f, "invalid quadkey digit '{}', expected 0-3", c)
+            }
+            QuadkeyError::TooLong(len) => {
+                write!(
+                    f,
+                    "quadkey of length {} exceeds the maximum zoom of {}",
+                    len, MAX_ZOOM
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuadkeyError {}
+
+/// Spread the bits of a 32 bit integer into the even bits of a 64 bit integer
+fn spread_bits(v: u32) -> u64 {
+    let mut v = v as u64;
+    v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+    v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+    v
+}
+
+/// Inverse of `spread_bits`, gather the even bits of a 64 bit integer
+fn compact_bits(v: u64) -> u32 {
+    let mut v = v & 0x5555_5555_5555_5555;
+    v = (v | (v >> 1)) & 0x3333_3333_3333_3333;
+    v = (v | (v >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v >> 4)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v >> 8)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v >> 16)) & 0x0000_0000_FFFF_FFFF;
+    v as u32
+}
+
+/// A single coordinate, either epsg:4326 lon/lat degrees or epsg:3857 meters
+/// depending on context
+///
+/// ```
+/// use mapserver_rs::coordinates::Point;
+///
+/// let p = Point::new(-105., 40.);
+/// let merc = p.to_mercator();
+/// assert!((merc.x - -11688546.53).abs() < 0.01);
+///
+/// let rt = merc.to_wgs84();
+/// assert!((rt.x - p.x).abs() < 1e-6);
+/// assert!((rt.y - p.y).abs() < 1e-6);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Point {
+    pub fn new(x: f64, y: f64) -> Self {
+        Point { x, y }
+    }
+
+    /// Project a lon/lat point to spherical web mercator (epsg:4326 to epsg:3857)
+    pub fn to_mercator(&self) -> Self {
+        TileGrid::web_mercator().project(self.x, self.y)
+    }
+
+    /// Unproject a spherical web mercator point to lon/lat (epsg:3857 to epsg:4326)
+    pub fn to_wgs84(&self) -> Self {
+        TileGrid::web_mercator().unproject(*self)
+    }
+}
+
+/// A pyramid of square tiles over a spherical mercator projection. Zoom 0 is a single
+/// tile one circumference of the sphere across, each zoom level splits every tile into four.
+/// `Tile` methods such as `bbox_mercator` use `TileGrid::web_mercator`, other grids
+/// are used through their own methods
+///
+/// ```
+/// use mapserver_rs::coordinates::{Tile, TileGrid};
+///
+/// let grid = TileGrid::web_mercator();
+/// let tile = Tile::from_zxy(7, 26, 48);
+/// assert_eq!(grid.bbox(&tile), tile.bbox_mercator());
+/// assert_eq!(grid.tile_at(-105., 40., 7), tile);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TileGrid {
+    /// Projected (x, y) of the upper left corner of tile 0/0/0
+    pub origin: (f64, f64),
+    /// Radius of the sphere, in projected units
+    pub radius: f64,
+    /// Width and height of a rendered tile in pixels
+    pub tile_size: u32,
+}
+
+impl TileGrid {
+    /// The epsg:3857 grid of OpenStreetMap, Google and most web maps
+    pub const fn web_mercator() -> Self {
+        TileGrid {
+            origin: (-EARTH_CIRCUMFERENCE / 2., EARTH_CIRCUMFERENCE / 2.),
+            radius: EARTH_RADIUS,
+            tile_size: TILE_SIZE,
+        }
+    }
+
+    /// Width and height of a tile at a zoom level, in projected units
+    pub fn tile_span(&self, zoom: u32) -> f64 {
+        2. * PI * self.radius / (2.0f64).powf(zoom as f64)
+    }
+
+    /// Projected units per pixel of a tile at a zoom level
+    pub fn resolution(&self, zoom: u32) -> f64 {
+        self.tile_span(zoom) / self.tile_size as f64
+    }
+
+    /// Project a lon/lat point onto the grid's sphere
+    pub fn project(&self, lon: f64, lat: f64) -> Point {
+        let x = self.radius * lon.to_radians();
+        let y = self.radius * (PI / 4. + lat.to_radians() / 2.).tan().ln();
+        Point { x, y }
+    }
+
+    /// Unproject a point on the grid's sphere to lon/lat
+    pub fn unproject(&self, point: Point) -> Point {
+        let x = (point.x / self.radius).to_degrees();
+        let y = (2. * (point.y / self.radius).exp().atan() - PI / 2.).to_degrees();
+        Point { x, y }
+    }
+
+    /// Bounding coordinates (minx, miny, maxx, maxy) of a tile, in projected units
+    pub fn bbox(&self, tile: &Tile) -> (f64, f64, f64, f64) {
+        let span = self.tile_span(tile.zoom);
+        let llx = self.origin.0 + tile.x as f64 * span;
+        let ury = self.origin.1 - tile.y as f64 * span;
+        (llx, ury - span, llx + span, ury)
+    }
+
+    /// Fractional tile index of a projected coordinate at a given zoom level
+    pub fn tile_index(&self, x: f64, y: f64, zoom: u32) -> (f64, f64) {
+        let span = self.tile_span(zoom);
+        ((x - self.origin.0) / span, (self.origin.1 - y) / span)
+    }
+
+    /// The tile containing a longitude and latitude at a given zoom level, see
+    /// `Tile::from_coords` for how coordinates off the grid are handled
+    pub fn tile_at(&self, lon: f64, lat: f64, zoom: u32) -> Tile {
+        let lon = if (-180. ..=180.).contains(&lon) {
+            lon
+        } else {
+            (lon + 180.).rem_euclid(360.) - 180.
+        };
+        let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE);
+        let point = self.project(lon, lat);
+        let (xtile, ytile) = self.tile_index(point.x, point.y, zoom);
+
+        let max = (2.0f64).powf(zoom as f64) - 1.;
+        Tile {
+            x: xtile.floor().clamp(0., max) as u32,
+            y: ytile.floor().clamp(0., max) as u32,
+            zoom,
+        }
+    }
+}
+
+impl Default for TileGrid {
+    fn default() -> Self {
+        TileGrid::web_mercator()
+    }
+}
+
+/// Fractional tile index of an epsg:3857 coordinate at a given zoom level
+fn mercator_to_tile_index(x: f64, y: f64, zoom: u32) -> (f64, f64) {
+    TileGrid::web_mercator().tile_index(x, y, zoom)
+}
+
+/// The (xmin, ymin, xmax, ymax) tile indices, inclusive, of the tiles at a given zoom level
+/// intersecting an epsg:3857 bounding box, clamped to the grid.
+/// Tiles that only touch the max edges of the box are excluded
+pub(crate) fn tile_range(bbox: (f64, f64, f64, f64), zoom: u32) -> (u32, u32, u32, u32) {
+    // Tolerate floating point noise when a bbox edge lies exactly on a tile edge
+    const EPSILON: f64 = 1e-9;
+
+    let (minx, miny, maxx, maxy) = bbox;
+    let max = (2.0f64).powf(zoom as f64) - 1.;
+
+    let (xtile, ytile) = mercator_to_tile_index(minx, maxy, zoom);
+    let xmin = (xtile + EPSILON).floor().clamp(0., max) as u32;
+    let ymin = (ytile + EPSILON).floor().clamp(0., max) as u32;
+
+    // The lower right corner is exclusive, unless the bbox is degenerate
+    let (xtile, ytile) = mercator_to_tile_index(maxx, miny, zoom);
+    let xmax = ((xtile - EPSILON).ceil() - 1.).clamp(0., max) as u32;
+    let ymax = ((ytile - EPSILON).ceil() - 1.).clamp(0., max) as u32;
+
+    (xmin, ymin, xmax.max(xmin), ymax.max(ymin))
+}
+
+/// All tiles at a given zoom level intersecting an epsg:3857 bounding box.
+/// Tiles that only touch the max edges of the box are excluded
+pub fn tiles_in_bbox(bbox: (f64, f64, f64, f64), zoom: u32) -> Vec<Tile> {
+    let (xmin, ymin, xmax, ymax) = tile_range(bbox, zoom);
+    let mut tiles = vec![];
+    for y in ymin..=ymax {
+        for x in xmin..=xmax {
+            tiles.push(Tile { x, y, zoom });
+        }
+    }
+    tiles
+}
+
+/// Error constructing a `Tile` outside of the tile pyramid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TileError {
+    /// The zoom level is greater than `MAX_ZOOM`
+    ZoomOutOfRange(u32),
+    /// The x or y index is not less than 2^zoom
+    IndexOutOfRange { zoom: u32, x: u32, y: u32 },
+}
+
+impl fmt::Display for TileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileError::ZoomOutOfRange(zoom) => {
+                write!(f, "zoom {} exceeds the maximum zoom of {}", zoom, MAX_ZOOM)
+            }
+            TileError::IndexOutOfRange { zoom, x, y } => write!(
+                f,
+                "tile {}/{}/{} is out of range, max index at zoom {} is {}",
+                zoom,
+                x,
+                y,
+                zoom,
+                (1u64 << zoom) - 1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TileError {}
+
+/// Error parsing a `Tile` from a "z/x/y" string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TileParseError {
+    /// The string did not have exactly three '/' separated parts
+    WrongPartCount(usize),
+    /// One of the parts was not a non-negative integer
+    InvalidIndex(String),
+}
+
+impl fmt::Display for TileParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TileParseError::WrongPartCount(n) => {
+                write!(f, "expected a tile as z/x/y, got {} parts", n)
+            }
+            TileParseError::InvalidIndex(s) => write!(f, "invalid tile index '{}'", s),
+        }
+    }
+}
+
+impl std::error::Error for TileParseError {}
+
+/// Order of the tiles returned by `Tile::children_ordered`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChildOrder {
+    /// Zooming in level by level from the parent, e.g. for streaming to a client
+    ParentFirst,
+    /// Zooming out level by level to the parent, e.g. for building overviews
+    ParentLast,
+}
 
 /// A Web Mercator ZXY tile
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct Tile {
     pub x: u32,
     pub y: u32,
     pub zoom: u32,
 }
 
+/// Tiles are ordered by zoom, then x, then y
+impl Ord for Tile {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.zoom, self.x, self.y).cmp(&(other.zoom, other.x, other.y))
+    }
+}
+
+impl PartialOrd for Tile {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Tile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.zoom, self.x, self.y)
+    }
+}
+
+/// Parse a tile from the "z/x/y" form
+///
+/// ```
+/// use mapserver_rs::coordinates::Tile;
+///
+/// let t: Tile = "7/26/48".parse().unwrap();
+/// assert_eq!(t, Tile::from_zxy(7, 26, 48));
+/// ```
+impl FromStr for Tile {
+    type Err = TileParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.len() != 3 {
+            return Err(TileParseError::WrongPartCount(parts.len()));
+        }
+        let index = |part: &str| {
+            part.trim()
+                .parse::<u32>()
+                .map_err(|_| TileParseError::InvalidIndex(part.to_string()))
+        };
+        Ok(Tile::from_zxy(
+            index(parts[0])?,
+            index(parts[1])?,
+            index(parts[2])?,
+        ))
+    }
+}
+
 impl Tile {
     pub fn from_zxy(z: u32, x: u32, y: u32) -> Self {
         Tile { x, y, zoom: z }
     }
 
+    /// Construct a tile, rejecting indices outside of the pyramid at that zoom
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// assert!(Tile::try_from_zxy(7, 26, 48).is_ok());
+    /// assert!(Tile::try_from_zxy(7, 128, 48).is_err());
+    /// ```
+    pub fn try_from_zxy(z: u32, x: u32, y: u32) -> Result<Self, TileError> {
+        let tile = Tile::from_zxy(z, x, y);
+        if z > MAX_ZOOM {
+            return Err(TileError::ZoomOutOfRange(z));
+        }
+        if !tile.is_valid() {
+            return Err(TileError::IndexOutOfRange { zoom: z, x, y });
+        }
+        Ok(tile)
+    }
+
+    /// True if the zoom is at most `MAX_ZOOM` and x and y are less than 2^zoom
+    pub fn is_valid(&self) -> bool {
+        if self.zoom > MAX_ZOOM {
+            return false;
+        }
+        let max = 1u32 << self.zoom;
+        self.x < max && self.y < max
+    }
+
     /// Convert a longitude and latitude to the bounding Tile
-    /// at a given zoom level
+    /// at a given zoom level.
+    /// Longitudes outside of -180..=180 wrap around the antimeridian, so 200 is -160,
+    /// and latitudes are clamped to the web mercator limit of ±85.0511°
     pub fn from_coords(lon: f64, lat: f64, zoom: u32) -> Self {
-        let latsin = lat.to_radians().sin();
-        let z2: f64 = (2.0f64).powf(zoom as f64);
-
-        // Normalize
-        let x = 0.5 + lon / 360.;
-        let y = 0.5 - 0.25 * ((1. + latsin) / (1. - latsin)).log(E) / std::f64::consts::PI;
-
-        // X Tile
-        let xtile = if x <= 0. {
-            0
-        } else if x >= 1. {
-            z2 as u32 - 1
-        } else {
-            (x * z2).floor() as u32
-        };
+        TileGrid::web_mercator().tile_at(lon, lat, zoom)
+    }
 
-        // Y Tile
-        let ytile = if y <= 0. {
-            0
-        } else if y >= 1. {
-            z2 as u32 - 1
-        } else {
-            (y * z2).floor() as u32
-        };
+    /// Convert zxy to bounding coordinates of tile in epsg:3857
+    pub fn bbox_mercator(&self) -> (f64, f64, f64, f64) {
+        TileGrid::web_mercator().bbox(self)
+    }
 
+    /// Geographic (lon, lat) center of the tile
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let (lon, lat) = Tile::from_zxy(0, 0, 0).center();
+    /// assert!(lon.abs() < 1e-9);
+    /// assert!(lat.abs() < 1e-9);
+    /// ```
+    pub fn center(&self) -> (f64, f64) {
+        let (minx, miny, maxx, maxy) = self.bbox_wgs84();
+        ((minx + maxx) / 2., (miny + maxy) / 2.)
+    }
+
+    /// Test if a geographic coordinate falls within the tile.
+    /// Bounds are half-open `[min, max)` so points on a shared edge
+    /// belong to exactly one tile
+    pub fn contains(&self, lon: f64, lat: f64) -> bool {
+        if !(-MAX_LATITUDE..=MAX_LATITUDE).contains(&lat) {
+            return false;
+        }
+        let (minx, miny, maxx, maxy) = self.bbox_wgs84();
+        (minx..maxx).contains(&lon) && (miny..maxy).contains(&lat)
+    }
+
+    /// Flip the y axis to the TMS convention, where y increases northward
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_zxy(7, 26, 48);
+    /// assert_eq!(t.to_tms().y, 79);
+    /// ```
+    pub fn to_tms(&self) -> Self {
         Tile {
-            x: xtile,
-            y: ytile,
-            zoom,
+            x: self.x,
+            y: ((1u64 << self.zoom) - 1).saturating_sub(self.y as u64) as u32,
+            zoom: self.zoom,
         }
     }
 
-    /// Convert zxy to bounding coordinates of tile in epsg:3857
-    pub fn bbox_mercator(&self) -> (f64, f64, f64, f64) {
-        let tile_size = EARTH_CIRCUMFERENCE / (2.0f64).powf(self.zoom as f64);
+    /// Ground resolution of the tile in meters per pixel,
+    /// given the rendered tile size in pixels
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let res = Tile::from_zxy(0, 0, 0).resolution(256);
+    /// assert!((res - 156543.03).abs() < 0.01);
+    /// ```
+    pub fn resolution(&self, tile_size: u32) -> f64 {
+        TileGrid {
+            tile_size,
+            ..TileGrid::web_mercator()
+        }
+        .resolution(self.zoom)
+    }
+
+    /// Convert zxy to bounding coordinates of tile in epsg:4326
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let (minx, miny, maxx, maxy) = Tile::from_zxy(7, 26, 48).bbox_wgs84();
+    /// assert!((minx - -106.875).abs() < 0.01);
+    /// assert!((miny - 38.82).abs() < 0.01);
+    /// assert!((maxx - -104.06).abs() < 0.01);
+    /// assert!((maxy - 40.98).abs() < 0.01);
+    /// ```
+    pub fn bbox_wgs84(&self) -> (f64, f64, f64, f64) {
+        let (llx, lly, urx, ury) = self.bbox_mercator();
+        let ll = Point::new(llx, lly).to_wgs84();
+        let ur = Point::new(urx, ury).to_wgs84();
 
-        let llx = self.x as f64 * tile_size - (EARTH_CIRCUMFERENCE / 2.);
-        let urx = llx + tile_size;
-        let ury = (EARTH_CIRCUMFERENCE / 2.) - self.y as f64 * tile_size;
-        let lly = ury - tile_size;
+        (ll.x, ll.y, ur.x, ur.y)
+    }
 
-        (llx, lly, urx, ury)
+    /// Geographic (lon, lat) of a pixel within the tile, rendered at `tile_size` pixels.
+    /// Pixel (0, 0) is the upper left corner and py increases downward, southward,
+    /// so (tile_size, tile_size) is the lower right corner. Pixels are interpolated
+    /// in web mercator, so rows are evenly spaced on the map rather than in latitude
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let (lon, lat) = Tile::from_zxy(0, 0, 0).pixel_to_lonlat(128., 128., 256);
+    /// assert!(lon.abs() < 1e-9 && lat.abs() < 1e-9);
+    /// ```
+    pub fn pixel_to_lonlat(&self, px: f64, py: f64, tile_size: u32) -> (f64, f64) {
+        let (minx, _, _, maxy) = self.bbox_mercator();
+        let resolution = self.resolution(tile_size);
+        let point = Point::new(minx + px * resolution, maxy - py * resolution).to_wgs84();
+        (point.x, point.y)
     }
 
+    /// Fractional (px, py) pixel of a geographic (lon, lat) within the tile, rendered at
+    /// `tile_size` pixels, the inverse of `pixel_to_lonlat`. Points outside of the tile are
+    /// not clamped, they fall outside of 0..tile_size, e.g. a negative py north of the tile
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let (px, py) = Tile::from_zxy(1, 1, 0).lonlat_to_pixel(0., 0., 256);
+    /// assert!(px.abs() < 1e-9 && (py - 256.).abs() < 1e-9);
+    /// ```
+    pub fn lonlat_to_pixel(&self, lon: f64, lat: f64, tile_size: u32) -> (f64, f64) {
+        let (minx, _, _, maxy) = self.bbox_mercator();
+        let resolution = self.resolution(tile_size);
+        let point = Point::new(lon, lat).to_mercator();
+        ((point.x - minx) / resolution, (maxy - point.y) / resolution)
+    }
+
+    /// Fill in a url template with `{z}`, `{x}` and `{y}` placeholders.
+    /// Use `{-y}` for the TMS (flipped) y axis
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_zxy(7, 26, 48);
+    /// assert_eq!(t.url_zyx("/{z}/{x}/{y}.png".into()), "/7/26/48.png");
+    /// assert_eq!(t.url_zyx("/{z}/{x}/{-y}.png".into()), "/7/26/79.png");
+    /// ```
     pub fn url_zyx(&self, template: String) -> String {
         let mut url = template;
+        url = url.replace("{-y}", self.to_tms().y.to_string().as_ref());
         url = url.replace("{x}", self.x.to_string().as_ref());
         url = url.replace("{y}", self.y.to_string().as_ref());
         url = url.replace("{z}", self.zoom.to_string().as_ref());
@@ -105,48 +576,446 @@ impl Tile {
         url
     }
 
+    /// Get the parent `Tile` one zoom level up.
+    /// Returns `None` for the root tile at zoom 0
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_zxy(7, 26, 48);
+    /// let parent = t.parent().unwrap();
+    /// assert_eq!(parent.zoom, 6);
+    /// assert_eq!(parent.x, 13);
+    /// assert_eq!(parent.y, 24);
+    ///
+    /// assert!(Tile::from_zxy(0, 0, 0).parent().is_none());
+    /// ```
+    pub fn parent(&self) -> Option<Self> {
+        if self.zoom == 0 {
+            return None;
+        }
+        Some(Tile {
+            x: self.x / 2,
+            y: self.y / 2,
+            zoom: self.zoom - 1,
+        })
+    }
+
+    /// Get the ancestor `Tile` at an arbitrary lower zoom level.
+    /// Returns `None` if the target zoom is greater than the tile's zoom
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_zxy(7, 26, 48);
+    /// let ancestor = t.parent_at(4).unwrap();
+    /// assert_eq!(ancestor.zoom, 4);
+    /// assert_eq!(ancestor.x, 3);
+    /// assert_eq!(ancestor.y, 6);
+    ///
+    /// // Parent at the same zoom is the tile itself
+    /// assert_eq!(t.parent_at(7).unwrap().x, 26);
+    /// assert!(t.parent_at(8).is_none());
+    /// ```
+    pub fn parent_at(&self, zoom: u32) -> Option<Self> {
+        if zoom > self.zoom {
+            return None;
+        }
+        let mut tile = self.clone();
+        while tile.zoom > zoom {
+            tile = tile.parent()?;
+        }
+        Some(tile)
+    }
+
+    /// Convert to a Bing Maps quadkey, one digit per zoom level.
+    /// Digits for zoom levels beyond the 32 bits of x and y are '0'
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_zxy(3, 3, 5);
+    /// assert_eq!(t.to_quadkey(), "213");
+    /// ```
+    pub fn to_quadkey(&self) -> String {
+        let mut quadkey = String::with_capacity(self.zoom as usize);
+        for z in (1..=self.zoom).rev() {
+            let mask = 1u32.checked_shl(z - 1).unwrap_or(0);
+            let mut digit = b'0';
+            if self.x & mask != 0 {
+                digit += 1;
+            }
+            if self.y & mask != 0 {
+                digit += 2;
+            }
+            quadkey.push(digit as char);
+        }
+        quadkey
+    }
+
+    /// Parse a Bing Maps quadkey, the zoom is the length of the quadkey
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_quadkey("213").unwrap();
+    /// assert_eq!(t.zoom, 3);
+    /// assert_eq!(t.x, 3);
+    /// assert_eq!(t.y, 5);
+    ///
+    /// assert!(Tile::from_quadkey("2140").is_err());
+    /// ```
+    pub fn from_quadkey(qk: &str) -> Result<Self, QuadkeyError> {
+        let zoom = qk.chars().count();
+        if zoom > MAX_ZOOM as usize {
+            return Err(QuadkeyError::TooLong(zoom));
+        }
+        let mut x = 0;
+        let mut y = 0;
+        for c in qk.chars() {
+            x <<= 1;
+            y <<= 1;
+            match c {
+                '0' => {}
+                '1' => x |= 1,
+                '2' => y |= 1,
+                '3' => {
+                    x |= 1;
+                    y |= 1;
+                }
+                _ => return Err(QuadkeyError::InvalidDigit(c)),
+            }
+        }
+        Ok(Tile {
+            x,
+            y,
+            zoom: zoom as u32,
+        })
+    }
+
+    /// Fill in a WMS url template with an epsg:4326 bbox.
+    /// Replaces `{bbox}`, `{srs}`, `{crs}`, `{width}` and `{height}` placeholders.
+    /// Tiles never cross the antimeridian so the bbox is always min to max
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::{Tile, WmsVersion};
+    ///
+    /// // WMS 1.3.0 puts latitude first, so the third value is max lat
+    /// let t = Tile::from_zxy(1, 1, 0);
+    /// let url = t.url_wms_4326("CRS={crs}&BBOX={bbox}".into(), WmsVersion::V1_3_0);
+    /// assert!(url.starts_with("CRS=EPSG:4326&BBOX=0,0,85.05"));
+    /// ```
+    pub fn url_wms_4326(&self, template: String, version: WmsVersion) -> String {
+        let (minx, miny, maxx, maxy) = self.bbox_wgs84();
+        let bbox = match version {
+            WmsVersion::V1_1_1 => format!("{},{},{},{}", minx, miny, maxx, maxy),
+            WmsVersion::V1_3_0 => format!("{},{},{},{}", miny, minx, maxy, maxx),
+        };
+
+        let mut url = template;
+        url = url.replace("{bbox}", &bbox);
+        url = url.replace("{srs}", "EPSG:4326");
+        url = url.replace("{crs}", "EPSG:4326");
+        url = url.replace("{width}", TILE_SIZE.to_string().as_ref());
+        url = url.replace("{height}", TILE_SIZE.to_string().as_ref());
+        url
+    }
+
+    /// Morton (Z-order) code interleaving the bits of x and y,
+    /// x in the even bits and y in the odd bits.
+    /// Tiles close in space tend to be close in code
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let t = Tile::from_zxy(2, 3, 1);
+    /// assert_eq!(t.morton(), 0b0111);
+    /// assert_eq!(Tile::from_morton(0b0111, 2), t);
+    /// ```
+    pub fn morton(&self) -> u64 {
+        spread_bits(self.x) | (spread_bits(self.y) << 1)
+    }
+
+    /// Build a tile from its Morton (Z-order) code at a given zoom level
+    pub fn from_morton(code: u64, zoom: u32) -> Self {
+        Tile {
+            x: compact_bits(code),
+            y: compact_bits(code >> 1),
+            zoom,
+        }
+    }
+
+    /// Get the other tiles sharing the same parent quad.
+    /// Unlike `neighbors`, this is defined by the pyramid rather than adjacency
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// assert_eq!(Tile::from_zxy(7, 26, 48).siblings().len(), 3);
+    /// assert!(Tile::from_zxy(0, 0, 0).siblings().is_empty());
+    /// ```
+    pub fn siblings(&self) -> Vec<Self> {
+        let parent = match self.parent() {
+            Some(parent) => parent,
+            None => return vec![],
+        };
+        let mut tiles = Vec::with_capacity(3);
+        for y in [parent.y * 2, parent.y * 2 + 1] {
+            for x in [parent.x * 2, parent.x * 2 + 1] {
+                if x != self.x || y != self.y {
+                    tiles.push(Tile {
+                        x,
+                        y,
+                        zoom: self.zoom,
+                    });
+                }
+            }
+        }
+        tiles
+    }
+
+    /// Get the up to 8 adjacent tiles at the same zoom level.
+    /// Tiles beyond the edge of the world are omitted rather than wrapped
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// // A corner tile only has 3 neighbors
+    /// let corner = Tile::from_zxy(2, 0, 0);
+    /// assert_eq!(corner.neighbors().len(), 3);
+    ///
+    /// let t = Tile::from_zxy(7, 26, 48);
+    /// assert_eq!(t.neighbors().len(), 8);
+    /// ```
+    pub fn neighbors(&self) -> Vec<Self> {
+        self.adjacent(false)
+    }
+
+    /// Get the adjacent tiles at the same zoom level,
+    /// wrapping the x-axis around the antimeridian.
+    /// The y-axis is still clamped at the poles
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let corner = Tile::from_zxy(2, 0, 0);
+    /// let neighbors = corner.neighbors_wrapping();
+    /// assert_eq!(neighbors.len(), 5);
+    /// assert!(neighbors.iter().any(|t| t.x == 3 && t.y == 0));
+    /// ```
+    pub fn neighbors_wrapping(&self) -> Vec<Self> {
+        self.adjacent(true)
+    }
+
+    fn adjacent(&self, wrap_x: bool) -> Vec<Self> {
+        let max = (1i64 << self.zoom) - 1;
+        let mut tiles = Vec::with_capacity(8);
+        for dy in -1i64..=1 {
+            for dx in -1i64..=1 {
+                if dx == 0 && dy == 0 {
+                    continue;
+                }
+                let y = self.y as i64 + dy;
+                if y < 0 || y > max {
+                    continue;
+                }
+                let mut x = self.x as i64 + dx;
+                if wrap_x {
+                    x = x.rem_euclid(max + 1);
+                } else if x < 0 || x > max {
+                    continue;
+                }
+                // At low zooms wrapping can land back on ourselves or a duplicate
+                if x == self.x as i64 && y == self.y as i64 {
+                    continue;
+                }
+                if tiles
+                    .iter()
+                    .any(|t: &Tile| t.x as i64 == x && t.y as i64 == y)
+                {
+                    continue;
+                }
+                tiles.push(Tile {
+                    x: x as u32,
+                    y: y as u32,
+                    zoom: self.zoom,
+                });
+            }
+        }
+        tiles
+    }
+
     /// Get all children of the parent `Tile`.
     /// In reverse order, graudally zooms out
     /// Final element includes the parent tile
     pub fn children(&self, target_zoom: u32) -> Vec<Self> {
-        let metatile = Tile {
-            x: self.x,
-            y: self.y,
-            zoom: self.zoom,
-        };
-        let mut tiles = vec![metatile];
-        // Iterate over tiles repeatedly, breaking each tile into four for the next zoom level
-        // TODO might be more efficient with recursion
+        self.children_ordered(target_zoom, ChildOrder::ParentLast)
+    }
+
+    /// Get the parent `Tile` and all its children down to `target_zoom`, level by level.
+    /// Within a level, each tile's four children are listed clockwise from the top left
+    pub fn children_ordered(&self, target_zoom: u32, order: ChildOrder) -> Vec<Self> {
+        let delta = target_zoom.saturating_sub(self.zoom);
+        let capacity: usize = (0..=delta).map(|k| 4usize.pow(k)).sum();
+        let mut tiles = Vec::with_capacity(capacity);
+        tiles.push(self.clone());
+
+        // Expand level by level, the frontier is the range of tiles at the current zoom,
+        // breaking each tile into four for the next zoom level
+        let mut frontier = 0..1;
         for z in self.zoom..target_zoom {
-            for t in tiles.clone().iter() {
-                if t.zoom == z {
-                    tiles.push(Tile {
-                        x: t.x * 2,
-                        y: t.y * 2,
-                        zoom: z + 1,
-                    });
-                    tiles.push(Tile {
-                        x: t.x * 2 + 1,
-                        y: t.y * 2,
-                        zoom: z + 1,
-                    });
-                    tiles.push(Tile {
-                        x: t.x * 2 + 1,
-                        y: t.y * 2 + 1,
-                        zoom: z + 1,
-                    });
-                    tiles.push(Tile {
-                        x: t.x * 2,
-                        y: t.y * 2 + 1,
-                        zoom: z + 1,
-                    });
-                }
+            let next_start = tiles.len();
+            for i in frontier {
+                let (x, y) = (tiles[i].x, tiles[i].y);
+                tiles.push(Tile {
+                    x: x * 2,
+                    y: y * 2,
+                    zoom: z + 1,
+                });
+                tiles.push(Tile {
+                    x: x * 2 + 1,
+                    y: y * 2,
+                    zoom: z + 1,
+                });
+                tiles.push(Tile {
+                    x: x * 2 + 1,
+                    y: y * 2 + 1,
+                    zoom: z + 1,
+                });
+                tiles.push(Tile {
+                    x: x * 2,
+                    y: y * 2 + 1,
+                    zoom: z + 1,
+                });
             }
+            frontier = next_start..tiles.len();
         }
 
-        tiles.reverse();
+        if order == ChildOrder::ParentLast {
+            tiles.reverse();
+        }
         tiles
     }
+
+    /// Number of tiles across the metatile containing this tile. Requested sizes are
+    /// clamped to the width of the pyramid at low zooms
+    pub fn metatile_size(&self, tiles_across: u32) -> u32 {
+        let across = tiles_across.max(1) as u64;
+        across.min(1u64 << self.zoom.min(MAX_ZOOM)) as u32
+    }
+
+    /// Tiles of the metatile containing this tile, row by row from the top left.
+    /// Metatiles are aligned to multiples of `tiles_across`
+    ///
+    /// ```
+    /// use mapserver_rs::coordinates::Tile;
+    ///
+    /// let tiles = Tile::from_zxy(7, 27, 49).metatile_tiles(2);
+    /// assert_eq!(tiles[0], Tile::from_zxy(7, 26, 48));
+    /// assert_eq!(tiles[3], Tile::from_zxy(7, 27, 49));
+    /// ```
+    pub fn metatile_tiles(&self, tiles_across: u32) -> Vec<Self> {
+        let across = self.metatile_size(tiles_across);
+        let (x0, y0) = (self.x - self.x % across, self.y - self.y % across);
+        let mut tiles = Vec::with_capacity((across * across) as usize);
+        for y in y0..y0 + across {
+            for x in x0..x0 + across {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    zoom: self.zoom,
+                });
+            }
+        }
+        tiles
+    }
+
+    /// Bounding coordinates in epsg:3857 of the metatile containing this tile,
+    /// the union of its `metatile_tiles`
+    pub fn metatile_extent(&self, tiles_across: u32) -> (f64, f64, f64, f64) {
+        let tiles = self.metatile_tiles(tiles_across);
+        let (minx, _, _, maxy) = tiles[0].bbox_mercator();
+        let (_, miny, maxx, _) = tiles[tiles.len() - 1].bbox_mercator();
+        (minx, miny, maxx, maxy)
+    }
+}
+
+/// A ZXY tile in the epsg:4326 (WGS84) grid, e.g. the WMTS EPSG:4326 TileMatrixSet.
+/// The world is 2 tiles across and 1 down at zoom 0, so every tile covers
+/// an equal number of degrees in longitude and latitude
+///
+/// ```
+/// use mapserver_rs::coordinates::GeoTile;
+///
+/// let t = GeoTile::from_coords(-105., 40., 7);
+/// assert_eq!((t.x, t.y), (53, 35));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct GeoTile {
+    pub x: u32,
+    pub y: u32,
+    pub zoom: u32,
+}
+
+impl fmt::Display for GeoTile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", self.zoom, self.x, self.y)
+    }
+}
+
+impl GeoTile {
+    pub fn from_zxy(z: u32, x: u32, y: u32) -> Self {
+        GeoTile { x, y, zoom: z }
+    }
+
+    /// True if the zoom is at most `MAX_ZOOM`, x is less than 2^(zoom + 1)
+    /// and y is less than 2^zoom
+    pub fn is_valid(&self) -> bool {
+        if self.zoom > MAX_ZOOM {
+            return false;
+        }
+        let rows = 1u64 << self.zoom;
+        (self.x as u64) < rows * 2 && (self.y as u64) < rows
+    }
+
+    /// Degrees covered by each side of a tile at a zoom level
+    fn degrees(zoom: u32) -> f64 {
+        180. / (2.0f64).powf(zoom as f64)
+    }
+
+    /// Convert a longitude and latitude to the bounding GeoTile at a given zoom level.
+    /// Coordinates beyond the edges of the world are clamped
+    pub fn from_coords(lon: f64, lat: f64, zoom: u32) -> Self {
+        let degrees = Self::degrees(zoom);
+        let rows = (2.0f64).powf(zoom as f64);
+
+        let x = ((lon + 180.) / degrees).floor().clamp(0., rows * 2. - 1.);
+        let y = ((90. - lat) / degrees).floor().clamp(0., rows - 1.);
+        GeoTile {
+            x: x as u32,
+            y: y as u32,
+            zoom,
+        }
+    }
+
+    /// Convert zxy to bounding coordinates of tile in epsg:4326
+    pub fn bbox_wgs84(&self) -> (f64, f64, f64, f64) {
+        let degrees = Self::degrees(self.zoom);
+        let minx = self.x as f64 * degrees - 180.;
+        let maxy = 90. - self.y as f64 * degrees;
+
+        (minx, maxy - degrees, minx + degrees, maxy)
+    }
+
+    /// Get all children of the parent `GeoTile`, in the same order as `Tile::children`.
+    /// Below zoom 0 each tile splits into four, just like web mercator tiles
+    pub fn children(&self, target_zoom: u32) -> Vec<Self> {
+        Tile::from_zxy(self.zoom, self.x, self.y)
+            .children(target_zoom)
+            .into_iter()
+            .map(|tile| GeoTile::from_zxy(tile.zoom, tile.x, tile.y))
+            .collect()
+    }
 }
 
 mod test {
@@ -158,4 +1027,470 @@ mod test {
         assert_eq!(t.x, 26);
         assert_eq!(t.y, 48);
     }
+
+    #[test]
+    fn test_from_coords_wraps() {
+        // 255° east is 105° west
+        assert_eq!(
+            super::Tile::from_coords(255., 40., 7),
+            super::Tile::from_zxy(7, 26, 48)
+        );
+        assert_eq!(
+            super::Tile::from_coords(-465., 40., 7),
+            super::Tile::from_zxy(7, 26, 48)
+        );
+        // 200° east is 160° west, not clamped to the eastern edge
+        assert_eq!(super::Tile::from_coords(200., 0., 1).x, 0);
+        // The antimeridian itself is still the last column
+        assert_eq!(super::Tile::from_coords(180., 0., 1).x, 1);
+        assert_eq!(super::Tile::from_coords(-180., 0., 1).x, 0);
+    }
+
+    #[test]
+    fn test_from_coords_beyond_mercator() {
+        // Beyond the mercator limit is the top or bottom row, not folded back over the pole
+        assert_eq!(super::Tile::from_coords(-105., 100., 7).y, 0);
+        assert_eq!(super::Tile::from_coords(-105., 89., 7).y, 0);
+        assert_eq!(super::Tile::from_coords(-105., -100., 7).y, 127);
+        assert_eq!(
+            super::Tile::from_coords(-105., 90., 3),
+            super::Tile::from_coords(-105., super::MAX_LATITUDE, 3)
+        );
+    }
+
+    #[test]
+    fn test_custom_grid() {
+        use std::f64::consts::PI;
+
+        // A unit sphere with 512px tiles, and its origin at the prime meridian
+        let grid = super::TileGrid {
+            origin: (0., PI),
+            radius: 1.,
+            tile_size: 512,
+        };
+        let tile = super::Tile::from_zxy(1, 1, 1);
+        assert_eq!(grid.bbox(&tile), (PI, -PI, 2. * PI, 0.));
+        assert_eq!(grid.resolution(1), PI / 512.);
+
+        // Projected onto the unit sphere, so the grid starts at 0° and spans 360°
+        let point = grid.project(90., 0.);
+        assert!((point.x - PI / 2.).abs() < 1e-12);
+        let lonlat = grid.unproject(point);
+        assert!((lonlat.x - 90.).abs() < 1e-9);
+        assert_eq!(grid.tile_at(90., 10., 1), super::Tile::from_zxy(1, 0, 0));
+        assert_eq!(grid.tile_at(-90., -10., 1), super::Tile::from_zxy(1, 0, 1));
+
+        // The same tiles as web mercator once the origin is shifted back
+        let shifted = super::TileGrid {
+            origin: (-PI, PI),
+            ..grid
+        };
+        for (lon, lat) in [(-105., 40.), (151.2, -33.9), (0.5, 0.5)] {
+            assert_eq!(
+                shifted.tile_at(lon, lat, 9),
+                super::Tile::from_coords(lon, lat, 9)
+            );
+        }
+        assert_eq!(super::TileGrid::default(), super::TileGrid::web_mercator());
+    }
+
+    #[test]
+    fn test_pixel_to_lonlat() {
+        let t = super::Tile::from_zxy(7, 26, 48);
+        let (minx, miny, maxx, maxy) = t.bbox_wgs84();
+
+        let (lon, lat) = t.pixel_to_lonlat(0., 0., 256);
+        assert!((lon - minx).abs() < 1e-9);
+        assert!((lat - maxy).abs() < 1e-9);
+
+        let (lon, lat) = t.pixel_to_lonlat(256., 256., 256);
+        assert!((lon - maxx).abs() < 1e-9);
+        assert!((lat - miny).abs() < 1e-9);
+
+        // The same corner at retina size
+        let (lon, lat) = t.pixel_to_lonlat(512., 512., 512);
+        assert!((lon - maxx).abs() < 1e-9);
+        assert!((lat - miny).abs() < 1e-9);
+
+        // Halfway down in mercator is north of halfway in latitude
+        let (_, lat) = t.pixel_to_lonlat(128., 128., 256);
+        assert!(lat > (miny + maxy) / 2.);
+    }
+
+    #[test]
+    fn test_lonlat_to_pixel() {
+        let t = super::Tile::from_zxy(7, 26, 48);
+        for (px, py) in [(0., 0.), (256., 256.), (17.25, 200.5), (-40., 300.)] {
+            let (lon, lat) = t.pixel_to_lonlat(px, py, 256);
+            let (rx, ry) = t.lonlat_to_pixel(lon, lat, 256);
+            assert!((rx - px).abs() < 1e-6, "{} != {}", rx, px);
+            assert!((ry - py).abs() < 1e-6, "{} != {}", ry, py);
+        }
+
+        // Denver is inside the tile, Salt Lake City to the west and Santa Fe to the south
+        let (px, py) = t.lonlat_to_pixel(-104.99, 39.74, 256);
+        assert!((0. ..256.).contains(&px) && (0. ..256.).contains(&py));
+        let (px, _) = t.lonlat_to_pixel(-111.89, 40.76, 256);
+        assert!(px < 0.);
+        let (_, py) = t.lonlat_to_pixel(-105.94, 35.69, 256);
+        assert!(py > 256.);
+    }
+
+    #[test]
+    fn test_tiles_in_bbox() {
+        let tiles = super::tiles_in_bbox((-1000., -1000., 1000., 1000.), 2);
+        let xy: Vec<(u32, u32)> = tiles.iter().map(|t| (t.x, t.y)).collect();
+        assert_eq!(xy, vec![(1, 1), (2, 1), (1, 2), (2, 2)]);
+        assert!(tiles.iter().all(|t| t.zoom == 2));
+
+        // Degenerate bbox is a single tile
+        let tiles = super::tiles_in_bbox((1000., 1000., 1000., 1000.), 2);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!((tiles[0].x, tiles[0].y), (2, 1));
+
+        // Exactly one tile, touching edges are excluded
+        let tile = super::Tile::from_zxy(7, 26, 48);
+        let tiles = super::tiles_in_bbox(tile.bbox_mercator(), 7);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!((tiles[0].x, tiles[0].y), (26, 48));
+
+        // Beyond the edges of the world is clamped
+        let tiles = super::tiles_in_bbox((-1e8, -1e8, 1e8, 1e8), 1);
+        assert_eq!(tiles.len(), 4);
+    }
+
+    #[test]
+    fn test_contains() {
+        let t = super::Tile::from_zxy(7, 26, 48);
+        assert!(t.contains(-105., 40.));
+        assert!(!t.contains(-100., 40.));
+
+        // Shared edges belong to the tile with the edge as its minimum
+        let nw = super::Tile::from_zxy(1, 0, 0);
+        let ne = super::Tile::from_zxy(1, 1, 0);
+        let sw = super::Tile::from_zxy(1, 0, 1);
+        assert!(!nw.contains(0., 40.));
+        assert!(ne.contains(0., 40.));
+        assert!(nw.contains(-90., 0.));
+        assert!(!sw.contains(-90., 0.));
+
+        // Poles are outside the mercator grid
+        let root = super::Tile::from_zxy(0, 0, 0);
+        assert!(root.contains(0., 0.));
+        assert!(!root.contains(0., 90.));
+        assert!(!root.contains(0., -90.));
+    }
+
+    #[test]
+    fn test_tile_hash_dedup() {
+        let mut set = std::collections::HashSet::new();
+        set.insert(super::Tile::from_zxy(7, 26, 48));
+        set.insert(super::Tile::from_zxy(7, 26, 48));
+        set.insert(super::Tile::from_zxy(7, 48, 26));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&super::Tile::from_coords(-105., 40., 7)));
+    }
+
+    #[test]
+    fn test_tile_sort() {
+        let mut tiles = vec![
+            super::Tile::from_zxy(2, 1, 0),
+            super::Tile::from_zxy(1, 1, 1),
+            super::Tile::from_zxy(2, 0, 3),
+            super::Tile::from_zxy(2, 0, 1),
+        ];
+        tiles.sort();
+        assert_eq!(
+            tiles,
+            vec![
+                super::Tile::from_zxy(1, 1, 1),
+                super::Tile::from_zxy(2, 0, 1),
+                super::Tile::from_zxy(2, 0, 3),
+                super::Tile::from_zxy(2, 1, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tile_from_str() {
+        let t = super::Tile::from_zxy(7, 26, 48);
+        let parsed: super::Tile = t.to_string().parse().unwrap();
+        assert_eq!(parsed, t);
+
+        assert_eq!(
+            "7/26".parse::<super::Tile>().unwrap_err(),
+            super::TileParseError::WrongPartCount(2)
+        );
+        assert_eq!(
+            "7/26/48/1".parse::<super::Tile>().unwrap_err(),
+            super::TileParseError::WrongPartCount(4)
+        );
+        assert_eq!(
+            "7/-1/48".parse::<super::Tile>().unwrap_err(),
+            super::TileParseError::InvalidIndex("-1".into())
+        );
+        assert!("".parse::<super::Tile>().is_err());
+    }
+
+    #[test]
+    fn test_children_ordering() {
+        let t = super::Tile::from_zxy(0, 0, 0);
+        let expected: Vec<super::Tile> =
+            vec![(1, 0, 1), (1, 1, 1), (1, 1, 0), (1, 0, 0), (0, 0, 0)]
+                .into_iter()
+                .map(|(z, x, y)| super::Tile::from_zxy(z, x, y))
+                .collect();
+        assert_eq!(t.children(1), expected);
+        assert_eq!(t.children(0), vec![t.clone()]);
+    }
+
+    #[test]
+    fn test_children_count() {
+        let t = super::Tile::from_zxy(7, 26, 48);
+        let children = t.children(15);
+        let expected: usize = (0..=8).map(|k| 4usize.pow(k)).sum();
+        assert_eq!(children.len(), expected);
+        assert_eq!(children.last(), Some(&t));
+        assert!(children[..4usize.pow(8)].iter().all(|c| c.zoom == 15));
+    }
+
+    #[test]
+    fn test_children_ordered() {
+        use super::ChildOrder;
+
+        let t = super::Tile::from_zxy(7, 26, 48);
+        let parent_first = t.children_ordered(9, ChildOrder::ParentFirst);
+        let parent_last = t.children_ordered(9, ChildOrder::ParentLast);
+        assert_eq!(parent_first.len(), t.children(9).len());
+        assert_eq!(parent_last, t.children(9));
+
+        assert_eq!(parent_first.first(), Some(&t));
+        assert_eq!(parent_first[1], super::Tile::from_zxy(8, 52, 96));
+        assert_eq!(
+            parent_first.last(),
+            Some(&super::Tile::from_zxy(9, 104, 195))
+        );
+
+        assert_eq!(
+            parent_last.first(),
+            Some(&super::Tile::from_zxy(9, 104, 195))
+        );
+        assert_eq!(parent_last.last(), Some(&t));
+
+        // Zooms never decrease top-down
+        assert!(parent_first.windows(2).all(|w| w[0].zoom <= w[1].zoom));
+    }
+
+    #[test]
+    fn test_siblings() {
+        let siblings = super::Tile::from_zxy(2, 3, 3).siblings();
+        let expected: Vec<super::Tile> = vec![(2, 2, 2), (2, 3, 2), (2, 2, 3)]
+            .into_iter()
+            .map(|(z, x, y)| super::Tile::from_zxy(z, x, y))
+            .collect();
+        assert_eq!(siblings, expected);
+    }
+
+    #[test]
+    fn test_try_from_zxy() {
+        assert!(super::Tile::try_from_zxy(10, 1023, 1023).is_ok());
+        assert_eq!(
+            super::Tile::try_from_zxy(10, 2000, 0).unwrap_err(),
+            super::TileError::IndexOutOfRange {
+                zoom: 10,
+                x: 2000,
+                y: 0
+            }
+        );
+        assert!(super::Tile::try_from_zxy(10, 0, 1024).is_err());
+        assert_eq!(
+            super::Tile::try_from_zxy(31, 0, 0).unwrap_err(),
+            super::TileError::ZoomOutOfRange(31)
+        );
+        assert!(!super::Tile::from_zxy(10, 2000, 0).is_valid());
+    }
+
+    #[test]
+    fn test_point_roundtrip() {
+        for lon in [-180., -105., 0., 0.5, 179.9] {
+            for lat in [-85., -40., 0., 40., 85.] {
+                let p = super::Point::new(lon, lat);
+                let rt = p.to_mercator().to_wgs84();
+                assert!((rt.x - p.x).abs() < 1e-6);
+                assert!((rt.y - p.y).abs() < 1e-6);
+            }
+        }
+
+        // The corner of the world in mercator
+        let corner = super::Point::new(180., super::MAX_LATITUDE).to_mercator();
+        assert!((corner.x - super::EARTH_CIRCUMFERENCE / 2.).abs() < 1e-6);
+        assert!((corner.y - super::EARTH_CIRCUMFERENCE / 2.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_url_wms_4326() {
+        // North east quadrant of the world
+        let t = super::Tile::from_zxy(1, 1, 0);
+        let (minx, miny, maxx, maxy) = t.bbox_wgs84();
+        assert_eq!((minx, miny), (0., 0.));
+        let template = "/wms?SRS={srs}&BBOX={bbox}&WIDTH={width}&HEIGHT={height}".to_string();
+
+        let url = t.url_wms_4326(template.clone(), super::WmsVersion::V1_1_1);
+        assert_eq!(
+            url,
+            format!(
+                "/wms?SRS=EPSG:4326&BBOX=0,0,{},{}&WIDTH=256&HEIGHT=256",
+                maxx, maxy
+            )
+        );
+
+        let url = t.url_wms_4326(template, super::WmsVersion::V1_3_0);
+        assert_eq!(
+            url,
+            format!(
+                "/wms?SRS=EPSG:4326&BBOX=0,0,{},{}&WIDTH=256&HEIGHT=256",
+                maxy, maxx
+            )
+        );
+    }
+
+    #[test]
+    fn test_morton_roundtrip() {
+        for zoom in [0, 1, 5, 16, 30] {
+            let max = (1u32 << zoom) - 1;
+            let indices = [0, 1, max / 3, max / 2, max.saturating_sub(1), max];
+            for x in indices {
+                for y in indices {
+                    let t = super::Tile::from_zxy(zoom, x, y);
+                    assert_eq!(super::Tile::from_morton(t.morton(), t.zoom), t);
+                }
+            }
+        }
+
+        // Max index at zoom 30 fills all 60 bits
+        let t = super::Tile::from_zxy(30, (1 << 30) - 1, (1 << 30) - 1);
+        assert_eq!(t.morton(), (1u64 << 60) - 1);
+    }
+
+    #[test]
+    fn test_quadkey_roundtrip() {
+        for zoom in 0..8 {
+            let max = 1 << zoom;
+            for x in 0..max {
+                for y in 0..max {
+                    let t = super::Tile::from_zxy(zoom, x, y);
+                    let qk = t.to_quadkey();
+                    assert_eq!(qk.len(), zoom as usize);
+                    let rt = super::Tile::from_quadkey(&qk).unwrap();
+                    assert_eq!((rt.zoom, rt.x, rt.y), (t.zoom, t.x, t.y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_quadkey_invalid() {
+        assert_eq!(
+            super::Tile::from_quadkey("0124").unwrap_err(),
+            super::QuadkeyError::InvalidDigit('4')
+        );
+        assert_eq!(
+            super::Tile::from_quadkey("01a").unwrap_err(),
+            super::QuadkeyError::InvalidDigit('a')
+        );
+        let max = "3".repeat(super::MAX_ZOOM as usize);
+        let t = super::Tile::from_quadkey(&max).unwrap();
+        assert_eq!((t.zoom, t.x, t.y), (30, (1 << 30) - 1, (1 << 30) - 1));
+        assert_eq!(t.to_quadkey(), max);
+        assert_eq!(
+            super::Tile::from_quadkey(&"0".repeat(31)).unwrap_err(),
+            super::QuadkeyError::TooLong(31)
+        );
+
+        // Zooms too deep for the pyramid still convert, without overflowing the mask
+        let deep = super::Tile::from_zxy(40, 1, 0).to_quadkey();
+        assert_eq!(deep, format!("{}1", "0".repeat(39)));
+    }
+
+    #[test]
+    fn test_metatile_extent() {
+        let t = super::Tile::from_zxy(7, 27, 49);
+        let tiles = t.metatile_tiles(2);
+        assert_eq!(tiles.len(), 4);
+        assert!(tiles.contains(&t));
+
+        // The union of the four tiles is the same area as their parent
+        let parent = t.parent().unwrap().bbox_mercator();
+        let extent = t.metatile_extent(2);
+        for (a, b) in [
+            (extent.0, parent.0),
+            (extent.1, parent.1),
+            (extent.2, parent.2),
+            (extent.3, parent.3),
+        ] {
+            assert!((a - b).abs() < 1e-6);
+        }
+
+        // Low zooms are clamped to the whole pyramid
+        let t = super::Tile::from_zxy(1, 1, 0);
+        assert_eq!(t.metatile_size(8), 2);
+        assert_eq!(t.metatile_tiles(8).len(), 4);
+    }
+
+    #[test]
+    fn test_geotile_zoom_0() {
+        // Two tiles across, one down
+        let west = super::GeoTile::from_coords(-90., 0., 0);
+        let east = super::GeoTile::from_coords(90., 0., 0);
+        assert_eq!((west.x, west.y), (0, 0));
+        assert_eq!((east.x, east.y), (1, 0));
+        assert!(super::GeoTile::from_zxy(0, 1, 0).is_valid());
+        assert!(!super::GeoTile::from_zxy(0, 2, 0).is_valid());
+        assert!(!super::GeoTile::from_zxy(0, 0, 1).is_valid());
+
+        assert_eq!(west.bbox_wgs84(), (-180., -90., 0., 90.));
+        assert_eq!(east.bbox_wgs84(), (0., -90., 180., 90.));
+
+        // Clamped at the edges of the world
+        let corner = super::GeoTile::from_coords(180., -90., 0);
+        assert_eq!((corner.x, corner.y), (1, 0));
+    }
+
+    #[test]
+    fn test_geotile_bbox() {
+        // 45 degree tiles at zoom 2, 8 across and 4 down
+        let t = super::GeoTile::from_coords(-105., 40., 2);
+        assert_eq!((t.x, t.y), (1, 1));
+        assert_eq!(t.bbox_wgs84(), (-135., 0., -90., 45.));
+
+        let t = super::GeoTile::from_zxy(2, 7, 3);
+        assert_eq!(t.bbox_wgs84(), (135., -90., 180., -45.));
+        assert!(t.is_valid());
+
+        // The point is inside the bbox of its tile at every zoom
+        for zoom in 0..12 {
+            let (minx, miny, maxx, maxy) =
+                super::GeoTile::from_coords(-105., 40., zoom).bbox_wgs84();
+            assert!(minx <= -105. && -105. < maxx);
+            assert!(miny < 40. && 40. <= maxy);
+        }
+    }
+
+    #[test]
+    fn test_geotile_children() {
+        let children = super::GeoTile::from_zxy(0, 1, 0).children(2);
+        assert_eq!(children.len(), 21);
+        assert_eq!(children[20], super::GeoTile::from_zxy(0, 1, 0));
+        assert!(children.iter().all(|t| t.is_valid()));
+
+        // Children cover the parent exactly
+        let zoom_1: Vec<_> = children.iter().filter(|t| t.zoom == 1).collect();
+        let (minx, miny, maxx, maxy) = zoom_1
+            .iter()
+            .map(|t| t.bbox_wgs84())
+            .fold((f64::MAX, f64::MAX, f64::MIN, f64::MIN), |a, b| {
+                (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3))
+            });
+        assert_eq!((minx, miny, maxx, maxy), (0., -90., 180., 90.));
+    }
 }
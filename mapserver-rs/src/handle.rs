@@ -0,0 +1,1371 @@
+//! Safe wrappers over the mapserver objects this crate uses.
+//!
+//! Every call into libmapserver goes through this module, so the unsafe FFI is kept in
+//! one place. A `MapHandle` owns a `mapObj` and frees it on drop. Images and output
+//! formats borrow the map they came from, so they can never outlive it.
+//!
+//! Failures are returned with the messages mapserver pushed onto its error stack,
+//! which is kept per thread. The stack is reset before each call that can fail.
+//!
+//! Mapserver mutates the `mapObj` on most calls, e.g. `set_extent` and `set_size`,
+//! but these take `&self`: a `MapHandle` holds a raw pointer so it is never `Sync`,
+//! and no two calls can touch the same `mapObj` at once. It is `Send`, so a map can be
+//! handed to the thread that will use it.
+//!
+//! Mapserver's process-wide state is set up by `ensure_initialized`, once, before the
+//! first map is loaded, along with the PROJ data directory from `set_proj_data`. While
+//! maps come and go, `cleanup_globals` releases the GDAL, OGR and PROJ state they opened,
+//! which mapserver sets up again on demand. Only `cleanup` tears down everything, after
+//! which mapserver may not be used again.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+
+use libc;
+
+use mapserver_sys::{
+    bufferObj, imageObj, layerObj, mapObj, msApplyMapConfigOptions, msBufferFree, msCleanup,
+    msDebugCleanup, msDrawMap, msFree, msFreeImage, msFreeMap, msFreeProjection, msFreeShape,
+    msGDALCleanup, msGetConfigOption, msGetErrorObj, msGetErrorString, msGetProjectionString,
+    msIO_Cleanup, msIO_getStdoutBufferBytes, msIO_installStdoutToBuffer, msIO_resetHandlers,
+    msInitQuery, msInitShape, msLayerClose, msLayerGetExtent, msLayerGetItems,
+    msLayerGetProcessingKey, msLayerGetShape, msLayerOpen, msLayerSetProcessingKey,
+    msLoadMapFromString, msLoadProjectionString, msMVTWriteTile, msMapSetExtent, msMapSetSize,
+    msOGRCleanup, msOGRWriteFromQuery, msProjectionContextPoolCleanup, msQueryByPoint,
+    msQueryByRect, msResetErrorList, msSaveImageBuffer, msSaveRasterBufferToBuffer,
+    msSelectOutputFormat, msSetConfigOption, msSetPROJ_DATA, msSetup, outputFormatObj,
+    rasterBufferObj, rectObj, rgbaArrayObj, shapeObj, GetMapserverUnitUsingProj,
+};
+
+use super::Extent;
+
+// Return codes and buffer types from mapserver.h
+const MS_SUCCESS: i32 = 0;
+const MS_NOERR: i32 = 0;
+const MS_TYPEERR: i32 = 3;
+const MS_SYMERR: i32 = 4;
+const MS_IDENTERR: i32 = 9;
+const MS_EOFERR: i32 = 10;
+const MS_NOTFOUND: i32 = 18;
+const MS_PARSEERR: i32 = 20;
+const MS_QUERY_BY_POINT: i32 = 1;
+const MS_QUERY_BY_RECT: i32 = 2;
+const MS_QUERY_MULTIPLE: i32 = 1;
+const MS_BUFFER_BYTE_RGBA: i32 = 2001;
+const MS_FALSE: i32 = 0;
+const MS_OFF: i32 = 0;
+const MS_ON: i32 = 1;
+const MS_LAYER_RASTER: u32 = 3;
+const MS_SHAPEFILE: u32 = 1;
+const MS_TILED_SHAPEFILE: u32 = 2;
+const MS_RASTER: u32 = 12;
+const MS_IMAGEMODE_RGB: i32 = 1;
+const MS_IMAGEMODE_RGBA: i32 = 2;
+
+const EMPTY_FEATURE_COLLECTION: &[u8] = br#"{"type":"FeatureCollection","features":[]}"#;
+
+///
+/// An owned mapserver mapObj, freed with msFreeMap when dropped
+///
+#[derive(Debug)]
+pub struct MapHandle(NonNull<mapObj>);
+
+impl MapHandle {
+    /// Parse a mapfile, with mapserver's error message if it is rejected.
+    /// An interior nul can never be a valid mapfile
+    pub fn load_from_string(mapfile_contents: &str) -> Result<Self, String> {
+        Self::load_from_string_with_retry(mapfile_contents, &LoadRetry::default())
+    }
+
+    /// Parse a mapfile, retrying loads that fail for reasons other than mapfile syntax,
+    /// e.g. a remote data source that could not be initialized on the first try.
+    /// The error message is from the final attempt. Mapfiles with DATA paths that
+    /// cannot be resolved, see `relative_data_paths`, are rejected without retrying
+    pub fn load_from_string_with_retry(
+        mapfile_contents: &str,
+        retry: &LoadRetry,
+    ) -> Result<Self, String> {
+        Self::load(mapfile_contents, None, retry)
+    }
+
+    /// Parse a mapfile as if it had been read from a file in the `mappath` directory,
+    /// so INCLUDEs, SYMBOLSET, FONTSET and relative DATA paths are resolved against it.
+    /// Retried as in `load_from_string_with_retry`
+    pub fn load_from_string_with_path(
+        mapfile_contents: &str,
+        mappath: &Path,
+        retry: &LoadRetry,
+    ) -> Result<Self, String> {
+        Self::load(mapfile_contents, Some(mappath), retry)
+    }
+
+    fn load(
+        mapfile_contents: &str,
+        mappath: Option<&Path>,
+        retry: &LoadRetry,
+    ) -> Result<Self, String> {
+        let mapfile_cstr = CString::new(mapfile_contents)
+            .map_err(|_| "Mapfile contains a nul byte".to_string())?;
+        ensure_initialized()?;
+        let mappath_cstr = match mappath {
+            Some(mappath) => Some(
+                mappath
+                    .to_str()
+                    .and_then(|mappath| CString::new(mappath).ok())
+                    .ok_or_else(|| format!("Invalid mappath '{}'", mappath.display()))?,
+            ),
+            None => None,
+        };
+        let handle = retry.run(|| {
+            let buffer = mapfile_cstr.as_ptr() as *mut c_char;
+            let mappath = mappath_cstr
+                .as_ref()
+                .map_or(std::ptr::null_mut(), |mappath| {
+                    mappath.as_ptr() as *mut c_char
+                });
+            reset_errors();
+            let map_obj = unsafe { msLoadMapFromString(buffer, mappath) };
+            NonNull::new(map_obj)
+                .map(MapHandle)
+                .ok_or_else(|| LoadFailure {
+                    retryable: !is_syntax_error(),
+                    message: take_errors(),
+                })
+        })?;
+
+        let relative = handle.relative_data_paths();
+        if !relative.is_empty() {
+            let layers: Vec<String> = relative
+                .iter()
+                .map(|(layer, data)| format!("layer '{}' DATA '{}'", layer, data))
+                .collect();
+            return Err(format!(
+                "Relative DATA paths cannot be resolved without a mappath or an absolute SHAPEPATH: {}",
+                layers.join(", ")
+            ));
+        }
+        Ok(handle)
+    }
+
+    /// The (layer name, DATA) of file-based layers whose DATA is a relative path that
+    /// mapserver would resolve against the process's working directory, since the map has
+    /// neither a mappath nor an absolute SHAPEPATH. Such layers fail to open and draw
+    /// nothing. URIs and GDAL driver strings, anything with a ':', are not paths
+    pub fn relative_data_paths(&self) -> Vec<(String, String)> {
+        let mut relative = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            let shapepath = c_string((*map_obj).shapepath);
+            if !(*map_obj).mappath.is_null() || Path::new(&shapepath).is_absolute() {
+                return relative;
+            }
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if layer.is_null()
+                    || !matches!(
+                        (*layer).connectiontype,
+                        MS_SHAPEFILE | MS_TILED_SHAPEFILE | MS_RASTER
+                    )
+                {
+                    continue;
+                }
+                let data = c_string((*layer).data);
+                if !data.is_empty() && !data.contains(':') && !Path::new(&data).is_absolute() {
+                    relative.push((c_string((*layer).name), data));
+                }
+            }
+        }
+        relative
+    }
+
+    fn as_ptr(&self) -> *mut mapObj {
+        self.0.as_ptr()
+    }
+
+    /// The current extent, the mapfile EXTENT until `set_extent` is called
+    pub fn extent(&self) -> Extent {
+        let rect = unsafe { (*self.as_ptr()).extent };
+        Extent::new(rect.minx, rect.miny, rect.maxx, rect.maxy)
+    }
+
+    /// Set the extent for the next draw, with mapserver's error message if it is rejected
+    pub fn set_extent(&self, ext: Extent) -> Result<(), String> {
+        reset_errors();
+        if unsafe { msMapSetExtent(self.as_ptr(), ext.0, ext.1, ext.2, ext.3) } != MS_SUCCESS {
+            return Err(take_errors());
+        }
+        Ok(())
+    }
+
+    /// The output projection as a PROJ definition, empty if the mapfile has no PROJECTION
+    pub fn projection(&self) -> String {
+        unsafe {
+            let definition = msGetProjectionString(&mut (*self.as_ptr()).projection);
+            let projection = c_string(definition);
+            msFree(definition as *mut libc::c_void);
+            projection
+        }
+    }
+
+    /// Set the output projection from a PROJ definition, e.g. `init=epsg:4326`, and the
+    /// map units to match. An empty definition removes the projection
+    pub fn set_projection(&self, definition: &str) -> Result<(), String> {
+        let definition = CString::new(definition).map_err(|err| err.to_string())?;
+        reset_errors();
+        unsafe {
+            let map_obj = self.as_ptr();
+            if definition.as_bytes().is_empty() {
+                msFreeProjection(&mut (*map_obj).projection);
+            } else if msLoadProjectionString(&mut (*map_obj).projection, definition.as_ptr())
+                != MS_SUCCESS
+            {
+                return Err(take_errors());
+            }
+            (*map_obj).units = GetMapserverUnitUsingProj(&mut (*map_obj).projection) as _;
+        }
+        Ok(())
+    }
+
+    /// Map units per pixel at the current extent and size.
+    /// Mapserver places the extent's edges on the centres of the edge pixels
+    pub fn cellsize(&self) -> f64 {
+        unsafe { (*self.as_ptr()).cellsize }
+    }
+
+    /// The image (width, height) in pixels
+    pub fn size(&self) -> (i32, i32) {
+        unsafe { ((*self.as_ptr()).width, (*self.as_ptr()).height) }
+    }
+
+    /// Resize the image for the next draw, false if mapserver rejects the size
+    pub fn set_size(&self, width: i32, height: i32) -> bool {
+        unsafe { msMapSetSize(self.as_ptr(), width, height) == MS_SUCCESS }
+    }
+
+    /// The extent of each layer whose extent can be determined, in the layer's projection.
+    /// Layers without an EXTENT are asked for the extent of their data source, which may open it
+    pub fn layer_extents(&self) -> Vec<Extent> {
+        let mut extents = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                let mut rect: rectObj = std::mem::zeroed();
+                if layer.is_null() || msLayerGetExtent(layer, &mut rect) != MS_SUCCESS {
+                    continue;
+                }
+                extents.push(Extent::new(rect.minx, rect.miny, rect.maxx, rect.maxy));
+            }
+        }
+        extents
+    }
+
+    /// Open a layer's data source and read its extent, the cheapest check that the data is
+    /// reachable, with mapserver's error message if it is not. Layers that cannot report
+    /// an extent without raising an error, e.g. some remote connections, pass
+    pub fn check_layer(&self, index: usize) -> Result<(), String> {
+        reset_errors();
+        unsafe {
+            let map_obj = self.as_ptr();
+            if index >= (*map_obj).numlayers.max(0) as usize {
+                return Err(format!("No layer {}", index));
+            }
+            let layer = *(*map_obj).layers.add(index);
+            if layer.is_null() {
+                return Err(format!("No layer {}", index));
+            }
+            if msLayerOpen(layer) != MS_SUCCESS {
+                let message = take_errors();
+                msLayerClose(layer);
+                return Err(message);
+            }
+            let mut rect: rectObj = std::mem::zeroed();
+            let status = msLayerGetExtent(layer, &mut rect);
+            msLayerClose(layer);
+            if status != MS_SUCCESS {
+                let message = take_errors();
+                if !message.is_empty() {
+                    return Err(message);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Names of every layer, in drawing order
+    pub fn layer_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if !layer.is_null() {
+                    names.push(c_string((*layer).name));
+                }
+            }
+        }
+        names
+    }
+
+    /// The STATUS of every layer, in drawing order, to restore with `set_layer_statuses`
+    pub fn layer_statuses(&self) -> Vec<i32> {
+        let mut statuses = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if !layer.is_null() {
+                    statuses.push((*layer).status);
+                }
+            }
+        }
+        statuses
+    }
+
+    /// Set the STATUS of each layer, in drawing order, as returned by `layer_statuses`
+    pub fn set_layer_statuses(&self, statuses: &[i32]) {
+        unsafe {
+            let map_obj = self.as_ptr();
+            let layers = (0..(*map_obj).numlayers as usize)
+                .map(|i| *(*map_obj).layers.add(i))
+                .filter(|layer| !layer.is_null());
+            for (layer, status) in layers.zip(statuses) {
+                (*layer).status = *status;
+            }
+        }
+    }
+
+    /// Switch the named layers on and every other layer off
+    pub fn set_layers_on(&self, names: &[&str]) {
+        let statuses: Vec<i32> = self
+            .layer_names()
+            .iter()
+            .map(|name| {
+                if names.contains(&name.as_str()) {
+                    MS_ON
+                } else {
+                    MS_OFF
+                }
+            })
+            .collect();
+        self.set_layer_statuses(&statuses);
+    }
+
+    /// The distinct GROUP names of the classes of every layer, in the order they are declared
+    pub fn class_groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if layer.is_null() {
+                    continue;
+                }
+                for j in 0..(*layer).numclasses.max(0) as usize {
+                    let class = *(*layer).class.add(j);
+                    if class.is_null() || (*class).group.is_null() {
+                        continue;
+                    }
+                    let group = c_string((*class).group);
+                    if !groups.contains(&group) {
+                        groups.push(group);
+                    }
+                }
+            }
+        }
+        groups
+    }
+
+    /// Call `f` with every layer's CLASSGROUP set to `group`, so only the classes of that
+    /// GROUP are drawn, then put the mapfile's CLASSGROUPs back. Layers with no class in
+    /// the group keep drawing with all of their classes, as in mapserver
+    pub fn with_class_group<T>(&self, group: &str, f: impl FnOnce() -> T) -> T {
+        let group = CString::new(group).unwrap_or_default();
+        unsafe {
+            let map_obj = self.as_ptr();
+            let layers: Vec<_> = (0..(*map_obj).numlayers as usize)
+                .map(|i| *(*map_obj).layers.add(i))
+                .filter(|layer| !layer.is_null())
+                .collect();
+            // Mapserver only reads CLASSGROUP while drawing, so it can borrow `group`
+            // as long as the original pointers are restored before it is dropped
+            let previous: Vec<*mut c_char> = layers
+                .iter()
+                .map(|&layer| std::mem::replace(&mut (*layer).classgroup, group.as_ptr() as *mut _))
+                .collect();
+            let result = f();
+            for (&layer, classgroup) in layers.iter().zip(previous) {
+                (*layer).classgroup = classgroup;
+            }
+            result
+        }
+    }
+
+    /// Call `f` with a PROCESSING option of every raster layer set to `value`, e.g.
+    /// `RESAMPLE=BILINEAR`, then put back each layer's own value, removing the option
+    /// from layers whose mapfile did not set it
+    pub fn with_raster_processing<T>(&self, key: &str, value: &str, f: impl FnOnce() -> T) -> T {
+        let key = CString::new(key).unwrap_or_default();
+        let value = CString::new(value).unwrap_or_default();
+        unsafe {
+            let map_obj = self.as_ptr();
+            let layers: Vec<_> = (0..(*map_obj).numlayers as usize)
+                .map(|i| *(*map_obj).layers.add(i))
+                .filter(|&layer| !layer.is_null() && (*layer).type_ == MS_LAYER_RASTER)
+                .collect();
+            // Copied, since setting the option frees the layer's own string
+            let previous: Vec<Option<CString>> = layers
+                .iter()
+                .map(|&layer| {
+                    let previous = msLayerGetProcessingKey(layer, key.as_ptr());
+                    (!previous.is_null()).then(|| CStr::from_ptr(previous).to_owned())
+                })
+                .collect();
+            for &layer in &layers {
+                msLayerSetProcessingKey(layer, key.as_ptr(), value.as_ptr());
+            }
+            let result = f();
+            for (&layer, previous) in layers.iter().zip(previous) {
+                let previous = previous.as_ref().map_or(std::ptr::null(), |p| p.as_ptr());
+                msLayerSetProcessingKey(layer, key.as_ptr(), previous);
+            }
+            result
+        }
+    }
+
+    /// The value of a PROCESSING option of every layer, in drawing order, None where it is unset
+    pub fn layer_processing(&self, key: &str) -> Vec<Option<String>> {
+        let key = CString::new(key).unwrap_or_default();
+        let mut values = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if !layer.is_null() {
+                    let value = msLayerGetProcessingKey(layer, key.as_ptr());
+                    values.push((!value.is_null()).then(|| c_string(value)));
+                }
+            }
+        }
+        values
+    }
+
+    /// Call `f` with the map's output formats TRANSPARENT or not, then put back each format's
+    /// own setting. While transparent, RGB formats draw in RGBA so the background has an
+    /// alpha channel, as mapserver does for `TRANSPARENT ON`
+    pub fn with_transparency<T>(&self, transparent: bool, f: impl FnOnce() -> T) -> T {
+        unsafe {
+            let map_obj = self.as_ptr();
+            let mut formats: Vec<*mut outputFormatObj> = self
+                .output_formats()
+                .iter()
+                .map(|format| format.format_obj.as_ptr())
+                .collect();
+            // The default format may be a copy rather than one of the list
+            if !(*map_obj).outputformat.is_null() && !formats.contains(&(*map_obj).outputformat) {
+                formats.push((*map_obj).outputformat);
+            }
+            let previous: Vec<(i32, i32)> = formats
+                .iter()
+                .map(|&format| ((*format).transparent, (*format).imagemode))
+                .collect();
+            for &format in &formats {
+                (*format).transparent = transparent as i32;
+                if transparent && (*format).imagemode == MS_IMAGEMODE_RGB {
+                    (*format).imagemode = MS_IMAGEMODE_RGBA;
+                }
+            }
+            let result = f();
+            for (&format, (transparent, imagemode)) in formats.iter().zip(previous) {
+                (*format).transparent = transparent;
+                (*format).imagemode = imagemode;
+            }
+            result
+        }
+    }
+
+    /// Set a CONFIG option on the map, as a CONFIG line in the mapfile would, and apply the
+    /// map's options to GDAL and PROJ. Those are process-wide, so every map sees the option
+    pub fn set_config_option(&self, key: &str, value: &str) {
+        let key = CString::new(key).unwrap_or_default();
+        let value = CString::new(value).unwrap_or_default();
+        unsafe {
+            msSetConfigOption(self.as_ptr(), key.as_ptr(), value.as_ptr());
+            msApplyMapConfigOptions(self.as_ptr());
+        }
+    }
+
+    /// The map's value for a CONFIG option, None if neither the mapfile nor
+    /// `set_config_option` set it
+    pub fn config_option(&self, key: &str) -> Option<String> {
+        let key = CString::new(key).ok()?;
+        unsafe {
+            let value = msGetConfigOption(self.as_ptr(), key.as_ptr());
+            (!value.is_null()).then(|| c_string(value))
+        }
+    }
+
+    /// Names of the layers that are switched on and hold raster data
+    pub fn raster_layers(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        unsafe {
+            let map_obj = self.as_ptr();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if !layer.is_null()
+                    && (*layer).status != MS_OFF
+                    && (*layer).type_ == MS_LAYER_RASTER
+                {
+                    names.push(c_string((*layer).name));
+                }
+            }
+        }
+        names
+    }
+
+    /// Encode the vector layers at the current extent as a Mapbox Vector Tile.
+    /// msMVTWriteTile reads its options from the map's output format and writes to
+    /// stdout, so the format is swapped in and stdout captured for the duration
+    pub fn write_mvt(&self, format: FormatHandle<'_>) -> Result<Vec<u8>, String> {
+        reset_errors();
+        unsafe {
+            let map_obj = self.as_ptr();
+            let previous = (*map_obj).outputformat;
+            (*map_obj).outputformat = format.format_obj.as_ptr();
+            let written = capture_stdout(|| msMVTWriteTile(map_obj, MS_FALSE));
+            (*map_obj).outputformat = previous;
+            written
+        }
+    }
+
+    /// Write the features of the queryable layers within the extent with an OGR output
+    /// format, e.g. a GeoJSON FeatureCollection. An extent with no features gives an
+    /// empty FeatureCollection rather than mapserver's "no matching record" error
+    pub fn write_ogr(&self, ext: Extent, format: FormatHandle<'_>) -> Result<Vec<u8>, String> {
+        let map_obj = self.as_ptr();
+        reset_errors();
+        unsafe {
+            let query = &mut (*map_obj).query;
+            msInitQuery(query);
+            query.type_ = MS_QUERY_BY_RECT;
+            query.mode = MS_QUERY_MULTIPLE;
+            query.layer = -1;
+            query.rect.minx = ext.0;
+            query.rect.miny = ext.1;
+            query.rect.maxx = ext.2;
+            query.rect.maxy = ext.3;
+            if msQueryByRect(map_obj) != MS_SUCCESS {
+                if error_codes().contains(&MS_NOTFOUND) {
+                    reset_errors();
+                    return Ok(EMPTY_FEATURE_COLLECTION.to_vec());
+                }
+                return Err(take_errors());
+            }
+            capture_stdout(|| msOGRWriteFromQuery(map_obj, format.format_obj.as_ptr(), MS_FALSE))
+        }
+    }
+
+    /// The map's default output format
+    pub fn output_format(&self) -> Option<FormatHandle<'_>> {
+        FormatHandle::new(unsafe { (*self.as_ptr()).outputformat })
+    }
+
+    /// Every output format the map knows, in the order they were declared
+    pub fn output_formats(&self) -> Vec<FormatHandle<'_>> {
+        unsafe {
+            let map_obj = self.as_ptr();
+            if (*map_obj).outputformatlist.is_null() {
+                return Vec::new();
+            }
+            (0..(*map_obj).numoutputformats.max(0) as usize)
+                .filter_map(|i| FormatHandle::new(*(*map_obj).outputformatlist.add(i)))
+                .collect()
+        }
+    }
+
+    /// Look up an OUTPUTFORMAT declared in the mapfile by name or MIME type
+    pub fn select_output_format(&self, name: &str) -> Option<FormatHandle<'_>> {
+        let name = CString::new(name).ok()?;
+        FormatHandle::new(unsafe { msSelectOutputFormat(self.as_ptr(), name.as_ptr()) })
+    }
+
+    /// Query every queryable layer, i.e. those with a TEMPLATE, for features at a point
+    /// in map coordinates, within each layer's TOLERANCE. Returns the attributes of each
+    /// matching feature, keyed by item name
+    pub fn query_point(&self, x: f64, y: f64) -> Result<Vec<HashMap<String, String>>, String> {
+        let map_obj = self.as_ptr();
+        reset_errors();
+        unsafe {
+            let query = &mut (*map_obj).query;
+            msInitQuery(query);
+            query.type_ = MS_QUERY_BY_POINT;
+            query.mode = MS_QUERY_MULTIPLE;
+            query.layer = -1;
+            query.point.x = x;
+            query.point.y = y;
+            if msQueryByPoint(map_obj) != MS_SUCCESS {
+                // Nothing at the point is reported as an error, but it is an empty result
+                if error_codes().contains(&MS_NOTFOUND) {
+                    reset_errors();
+                    return Ok(Vec::new());
+                }
+                return Err(take_errors());
+            }
+
+            let mut features = Vec::new();
+            for i in 0..(*map_obj).numlayers as usize {
+                let layer = *(*map_obj).layers.add(i);
+                if layer.is_null() || (*layer).resultcache.is_null() {
+                    continue;
+                }
+                features.extend(query_results(layer)?);
+            }
+            Ok(features)
+        }
+    }
+
+    /// Draw the map at its current extent and size,
+    /// with mapserver's error message if it fails to render
+    pub fn draw(&self) -> Result<ImageHandle<'_>, String> {
+        reset_errors();
+        let img = NonNull::new(unsafe { msDrawMap(self.as_ptr(), 0) }).ok_or_else(take_errors)?;
+        Ok(ImageHandle {
+            img,
+            _map: PhantomData,
+        })
+    }
+}
+
+/// Run a mapserver writer with stdout redirected to a buffer, and return what it wrote.
+/// The writer returns a mapserver status, and failures carry mapserver's error message
+unsafe fn capture_stdout(write: impl FnOnce() -> i32) -> Result<Vec<u8>, String> {
+    msIO_installStdoutToBuffer();
+    let status = write();
+    let buffer = msIO_getStdoutBufferBytes();
+    msIO_resetHandlers();
+
+    let bytes = if buffer.data.is_null() {
+        Vec::new()
+    } else {
+        let len = usize::try_from(buffer.size).unwrap_or(0);
+        let bytes = std::slice::from_raw_parts(buffer.data, len).to_owned();
+        // The buffer is handed over to the caller
+        msFree(buffer.data as *mut libc::c_void);
+        bytes
+    };
+    if status != MS_SUCCESS {
+        return Err(take_errors());
+    }
+    Ok(bytes)
+}
+
+// SAFETY: a mapObj has no affinity to the thread that loaded it. Mapserver's error stack
+// and connection pool are per thread, but neither outlives a call on the handle:
+// the stack is reset before each call and layers are closed at the end of each draw.
+// What mapserver cannot handle is two threads using one mapObj at once, which `!Sync`
+// rules out. Images and formats borrow the handle, so they cannot be sent without it
+unsafe impl Send for MapHandle {}
+
+impl Drop for MapHandle {
+    fn drop(&mut self) {
+        unsafe {
+            // We cannot do a full msCleanup() or msGDALCleanup() here
+            msFreeMap(self.as_ptr());
+            msDebugCleanup();
+        }
+    }
+}
+
+/// Read the attributes of every feature in a layer's query results
+unsafe fn query_results(layer: *mut layerObj) -> Result<Vec<HashMap<String, String>>, String> {
+    let cache = (*layer).resultcache;
+    if (*cache).numresults == 0 {
+        return Ok(Vec::new());
+    }
+    if msLayerOpen(layer) != MS_SUCCESS {
+        return Err(take_errors());
+    }
+    if msLayerGetItems(layer) != MS_SUCCESS {
+        msLayerClose(layer);
+        return Err(take_errors());
+    }
+
+    let numitems = (*layer).numitems.max(0) as usize;
+    let mut features = Vec::with_capacity((*cache).numresults as usize);
+    for i in 0..(*cache).numresults as usize {
+        let mut shape: shapeObj = std::mem::zeroed();
+        msInitShape(&mut shape);
+        if msLayerGetShape(layer, &mut shape, (*cache).results.add(i)) == MS_SUCCESS {
+            let numvalues = (shape.numvalues.max(0) as usize).min(numitems);
+            let attributes = (0..numvalues)
+                .map(|j| {
+                    (
+                        c_string(*(*layer).items.add(j)),
+                        c_string(*shape.values.add(j)),
+                    )
+                })
+                .collect();
+            features.push(attributes);
+        }
+        msFreeShape(&mut shape);
+    }
+    msLayerClose(layer);
+    Ok(features)
+}
+
+/// Copy a C string, empty if it is null
+unsafe fn c_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+///
+/// An output format belonging to a loaded map
+///
+#[derive(Debug, Clone, Copy)]
+pub struct FormatHandle<'map> {
+    format_obj: NonNull<outputFormatObj>,
+    _map: PhantomData<&'map MapHandle>,
+}
+
+impl<'map> FormatHandle<'map> {
+    fn new(format_obj: *mut outputFormatObj) -> Option<Self> {
+        Some(FormatHandle {
+            format_obj: NonNull::new(format_obj)?,
+            _map: PhantomData,
+        })
+    }
+
+    /// The OUTPUTFORMAT NAME
+    pub fn name(&self) -> String {
+        unsafe { c_string((*self.format_obj.as_ptr()).name) }
+    }
+
+    /// The format's MIME type, falling back to a generic binary type
+    pub fn content_type(&self) -> String {
+        let mimetype = unsafe { (*self.format_obj.as_ptr()).mimetype };
+        if mimetype.is_null() {
+            return "application/octet-stream".to_string();
+        }
+        unsafe { CStr::from_ptr(mimetype) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Encode a `width` x `height` window of RGBA pixels starting at a byte offset
+    pub fn save_raster(
+        &self,
+        pixels: &RasterPixels,
+        offset: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<Vec<u8>, String> {
+        if !pixels.contains(offset, width, height) {
+            return Err(format!("{}x{} window is outside the raster", width, height));
+        }
+        reset_errors();
+        unsafe {
+            let mut raster = pixels.raster_buffer(offset, width, height);
+            let mut buffer: bufferObj = std::mem::zeroed();
+            if msSaveRasterBufferToBuffer(&mut raster, &mut buffer, self.format_obj.as_ptr())
+                != MS_SUCCESS
+                || buffer.data.is_null()
+            {
+                return Err(take_errors());
+            }
+            let bytes = std::slice::from_raw_parts(buffer.data, buffer.size as usize).to_owned();
+            msBufferFree(&mut buffer);
+            Ok(bytes)
+        }
+    }
+}
+
+///
+/// A drawn image, freed with msFreeImage when dropped
+///
+#[derive(Debug)]
+pub struct ImageHandle<'map> {
+    img: NonNull<imageObj>,
+    _map: PhantomData<&'map MapHandle>,
+}
+
+impl<'map> ImageHandle<'map> {
+    /// The output format the image was drawn with
+    pub fn format(&self) -> Option<FormatHandle<'map>> {
+        FormatHandle::new(unsafe { (*self.img.as_ptr()).format })
+    }
+
+    /// Encode the image, with mapserver's error message if it fails to save.
+    /// msSaveImageBuffer returns null when the format cannot encode the image,
+    /// which must be neither sliced nor freed
+    pub fn save(&self, format: FormatHandle<'_>) -> Result<Vec<u8>, String> {
+        let mut size = 0;
+        reset_errors();
+        unsafe {
+            let result_ptr =
+                msSaveImageBuffer(self.img.as_ptr(), &mut size, format.format_obj.as_ptr());
+            if result_ptr.is_null() {
+                return Err(take_errors());
+            }
+            let len = usize::try_from(size).unwrap_or(0);
+            let bytes = std::slice::from_raw_parts(result_ptr, len).to_owned();
+            // Free the temporary buffer
+            libc::free(result_ptr as *mut libc::c_void);
+            Ok(bytes)
+        }
+    }
+
+    /// Copy the rendered pixels, None if the renderer has no RGBA buffer
+    pub fn rgba_pixels(&self) -> Option<RasterPixels> {
+        unsafe {
+            let img = self.img.as_ptr();
+            let vtable = (*(*img).format).vtable;
+            let get_handle = vtable.as_ref()?.getRasterBufferHandle?;
+            let mut raster: rasterBufferObj = std::mem::zeroed();
+            if get_handle(img, &mut raster) != MS_SUCCESS || raster.type_ != MS_BUFFER_BYTE_RGBA {
+                return None;
+            }
+
+            let rgba = raster.data.rgba;
+            let row_step = rgba.row_step as usize;
+            let data = std::slice::from_raw_parts(rgba.pixels, row_step * raster.height as usize);
+            let offset = |channel: *mut u8| channel.offset_from(rgba.pixels) as usize;
+            Some(RasterPixels {
+                data: data.to_owned(),
+                pixel_step: rgba.pixel_step as usize,
+                row_step,
+                channels: [
+                    offset(rgba.r),
+                    offset(rgba.g),
+                    offset(rgba.b),
+                    offset(rgba.a),
+                ],
+            })
+        }
+    }
+}
+
+impl Drop for ImageHandle<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            msFreeImage(self.img.as_ptr());
+        }
+    }
+}
+
+///
+/// An owned copy of an RGBA raster buffer from a renderer
+///
+#[derive(Debug)]
+pub struct RasterPixels {
+    data: Vec<u8>,
+    pixel_step: usize,
+    row_step: usize,
+    // Byte offsets of the r, g, b and a channels within a pixel
+    channels: [usize; 4],
+}
+
+impl RasterPixels {
+    /// Fully transparent pixels, for encoding an empty image without drawing
+    pub fn transparent(width: u32, height: u32) -> Self {
+        RasterPixels {
+            data: vec![0; width as usize * height as usize * 4],
+            pixel_step: 4,
+            row_step: width as usize * 4,
+            channels: [0, 1, 2, 3],
+        }
+    }
+
+    /// Byte offset of the pixel at column `x` and row `y`
+    pub fn offset(&self, x: usize, y: usize) -> usize {
+        y * self.row_step + x * self.pixel_step
+    }
+
+    /// The [r, g, b, a] of the pixel at column `x` and row `y`, None past the end of the
+    /// pixels. Rows are not bounds checked, `x` must be less than the width
+    pub fn pixel(&self, x: usize, y: usize) -> Option<[u8; 4]> {
+        let offset = self.offset(x, y);
+        if !self.contains(offset, 1, 1) {
+            return None;
+        }
+        Some(self.channels.map(|channel| self.data[offset + channel]))
+    }
+
+    /// Whether a window starting at a byte offset lies within the pixels
+    fn contains(&self, offset: usize, width: u32, height: u32) -> bool {
+        if width == 0 || height == 0 {
+            return false;
+        }
+        let last_pixel =
+            offset + (height as usize - 1) * self.row_step + (width as usize - 1) * self.pixel_step;
+        last_pixel + self.channels.iter().max().unwrap() < self.data.len()
+    }
+
+    /// A raster buffer viewing a window of the pixels starting at a byte offset.
+    /// Only valid while self is borrowed
+    unsafe fn raster_buffer(&self, offset: usize, width: u32, height: u32) -> rasterBufferObj {
+        let pixels = self.data.as_ptr().add(offset) as *mut u8;
+        let mut raster: rasterBufferObj = std::mem::zeroed();
+        raster.type_ = MS_BUFFER_BYTE_RGBA;
+        raster.width = width;
+        raster.height = height;
+        raster.data.rgba = rgbaArrayObj {
+            pixels,
+            pixel_step: self.pixel_step as u32,
+            row_step: self.row_step as u32,
+            r: pixels.add(self.channels[0]),
+            g: pixels.add(self.channels[1]),
+            b: pixels.add(self.channels[2]),
+            a: pixels.add(self.channels[3]),
+        };
+        raster
+    }
+}
+
+///
+/// How many times to attempt loading a mapfile, doubling the backoff between attempts.
+/// A single attempt by default
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadRetry {
+    pub attempts: u32,
+    pub backoff: Duration,
+}
+
+impl Default for LoadRetry {
+    fn default() -> Self {
+        LoadRetry {
+            attempts: 1,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// A failed load attempt
+#[derive(Debug)]
+struct LoadFailure {
+    /// False for syntax errors, which no retry can fix
+    retryable: bool,
+    message: String,
+}
+
+impl LoadRetry {
+    fn run<T>(&self, mut load: impl FnMut() -> Result<T, LoadFailure>) -> Result<T, String> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match load() {
+                Ok(loaded) => return Ok(loaded),
+                Err(failure) if !failure.retryable || attempt >= self.attempts => {
+                    return Err(failure.message)
+                }
+                Err(_) => {
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Whether any error on this thread's stack is from parsing the mapfile
+fn is_syntax_error() -> bool {
+    error_codes().iter().any(|code| {
+        matches!(
+            *code,
+            MS_TYPEERR | MS_SYMERR | MS_IDENTERR | MS_EOFERR | MS_PARSEERR
+        )
+    })
+}
+
+/// The codes of the errors on this thread's stack, most recent first
+fn error_codes() -> Vec<i32> {
+    let mut codes = Vec::new();
+    unsafe {
+        let mut error = msGetErrorObj();
+        while !error.is_null() && (*error).code != MS_NOERR {
+            codes.push((*error).code);
+            error = (*error).next;
+        }
+    }
+    codes
+}
+
+/// Clear this thread's error stack, so earlier warnings are not reported with the next failure
+fn reset_errors() {
+    unsafe {
+        msResetErrorList();
+    }
+}
+
+///
+/// Drain this thread's error stack into a single message, most recent error last.
+/// Empty if mapserver did not report an error
+///
+pub fn take_errors() -> String {
+    unsafe {
+        let error = msGetErrorObj();
+        if error.is_null() || (*error).code == MS_NOERR {
+            return String::new();
+        }
+        let delimiter = CString::new("; ").unwrap();
+        let error_ptr = msGetErrorString(delimiter.as_ptr());
+        let message = if error_ptr.is_null() {
+            String::new()
+        } else {
+            let message = CStr::from_ptr(error_ptr)
+                .to_string_lossy()
+                .trim()
+                .to_owned();
+            msFree(error_ptr as *mut libc::c_void);
+            message
+        };
+        msResetErrorList();
+        message
+    }
+}
+
+static SETUP: Once = Once::new();
+static SETUP_ERROR: Mutex<Option<String>> = Mutex::new(None);
+static PROJ_DATA: Mutex<Option<CString>> = Mutex::new(None);
+
+///
+/// Set up mapserver's process-wide state with msSetup: its thread locks, GEOS, the font
+/// cache, and PROJ_DATA and MS_ERRORFILE from the environment. Only the first call does
+/// the setup, later calls return its result, so every map load calls this rather than
+/// relying on whatever mapserver initializes implicitly.
+///
+pub fn ensure_initialized() -> Result<(), String> {
+    SETUP.call_once(|| {
+        reset_errors();
+        if unsafe { msSetup() } != MS_SUCCESS {
+            *SETUP_ERROR.lock().unwrap() =
+                Some(format!("Unable to set up mapserver: {}", take_errors()));
+        }
+        // Otherwise msSetup has taken PROJ_DATA from the environment
+        if PROJ_DATA.lock().unwrap().is_some() {
+            apply_proj_data();
+        }
+    });
+    match SETUP_ERROR.lock().unwrap().clone() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+///
+/// Search `dir` for PROJ's data, e.g. proj.db, rather than relying on PROJ_DATA in the
+/// environment, or go back to the environment with None. Set before the first map loads,
+/// `ensure_initialized` passes it to mapserver, later changes are passed on right away
+///
+pub fn set_proj_data(dir: Option<&Path>) -> Result<(), String> {
+    let dir = match dir {
+        Some(dir) if !dir.is_dir() => {
+            return Err(format!(
+                "PROJ data directory {} does not exist",
+                dir.display()
+            ))
+        }
+        Some(dir) => Some(
+            dir.to_str()
+                .and_then(|dir| CString::new(dir).ok())
+                .ok_or_else(|| format!("Invalid PROJ data directory '{}'", dir.display()))?,
+        ),
+        None => None,
+    };
+    *PROJ_DATA.lock().unwrap() = dir;
+    if SETUP.is_completed() {
+        apply_proj_data();
+    }
+    Ok(())
+}
+
+/// The PROJ data directory passed to mapserver, None if PROJ_DATA is taken from the
+/// environment
+pub fn proj_data() -> Option<PathBuf> {
+    PROJ_DATA
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|dir| PathBuf::from(dir.to_string_lossy().into_owned()))
+}
+
+/// Pass the configured PROJ data directory to mapserver, or null to clear it.
+/// Mapserver locks around the change and every projection context picks it up
+fn apply_proj_data() {
+    let proj_data = PROJ_DATA.lock().unwrap();
+    let dir = proj_data
+        .as_ref()
+        .map_or(std::ptr::null(), |dir| dir.as_ptr());
+    unsafe {
+        msSetPROJ_DATA(dir, std::ptr::null());
+    }
+}
+
+///
+/// Cleanup the global state shared by all maps.
+///
+/// # Safety
+///
+/// Only safe when no maps are loaded and no renders are in flight
+///
+pub unsafe fn cleanup_globals() {
+    // We cannot do a full msCleanup() here either :-/
+    // What *can* we safely cleanup without fully unloading the shared library?
+    msGDALCleanup();
+    msOGRCleanup();
+    msIO_Cleanup();
+    // Frees mapserver's copy of the PROJ data directory, keeping the configured one
+    apply_proj_data();
+    msProjectionContextPoolCleanup();
+}
+
+///
+/// Fully cleanup mapserver.
+///
+/// # Safety
+///
+/// No thread may use mapserver afterwards, including to free maps that are still loaded
+///
+pub unsafe fn cleanup() {
+    msCleanup();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAPFILE: &str = "MAP EXTENT 0 0 100 100 SIZE 256 256 END";
+
+    /// Held by the tests that change mapserver's process-wide state, one at a time
+    static GLOBAL_STATE: Mutex<()> = Mutex::new(());
+
+    /// Puts back the PROJ data directory a test started with, even if it fails
+    struct RestoreProjData(Option<PathBuf>);
+
+    impl Drop for RestoreProjData {
+        fn drop(&mut self) {
+            set_proj_data(self.0.as_deref()).ok();
+        }
+    }
+
+    /// The directory PROJ finds proj.db in, when nothing is configured
+    fn system_proj_data() -> Option<PathBuf> {
+        ["PROJ_DATA", "PROJ_LIB"]
+            .into_iter()
+            .filter_map(std::env::var_os)
+            .map(PathBuf::from)
+            .chain(["/usr/share/proj", "/usr/local/share/proj"].map(PathBuf::from))
+            .find(|dir| dir.join("proj.db").is_file())
+    }
+
+    #[test]
+    fn test_ensure_initialized() {
+        let _global = GLOBAL_STATE.lock().unwrap_or_else(|err| err.into_inner());
+        // Every racing caller waits for the one setup and gets its result
+        let threads: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(ensure_initialized))
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Ok(()));
+        }
+        assert!(SETUP.is_completed());
+        assert_eq!(ensure_initialized(), Ok(()));
+
+        // Maps load as usual after setup
+        assert!(MapHandle::load_from_string(MAPFILE).is_ok());
+    }
+
+    #[test]
+    fn test_set_proj_data() {
+        let _global = GLOBAL_STATE.lock().unwrap_or_else(|err| err.into_inner());
+        let restore = RestoreProjData(proj_data());
+
+        // PROJ's own data, so projections elsewhere keep working, and an init file of our own
+        let dir = std::env::temp_dir().join(format!("proj-data-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let system = restore.0.clone().or_else(system_proj_data);
+        for entry in system
+            .iter()
+            .flat_map(|system| std::fs::read_dir(system).unwrap())
+        {
+            let entry = entry.unwrap();
+            std::os::unix::fs::symlink(entry.path(), dir.join(entry.file_name())).unwrap();
+        }
+        std::fs::write(
+            dir.join("mapserver_rs_test"),
+            "<900913> +proj=merc +a=6378137 +b=6378137 +lat_ts=0 +lon_0=0 +x_0=0 +y_0=0 \
+             +k=1 +units=m +nadgrids=@null +no_defs <>\n",
+        )
+        .unwrap();
+
+        // Mapserver only finds the init file once it searches the directory
+        let map = MapHandle::load_from_string(MAPFILE).unwrap();
+        let custom = "init=mapserver_rs_test:900913";
+        assert!(map.set_projection(custom).is_err());
+        assert_eq!(set_proj_data(Some(&dir)), Ok(()));
+        assert_eq!(proj_data(), Some(dir.clone()));
+        assert_eq!(map.set_projection(custom), Ok(()));
+
+        // A missing directory is rejected, keeping the configured one
+        let missing = Path::new("/nonexistent/proj");
+        assert_eq!(
+            set_proj_data(Some(missing)),
+            Err("PROJ data directory /nonexistent/proj does not exist".to_string())
+        );
+        assert_eq!(proj_data(), Some(dir.clone()));
+
+        let previous = restore.0.clone();
+        drop(restore);
+        assert_eq!(proj_data(), previous);
+        assert_eq!(map.set_projection("init=epsg:3857"), Ok(()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_rejects_relative_data() {
+        let err = MapHandle::load_from_string(
+            "MAP LAYER NAME 'roads' TYPE LINE DATA 'data/roads.shp' END \
+             LAYER NAME 'rivers' TYPE LINE DATA '/data/rivers.shp' END END",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "Relative DATA paths cannot be resolved without a mappath or an absolute SHAPEPATH: \
+             layer 'roads' DATA 'data/roads.shp'"
+        );
+
+        // Resolved against an absolute SHAPEPATH
+        let handle = MapHandle::load_from_string(
+            "MAP SHAPEPATH '/data' LAYER NAME 'roads' TYPE LINE DATA 'roads.shp' END END",
+        )
+        .unwrap();
+        assert!(handle.relative_data_paths().is_empty());
+
+        // Not paths at all
+        assert!(MapHandle::load_from_string(
+            "MAP LAYER NAME 'naip' TYPE RASTER DATA 's3://arrays/naip' END \
+             LAYER NAME 'parcels' TYPE POLYGON CONNECTIONTYPE POSTGIS \
+             DATA 'geom from parcels' END END"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_load_rejects_interior_nul() {
+        // Rejected before reaching mapserver, so this also runs under Miri
+        assert!(MapHandle::load_from_string("MAP\0END").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_load_and_drop() {
+        assert!(MapHandle::load_from_string("NOT A MAPFILE").is_err());
+
+        // Each handle frees its own mapObj, so loading and dropping repeatedly must not leak or double free
+        for _ in 0..10 {
+            let map = MapHandle::load_from_string(MAPFILE).unwrap();
+            assert_eq!(map.extent(), Extent::new(0.0, 0.0, 100.0, 100.0));
+            drop(map);
+        }
+    }
+
+    #[test]
+    fn test_load_retry() {
+        let retry = LoadRetry {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        };
+        let transient = |attempts: &mut u32| {
+            *attempts += 1;
+            if *attempts < 2 {
+                return Err(LoadFailure {
+                    retryable: true,
+                    message: "msTileDBOpen(): connection refused".to_string(),
+                });
+            }
+            Ok(*attempts)
+        };
+
+        // Succeeds on the second attempt
+        let mut attempts = 0;
+        assert_eq!(retry.run(|| transient(&mut attempts)), Ok(2));
+
+        // The default makes a single attempt and reports its error
+        let mut attempts = 0;
+        assert_eq!(
+            LoadRetry::default().run(|| transient(&mut attempts)),
+            Err("msTileDBOpen(): connection refused".to_string())
+        );
+        assert_eq!(attempts, 1);
+
+        // Syntax errors fail fast
+        let mut attempts = 0;
+        let result: Result<(), String> = retry.run(|| {
+            attempts += 1;
+            Err(LoadFailure {
+                retryable: false,
+                message: "msyylex(): Parsing error".to_string(),
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_load_syntax_error_fails_fast() {
+        let retry = LoadRetry {
+            attempts: 5,
+            backoff: Duration::from_secs(60),
+        };
+        let message = MapHandle::load_from_string_with_retry("NOT A MAPFILE", &retry).unwrap_err();
+        assert!(!message.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_set_extent_and_draw() {
+        let map = MapHandle::load_from_string(MAPFILE).unwrap();
+        let extent = Extent::new(10.0, 10.0, 20.0, 20.0);
+        assert!(map.set_extent(extent).is_ok());
+        assert!(map.set_size(64, 32));
+        assert_eq!(map.size(), (64, 32));
+
+        let img = map.draw().unwrap();
+        let format = img.format().unwrap();
+        assert!(!img.save(format).unwrap().is_empty());
+        // Images are freed before the map that drew them
+        drop(img);
+        drop(map);
+    }
+
+    #[test]
+    fn test_with_raster_processing() {
+        let map = MapHandle::load_from_string(
+            "MAP LAYER NAME 'imagery' TYPE RASTER PROCESSING 'RESAMPLE=AVERAGE' END \
+             LAYER NAME 'hillshade' TYPE RASTER END \
+             LAYER NAME 'roads' TYPE LINE END END",
+        )
+        .unwrap();
+        let own = vec![Some("AVERAGE".to_string()), None, None];
+        assert_eq!(map.layer_processing("RESAMPLE"), own);
+
+        // Set on the raster layers only while `f` runs
+        let during =
+            map.with_raster_processing("RESAMPLE", "BILINEAR", || map.layer_processing("RESAMPLE"));
+        let bilinear = Some("BILINEAR".to_string());
+        assert_eq!(during, vec![bilinear.clone(), bilinear, None]);
+        assert_eq!(map.layer_processing("RESAMPLE"), own);
+    }
+}
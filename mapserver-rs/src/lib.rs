@@ -1,13 +1,302 @@
 pub mod coordinates;
+pub mod handle;
 pub mod mappool;
+#[cfg(feature = "mbtiles")]
+pub mod mbtiles;
+pub mod metrics;
+pub mod ratelimit;
+pub mod remote;
+pub mod seed;
+pub mod template;
+pub mod tilecache;
+pub mod wms;
+pub mod wmts;
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Deserialize, Serialize)]
+use coordinates::Tile;
+
+/// Error parsing an `Extent` from a "minx,miny,maxx,maxy" string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentParseError {
+    /// The string did not have exactly four ',' separated parts
+    WrongPartCount(usize),
+    /// One of the parts was not a valid float
+    InvalidCoordinate(String),
+}
+
+impl fmt::Display for ExtentParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtentParseError::WrongPartCount(n) => {
+                write!(
+                    f,
+                    "expected an extent as minx,miny,maxx,maxy, got {} parts",
+                    n
+                )
+            }
+            ExtentParseError::InvalidCoordinate(s) => write!(f, "invalid coordinate '{}'", s),
+        }
+    }
+}
+
+impl std::error::Error for ExtentParseError {}
+
+/// A bounding box as (minx, miny, maxx, maxy)
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Extent(f64, f64, f64, f64);
 
 impl Extent {
+    pub fn new(minx: f64, miny: f64, maxx: f64, maxy: f64) -> Self {
+        Extent(minx, miny, maxx, maxy)
+    }
+
     pub fn from(e: (f64, f64, f64, f64)) -> Self {
         Extent(e.0, e.1, e.2, e.3)
     }
+
+    pub fn minx(&self) -> f64 {
+        self.0
+    }
+
+    pub fn miny(&self) -> f64 {
+        self.1
+    }
+
+    pub fn maxx(&self) -> f64 {
+        self.2
+    }
+
+    pub fn maxy(&self) -> f64 {
+        self.3
+    }
+
+    /// Span of the extent along the x axis
+    pub fn width(&self) -> f64 {
+        self.2 - self.0
+    }
+
+    /// Span of the extent along the y axis
+    pub fn height(&self) -> f64 {
+        self.3 - self.1
+    }
+
+    /// True if the two extents overlap.
+    /// Extents that only share an edge or corner are considered intersecting
+    pub fn intersects(&self, other: &Extent) -> bool {
+        self.0 <= other.2 && other.0 <= self.2 && self.1 <= other.3 && other.1 <= self.3
+    }
+
+    /// The smallest extent containing both extents
+    pub fn union(&self, other: &Extent) -> Extent {
+        Extent(
+            self.0.min(other.0),
+            self.1.min(other.1),
+            self.2.max(other.2),
+            self.3.max(other.3),
+        )
+    }
+
+    /// True if the point is inside the extent, including its edges
+    pub fn contains_point(&self, x: f64, y: f64) -> bool {
+        self.0 <= x && x <= self.2 && self.1 <= y && y <= self.3
+    }
+
+    /// All tiles at a given zoom level covering this epsg:3857 extent, at both ends of
+    /// the grid for an extent crossing the antimeridian
+    pub fn tiles(&self, zoom: u32) -> Vec<Tile> {
+        self.tile_ranges(zoom)
+            .into_iter()
+            .flat_map(|(xs, ys)| ys.flat_map(move |y| xs.clone().map(move |x| (x, y))))
+            .map(|(x, y)| Tile::from_zxy(zoom, x, y))
+            .collect()
+    }
+
+    /// How many tiles cover this epsg:3857 extent from `minzoom` to `maxzoom` inclusive,
+    /// as `tiles` would list them, without listing them. An extent with `minx > maxx`
+    /// crosses the antimeridian, covering both ends of the grid
+    pub fn tile_count(&self, minzoom: u32, maxzoom: u32) -> u64 {
+        let span = |range: &RangeInclusive<u32>| u64::from(range.end() - range.start()) + 1;
+        (minzoom..=maxzoom.min(coordinates::MAX_ZOOM))
+            .flat_map(|zoom| self.tile_ranges(zoom))
+            .map(|(xs, ys)| span(&xs) * span(&ys))
+            .sum()
+    }
+
+    /// The (columns, rows) of tiles covering this epsg:3857 extent at a zoom level.
+    /// An extent crossing the antimeridian covers columns at both ends of the grid,
+    /// as two ranges, or one spanning the grid when the ends meet
+    pub fn tile_ranges(&self, zoom: u32) -> Vec<(RangeInclusive<u32>, RangeInclusive<u32>)> {
+        let (xmin, ymin, xmax, ymax) =
+            coordinates::tile_range((self.0, self.1, self.2, self.3), zoom);
+        if self.0 <= self.2 {
+            return vec![(xmin..=xmax, ymin..=ymax)];
+        }
+
+        // The columns from minx to the east edge, and from the west edge to maxx
+        let world = Tile::from_zxy(0, 0, 0).bbox_mercator();
+        let (east_min, _, _, _) = coordinates::tile_range((self.0, self.1, world.2, self.3), zoom);
+        let (_, _, west_max, _) = coordinates::tile_range((world.0, self.1, self.2, self.3), zoom);
+        let last = ((1u64 << zoom) - 1) as u32;
+        if west_max >= east_min {
+            return vec![(0..=last, ymin..=ymax)];
+        }
+        vec![(east_min..=last, ymin..=ymax), (0..=west_max, ymin..=ymax)]
+    }
+}
+
+impl fmt::Display for Extent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.0, self.1, self.2, self.3)
+    }
+}
+
+/// Parse an extent from the "minx,miny,maxx,maxy" form
+impl FromStr for Extent {
+    type Err = ExtentParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').collect();
+        if parts.len() != 4 {
+            return Err(ExtentParseError::WrongPartCount(parts.len()));
+        }
+        let coord = |part: &str| {
+            part.trim()
+                .parse::<f64>()
+                .map_err(|_| ExtentParseError::InvalidCoordinate(part.to_string()))
+        };
+        Ok(Extent(
+            coord(parts[0])?,
+            coord(parts[1])?,
+            coord(parts[2])?,
+            coord(parts[3])?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extent_accessors() {
+        let e = Extent::new(-10., 5., 30., 25.);
+        assert_eq!(e, Extent::from((-10., 5., 30., 25.)));
+        assert_eq!(
+            (e.minx(), e.miny(), e.maxx(), e.maxy()),
+            (-10., 5., 30., 25.)
+        );
+        assert_eq!(e.width(), 40.);
+        assert_eq!(e.height(), 20.);
+    }
+
+    #[test]
+    fn test_extent_intersects() {
+        let a = Extent(0., 0., 10., 10.);
+
+        // Disjoint
+        let b = Extent(20., 20., 30., 30.);
+        assert!(!a.intersects(&b));
+        assert!(!b.intersects(&a));
+
+        // Touching edges and corners
+        assert!(a.intersects(&Extent(10., 0., 20., 10.)));
+        assert!(a.intersects(&Extent(10., 10., 20., 20.)));
+
+        // Nested
+        let c = Extent(2., 2., 8., 8.);
+        assert!(a.intersects(&c));
+        assert!(c.intersects(&a));
+
+        // Overlapping
+        let d = Extent(5., -5., 15., 5.);
+        assert!(a.intersects(&d));
+        assert!(d.intersects(&a));
+    }
+
+    #[test]
+    fn test_extent_union() {
+        let a = Extent(0., 0., 10., 10.);
+        assert_eq!(
+            a.union(&Extent(20., -5., 30., 5.)),
+            Extent(0., -5., 30., 10.)
+        );
+        assert_eq!(a.union(&Extent(2., 2., 8., 8.)), a);
+    }
+
+    #[test]
+    fn test_extent_contains_point() {
+        let a = Extent(0., 0., 10., 10.);
+        assert!(a.contains_point(5., 5.));
+        assert!(a.contains_point(10., 0.));
+        assert!(!a.contains_point(10.1, 5.));
+        assert!(!a.contains_point(5., -0.1));
+    }
+
+    #[test]
+    fn test_extent_tile_count() {
+        let extent = Extent(-11711375.7, 4941042.3, -11000000., 5500000.);
+        for (minzoom, maxzoom) in [(0, 0), (0, 3), (4, 8)] {
+            let listed: usize = (minzoom..=maxzoom).map(|z| extent.tiles(z).len()).sum();
+            assert_eq!(extent.tile_count(minzoom, maxzoom), listed as u64);
+        }
+        assert_eq!(extent.tile_count(3, 2), 0);
+
+        // The whole world, and beyond it, is clamped to the grid
+        let world = Extent::from(Tile::from_zxy(0, 0, 0).bbox_mercator());
+        assert_eq!(world.tile_count(0, 2), 1 + 4 + 16);
+        let beyond = Extent(-1e8, -1e8, 1e8, 1e8);
+        assert_eq!(beyond.tile_count(0, 2), 1 + 4 + 16);
+
+        // Across the antimeridian, the tiles at either end of the grid
+        let (west, _, east, _) = world.tiles(0)[0].bbox_mercator();
+        let crossing = Extent(east - 1e6, 0., west + 1e6, 1e6);
+        let ends = |zoom| {
+            Extent(east - 1e6, 0., east, 1e6).tiles(zoom).len()
+                + Extent(west, 0., west + 1e6, 1e6).tiles(zoom).len()
+        };
+        for zoom in 2..=6 {
+            assert_eq!(crossing.tile_count(zoom, zoom), ends(zoom) as u64);
+            assert_eq!(crossing.tiles(zoom).len(), ends(zoom));
+        }
+        // Both ends fall in the same tiles at zoom 0 and 1
+        assert_eq!(crossing.tile_count(0, 1), 1 + 2);
+        for zoom in 0..=6 {
+            let tiles = crossing.tiles(zoom);
+            assert_eq!(crossing.tile_count(zoom, zoom), tiles.len() as u64);
+            let mut unique = tiles.clone();
+            unique.sort_by_key(|tile| (tile.x, tile.y));
+            unique.dedup();
+            assert_eq!(unique.len(), tiles.len());
+        }
+    }
+
+    #[test]
+    fn test_extent_from_str() {
+        let extent = Extent(
+            -11711375.725741563,
+            4941042.382410363,
+            -11711222.851684993,
+            4941195.256466932,
+        );
+        let parsed: Extent = extent.to_string().parse().unwrap();
+        assert_eq!(parsed, extent);
+
+        let parsed: Extent = "-1, -2.5, 3, 4e2".parse().unwrap();
+        assert_eq!(parsed, Extent(-1., -2.5, 3., 400.));
+
+        assert_eq!(
+            "1,2,3".parse::<Extent>().unwrap_err(),
+            ExtentParseError::WrongPartCount(3)
+        );
+        assert_eq!(
+            "1,2,3,four".parse::<Extent>().unwrap_err(),
+            ExtentParseError::InvalidCoordinate("four".into())
+        );
+        assert!("".parse::<Extent>().is_err());
+    }
 }
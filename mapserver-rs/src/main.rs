@@ -1,119 +1,2826 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use mapserver_rs::coordinates::Tile;
-use mapserver_rs::mappool::MapPool;
+use mapserver_rs::coordinates::{Point, Tile, MAX_ZOOM, TILE_SIZE};
+use mapserver_rs::handle::{set_proj_data, LoadRetry};
+use mapserver_rs::mappool::{
+    ConfigOptions, Map, MapError, MapPool, MapRenderChannel, OutputFormat, RenderError,
+    RenderOptions, Resample,
+};
+use mapserver_rs::metrics::Metrics;
+use mapserver_rs::ratelimit::RateLimiter;
+use mapserver_rs::remote::{fetch_mapfile, is_remote};
+use mapserver_rs::seed::{seed, DirectoryStore, SeedOptions, TileStore};
+use mapserver_rs::template::{parse_timestamp, CachedTemplate, MapfileTemplate};
+use mapserver_rs::tilecache::TileCache;
+use mapserver_rs::wms::{GetMapRequest, WmsError};
+use mapserver_rs::wmts::{self, WmtsError};
 use mapserver_rs::Extent;
 
-use axum::extract::Path;
-use axum::http::header;
-use axum::response::{Html, IntoResponse};
-use axum::Extension;
+use axum::body::{self, BoxBody};
+use axum::extract::{ConnectInfo, Path, Query};
+use axum::http::header::{self, HeaderName};
+use axum::http::{HeaderMap, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
 use axum::{routing::get, Router};
-use tokio::sync::Mutex;
-
-pub fn make_mapfile_str(timestamp: i64) -> String {
-    format!(
-        "MAP
-          NAME 'default'
-          STATUS ON
-          PROJECTION
-            'init=epsg:3857'
-          END
-          EXTENT -11711375.725741563 4941042.382410363 -11711222.851684993 4941195.256466932
-          UNITS METERS
-          DEBUG 5
-          CONFIG 'CPL_DEBUG' 'ON'
-          CONFIG 'CPL_TIMESTAMP' 'ON'
-          CONFIG 'CPL_LOG' '/dev/stderr'
-          CONFIG 'CPL_LOG_ERRORS' 'ON'
-          CONFIG 'MS_ERRORFILE' '/dev/stderr'
-          CONFIG 'GDAL_DISABLE_READDIR_ON_OPEN' 'TRUE'
-          CONFIG 'GDAL_FORCE_CACHING' 'NO'
-          CONFIG 'GDAL_CACHEMAX' '10%'
-          CONFIG 'VSI_CACHE' 'FALSE'
-          CONFIG 'VSI_CACHE_SIZE' '0'  # bytes
-          CONFIG 'CPL_VSIL_CURL_CACHE_SIZE' '0'  # bytes
-          SIZE 256 256
-          IMAGECOLOR 255 255 255
-          IMAGETYPE 'png'
-          SHAPEPATH '/tmp'
-          LAYER
-            NAME 'default'
-            TYPE RASTER
-            STATUS ON
-            DEBUG 5
-            PROJECTION
-              AUTO
-            END
-            DATA '/home/mperry/work/tiledb/naip/naip-combined'
-            # DATA 's3://perrygeo-tiledb/arrays/naip-2017'
-            CONNECTIONOPTIONS
-              'TILEDB_CONFIG'	'/home/mperry/work/tiledb/tiledb.aws.config'
-              'TILEDB_TIMESTAMP' '{}'
-            END
-            PROCESSING 'CLOSE_CONNECTION=DEFER'
-            PROCESSING 'BANDS=1,2,3,4'
-            PROCESSING 'SCALE_4=0,1'  # Hack to ignore band 4`
-          END
-        END",
-        timestamp
-    )
-}
+use axum::{Extension, Json};
+use hyper::body::Bytes;
+use hyper::server::conn::AddrIncoming;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{field, Instrument, Span};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+// Where the server listens unless --listen or LISTEN_ADDR is given
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:3000";
+
+// Rendered tiles kept in memory across requests
+const TILE_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+// Readiness probes render the mapfile for a fixed timestamp, so they reuse a single map thread
+const PROBE_TIMESTAMP: i64 = 0;
+
+// Number of map threads in the pool, unless MAPSERVER_POOL_SIZE is set
+const POOL_SIZE: usize = 24;
+
+// How long a tile request waits for a render permit before giving up with a 503
+const RENDER_PERMIT_TIMEOUT: Duration = Duration::from_millis(250);
+
+// Seconds clients are asked to wait before retrying a 503
+const RETRY_AFTER_SECONDS: u64 = 1;
+
+// Tile renders slower than this are logged as warnings
+const SLOW_RENDER: Duration = Duration::from_secs(2);
+
+// How long shutdown waits for the last references to the shared state to be released
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Zoom levels served unless MIN_ZOOM and MAX_ZOOM say otherwise
+const DEFAULT_ZOOMS: RangeInclusive<u32> = 0..=24;
+
+// Tiles for a timestamp never change, let clients and proxies hold on to them
+const TILE_MAX_AGE_SECONDS: u64 = 24 * 60 * 60;
+
+// Images larger than this, e.g. metatiles, GeoJSON or big WMS maps, are streamed in chunks
+const STREAM_THRESHOLD_BYTES: usize = 1024 * 1024;
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
 
 #[derive(Debug)]
 struct State {
-    maplock: Mutex<MapPool>,
+    map_pool: MapPool,
+    tile_cache: TileCache,
+    template: CachedTemplate,
+    metrics: Metrics,
+    // Limits concurrent renders, GDAL serializes much of the IO anyway
+    render_permits: Semaphore,
+    // Origins allowed to fetch cross-origin, empty allows any
+    cors_origins: Vec<HeaderValue>,
+    // Requests per client IP, unlimited if None
+    rate_limiter: Option<RateLimiter>,
+    // Proxies whose X-Forwarded-For names the client, e.g. a load balancer
+    trusted_proxies: Vec<IpAddr>,
+    // Zoom levels served by /map, others are a 404
+    zooms: RangeInclusive<u32>,
+    // Answer tiles with nothing drawn with 204 No Content rather than a transparent image
+    no_content_for_empty: bool,
+}
+
+/// The value of a `--flag <value>` or `--flag=value` command line argument
+fn flag_value(args: &[String], flag: &str) -> Option<String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg
+            .strip_prefix(flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// The mapfile template path, from `--mapfile <path>` or else the `MAPFILE` environment variable.
+/// This may be an `s3://` or `https://` URL instead, see `remote::fetch_mapfile`
+fn mapfile_path(args: &[String], env_mapfile: Option<String>) -> Option<PathBuf> {
+    flag_value(args, "--mapfile")
+        .or(env_mapfile)
+        .map(PathBuf::from)
+}
+
+/// The data source for `{data}` in the mapfile template, from `--data <source>` or else the
+/// `DATA_SOURCE` environment variable
+fn data_source(args: &[String], env_data: Option<String>) -> Option<String> {
+    flag_value(args, "--data").or(env_data)
+}
+
+/// The directory of PROJ's data, e.g. proj.db, from `--proj-data <dir>` or else the
+/// `PROJ_DATA` environment variable. None if neither is set, an error if it does not exist
+fn proj_data_dir(
+    args: &[String],
+    env_proj_data: Option<String>,
+) -> Result<Option<PathBuf>, String> {
+    match flag_value(args, "--proj-data").or(env_proj_data) {
+        Some(dir) if !PathBuf::from(&dir).is_dir() => {
+            Err(format!("PROJ data directory {} does not exist", dir))
+        }
+        dir => Ok(dir.map(PathBuf::from)),
+    }
+}
+
+/// The address to listen on, from `--listen <addr:port>` or else the `LISTEN_ADDR`
+/// environment variable, defaulting to all interfaces on port 3000
+fn listen_addr(args: &[String], env_listen: Option<String>) -> Result<SocketAddr, String> {
+    let addr = flag_value(args, "--listen")
+        .or(env_listen)
+        .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+    addr.trim()
+        .parse()
+        .map_err(|_| format!("Invalid listen address '{}', expected <addr:port>", addr))
+}
+
+/// Origins allowed to fetch tiles cross-origin, a comma separated list from
+/// `--cors-origins <origins>` or else the `CORS_ALLOWED_ORIGINS` environment variable.
+/// Unset or `*` allows any origin
+fn cors_origins(args: &[String], env_origins: Option<String>) -> Result<Vec<HeaderValue>, String> {
+    let origins = match flag_value(args, "--cors-origins").or(env_origins) {
+        Some(origins) if origins.trim() != "*" => origins,
+        _ => return Ok(Vec::new()),
+    };
+    origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            HeaderValue::from_str(origin).map_err(|_| format!("Invalid CORS origin '{}'", origin))
+        })
+        .collect()
+}
+
+/// Addresses of the proxies in front of the server, a comma separated list from the
+/// `TRUSTED_PROXIES` environment variable. Only their `X-Forwarded-For` is believed
+fn trusted_proxies(env_proxies: Option<String>) -> Result<Vec<IpAddr>, String> {
+    env_proxies
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| {
+            proxy
+                .parse()
+                .map_err(|_| format!("Invalid trusted proxy address '{}'", proxy))
+        })
+        .collect()
+}
+
+/// Timestamps to load maps for before serving, a comma separated list from
+/// `--warm <timestamps>` or else the `WARM_TIMESTAMPS` environment variable
+fn warm_timestamps(args: &[String], env_warm: Option<String>) -> Result<Vec<i64>, String> {
+    let timestamps = match flag_value(args, "--warm").or(env_warm) {
+        Some(timestamps) => timestamps,
+        None => return Ok(Vec::new()),
+    };
+    timestamps
+        .split(',')
+        .map(str::trim)
+        .filter(|timestamp| !timestamp.is_empty())
+        .map(|timestamp| parse_timestamp(timestamp).map_err(|err| err.to_string()))
+        .collect()
+}
+
+/// The zoom levels to serve from the `MIN_ZOOM` and `MAX_ZOOM` environment variables,
+/// each defaulting to the ends of `DEFAULT_ZOOMS`
+fn zoom_range(
+    env_min: Option<String>,
+    env_max: Option<String>,
+) -> Result<RangeInclusive<u32>, String> {
+    let zoom = |name: &str, value: Option<String>, default: u32| match value {
+        None => Ok(default),
+        Some(value) => value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|zoom| *zoom <= MAX_ZOOM)
+            .ok_or_else(|| {
+                format!(
+                    "{} must be a zoom level from 0 to {}, got '{}'",
+                    name, MAX_ZOOM, value
+                )
+            }),
+    };
+    let minzoom = zoom("MIN_ZOOM", env_min, *DEFAULT_ZOOMS.start())?;
+    let maxzoom = zoom("MAX_ZOOM", env_max, *DEFAULT_ZOOMS.end())?;
+    if minzoom > maxzoom {
+        return Err(format!(
+            "MIN_ZOOM {} is greater than MAX_ZOOM {}",
+            minzoom, maxzoom
+        ));
+    }
+    Ok(minzoom..=maxzoom)
+}
+
+/// Parse an on/off switch from an environment variable, off if it is unset
+fn parse_switch(name: &str, value: Option<String>) -> Result<bool, String> {
+    match value.as_deref().map(str::trim) {
+        None => Ok(false),
+        Some("1" | "true" | "yes" | "on") => Ok(true),
+        Some("0" | "false" | "no" | "off") => Ok(false),
+        Some(value) => Err(format!("{} must be true or false, got '{}'", name, value)),
+    }
+}
+
+/// Parse a positive rate from an environment variable, None if it is unset
+fn parse_rate(name: &str, value: Option<String>) -> Result<Option<f64>, String> {
+    match value {
+        None => Ok(None),
+        Some(value) => value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| rate.is_finite() && *rate > 0.)
+            .map(Some)
+            .ok_or_else(|| format!("{} must be a positive number, got '{}'", name, value)),
+    }
+}
+
+/// Parse a positive count from an environment variable, using the default if it is unset
+fn parse_count(name: &str, value: Option<String>, default: usize) -> Result<usize, String> {
+    match value {
+        None => Ok(default),
+        Some(value) => value
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|count| *count >= 1)
+            .ok_or_else(|| format!("{} must be a positive integer, got '{}'", name, value)),
+    }
+}
+
+/// The value of a startup step, or exit after printing why it failed
+fn or_exit<T>(result: Result<T, String>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    // JSON logs, with a summary of each request span as it closes, when RUST_LOG asks for them.
+    // Otherwise plain logs of info events such as warm-up results, without request summaries
+    if std::env::var_os("RUST_LOG").is_some() {
+        tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_span_events(FmtSpan::CLOSE)
+            .init();
+    } else {
+        tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new("info"))
+            .init();
+    }
+
+    // Load the mapfile template, refusing to start rather than serving broken tiles
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = or_exit(
+        mapfile_path(&args, std::env::var("MAPFILE").ok()).ok_or_else(|| {
+            "No mapfile configured, pass --mapfile <path> or set MAPFILE".to_string()
+        }),
+    );
+    let template = match path.to_str().filter(|source| is_remote(source)) {
+        // Fetched once, the template is then held in memory
+        Some(url) => or_exit(
+            fetch_mapfile(
+                url,
+                std::env::var("AWS_ENDPOINT_URL").ok().as_deref(),
+                std::env::var("AWS_REGION").ok().as_deref(),
+            )
+            .await
+            .map(MapfileTemplate::new)
+            .map_err(|err| format!("Unable to fetch mapfile {}: {}", url, err)),
+        ),
+        None => or_exit(
+            MapfileTemplate::from_path(&path)
+                .map_err(|err| format!("Unable to read mapfile {}: {}", path.display(), err)),
+        ),
+    };
+    let template = or_exit(
+        match data_source(&args, std::env::var("DATA_SOURCE").ok()) {
+            Some(data) => template
+                .with_data(&data)
+                .map_err(|err| format!("Invalid data source: {}", err)),
+            None if template.uses_data() => Err(format!(
+                "{} uses {{data}}, pass --data <source> or set DATA_SOURCE",
+                path.display()
+            )),
+            None => Ok(template),
+        },
+    );
+
+    // Reprojection needs proj.db, which minimal containers may not have where PROJ looks
+    let proj_data = or_exit(proj_data_dir(&args, std::env::var("PROJ_DATA").ok()));
+    or_exit(set_proj_data(proj_data.as_deref()));
+
+    // `seed` pre-renders tiles to disk and exits instead of serving
+    if args.first().map(String::as_str) == Some("seed") {
+        std::process::exit(run_seed(&template, &args[1..]));
+    }
+    // `validate` checks the mapfile loads and its data is reachable, then exits
+    if args.first().map(String::as_str) == Some("validate") {
+        std::process::exit(run_validate(&template, &args[1..]));
+    }
+
+    let pool_size = or_exit(parse_count(
+        "MAPSERVER_POOL_SIZE",
+        std::env::var("MAPSERVER_POOL_SIZE").ok(),
+        POOL_SIZE,
+    ));
+
+    let addr = or_exit(listen_addr(&args, std::env::var("LISTEN_ADDR").ok()));
+
+    let max_renders = or_exit(parse_count(
+        "MAX_CONCURRENT_RENDERS",
+        std::env::var("MAX_CONCURRENT_RENDERS").ok(),
+        pool_size,
+    ));
+
+    let cors_origins = or_exit(cors_origins(
+        &args,
+        std::env::var("CORS_ALLOWED_ORIGINS").ok(),
+    ));
+
+    // Per client limits, e.g. against scrapers, if RATE_LIMIT_PER_SECOND is set. The burst
+    // defaults to a second's worth of requests
+    let rate_limiter = or_exit(
+        parse_rate(
+            "RATE_LIMIT_PER_SECOND",
+            std::env::var("RATE_LIMIT_PER_SECOND").ok(),
+        )
+        .and_then(|rate| match rate {
+            Some(rate) => parse_count(
+                "RATE_LIMIT_BURST",
+                std::env::var("RATE_LIMIT_BURST").ok(),
+                rate.ceil() as usize,
+            )
+            .map(|burst| Some(RateLimiter::new(rate, burst.min(u32::MAX as usize) as u32))),
+            None => Ok(None),
+        }),
+    );
+
+    let trusted_proxies = or_exit(trusted_proxies(std::env::var("TRUSTED_PROXIES").ok()));
+
+    // Loads are retried when data sources fail to initialize, e.g. unreachable object storage
+    let load_attempts = or_exit(parse_count(
+        "MAPSERVER_LOAD_ATTEMPTS",
+        std::env::var("MAPSERVER_LOAD_ATTEMPTS").ok(),
+        1,
+    ));
+
+    // GDAL tuning applied to every map, e.g. MAPSERVER_CONFIG_OPTIONS=GDAL_CACHEMAX=512
+    let config_options = or_exit(
+        std::env::var("MAPSERVER_CONFIG_OPTIONS")
+            .unwrap_or_default()
+            .parse::<ConfigOptions>()
+            .map_err(|err| format!("MAPSERVER_CONFIG_OPTIONS: {}", err)),
+    );
+
+    let zooms = or_exit(zoom_range(
+        std::env::var("MIN_ZOOM").ok(),
+        std::env::var("MAX_ZOOM").ok(),
+    ));
+
+    // MapLibre and others skip 204 tiles, but some clients need every tile to be an image
+    let no_content_for_empty = or_exit(parse_switch(
+        "EMPTY_TILE_NO_CONTENT",
+        std::env::var("EMPTY_TILE_NO_CONTENT").ok(),
+    ));
+
+    let warm = or_exit(warm_timestamps(
+        &args,
+        std::env::var("WARM_TIMESTAMPS").ok(),
+    ));
+
     // Set up shared state
-    let map_pool = MapPool::create(24);
+    let map_pool = MapPool::create(pool_size)
+        .with_load_retry(LoadRetry {
+            attempts: load_attempts.min(u32::MAX as usize) as u32,
+            ..Default::default()
+        })
+        .with_config_options(config_options);
     let shared_state = Arc::new(State {
-        maplock: Mutex::new(map_pool),
+        map_pool,
+        tile_cache: TileCache::new(TILE_CACHE_BYTES),
+        template: CachedTemplate::new(template),
+        metrics: Metrics::new(),
+        render_permits: Semaphore::new(max_renders),
+        cors_origins,
+        rate_limiter,
+        trusted_proxies,
+        zooms,
+        no_content_for_empty,
     });
 
-    // Routes
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/map/:timestamp/:z/:x/:y", get(render_map))
-        .layer(Extension(shared_state));
+    // Bind up front, so a port in use stops startup with a clear error
+    let incoming = or_exit(
+        AddrIncoming::bind(&addr).map_err(|err| format!("Unable to listen on {}: {}", addr, err)),
+    );
 
-    // Spawn the web handler
-    tokio::spawn(async move {
-        println!("Listening on 0.0.0.0:3000");
-        axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-            .serve(app.into_make_service())
+    warm_up(&shared_state, &warm).await;
+
+    println!("Listening on {}", addr);
+    or_exit(
+        serve(incoming, shared_state, shutdown_signal())
             .await
-            .unwrap();
+            .map_err(|err| format!("Server error: {}", err)),
+    );
+}
+
+/// Options and output for `seed --extent <minx,miny,maxx,maxy> --minzoom <z> --maxzoom <z> --output <path>`,
+/// with the extent in epsg:3857 and an optional `--timestamp`, which defaults to now.
+/// The output is a directory, or an MBTiles file when it ends in `.mbtiles`
+fn seed_options(args: &[String]) -> Result<(SeedOptions, PathBuf), String> {
+    let required =
+        |flag: &str| flag_value(args, flag).ok_or_else(|| format!("seed requires {}", flag));
+    let zoom = |flag: &str| {
+        let value = required(flag)?;
+        value
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| format!("{} must be a zoom level, got '{}'", flag, value))
+    };
+
+    let extent = required("--extent")?
+        .parse::<Extent>()
+        .map_err(|err| format!("Invalid --extent: {}", err))?;
+    let timestamp = match flag_value(args, "--timestamp") {
+        Some(value) => value
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| format!("--timestamp must be an integer, got '{}'", value))?,
+        None => now_timestamp(),
+    };
+    let options = SeedOptions {
+        extent,
+        min_zoom: zoom("--minzoom")?,
+        max_zoom: zoom("--maxzoom")?,
+        timestamp,
+    };
+    Ok((options, PathBuf::from(required("--output")?)))
+}
+
+/// Run the seed subcommand, returning the process exit code
+fn run_seed(template: &MapfileTemplate, args: &[String]) -> i32 {
+    let (options, output) = match seed_options(args) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    if output.extension() == Some(std::ffi::OsStr::new("mbtiles")) {
+        #[cfg(feature = "mbtiles")]
+        {
+            let name = output
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let store = mapserver_rs::mbtiles::MbtilesWriter::create(&output)
+                .and_then(|writer| writer.write_metadata(&name, &options).map(|_| writer));
+            return match store {
+                Ok(mut store) => seed_into(template, &options, &mut store, &output),
+                Err(err) => {
+                    eprintln!("Seeding failed: {}", err);
+                    1
+                }
+            };
+        }
+        #[cfg(not(feature = "mbtiles"))]
+        {
+            eprintln!("Seeding into an MBTiles file needs the mbtiles feature");
+            return 1;
+        }
+    }
+    seed_into(
+        template,
+        &options,
+        &mut DirectoryStore::new(&output),
+        &output,
+    )
+}
+
+/// Run the validate subcommand, `validate [--timestamp <timestamp>]`, printing a report
+/// and returning the process exit code
+fn run_validate(template: &MapfileTemplate, args: &[String]) -> i32 {
+    let timestamp = match flag_value(args, "--timestamp") {
+        Some(value) => match parse_timestamp(&value) {
+            Ok(timestamp) => timestamp,
+            Err(err) => {
+                eprintln!("{}", err);
+                return 2;
+            }
+        },
+        None => now_timestamp(),
+    };
+    match validate(template, timestamp) {
+        Ok(report) => {
+            print!("{}", report);
+            0
+        }
+        Err(report) => {
+            print!("{}", report);
+            1
+        }
+    }
+}
+
+/// Load the mapfile for a timestamp and open each layer's data source, with one line per
+/// check. The report is an error if any check failed
+fn validate(template: &MapfileTemplate, timestamp: i64) -> Result<String, String> {
+    let map = match Map::try_from(template.render(timestamp)) {
+        Ok(map) => map,
+        Err(err) => return Err(format!("FAIL mapfile: {}\n", err)),
+    };
+    let mut report = String::from("ok   mapfile\n");
+    let mut failed = false;
+    for (name, check) in map.check_layers() {
+        match check {
+            Ok(()) => report.push_str(&format!("ok   layer {}\n", name)),
+            Err(message) => {
+                failed = true;
+                report.push_str(&format!("FAIL layer {}: {}\n", name, message));
+            }
+        }
+    }
+    if failed {
+        Err(report)
+    } else {
+        Ok(report)
+    }
+}
+
+/// Seed into a store, printing progress as tiles are rendered
+fn seed_into(
+    template: &MapfileTemplate,
+    options: &SeedOptions,
+    store: &mut impl TileStore,
+    output: &std::path::Path,
+) -> i32 {
+    let result = seed(template, options, store, |progress| {
+        eprint!(
+            "\rSeeded {}/{} tiles, {} already stored",
+            progress.rendered + progress.skipped,
+            progress.total,
+            progress.skipped
+        );
     });
+    eprintln!();
+    match result {
+        Ok(progress) => {
+            println!(
+                "Rendered {} tiles into {}",
+                progress.rendered,
+                output.display()
+            );
+            0
+        }
+        Err(err) => {
+            eprintln!("Seeding failed: {}", err);
+            1
+        }
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM where there is one
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Unable to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Serve requests until `shutdown` resolves, then let requests in flight finish
+/// and stop the map threads
+async fn serve(
+    incoming: AddrIncoming,
+    state: Arc<State>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), hyper::Error> {
+    axum::Server::builder(incoming)
+        .serve(app(state.clone()).into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    shutdown_pool(state).await;
+    Ok(())
+}
+
+/// Shut down the map pool once nothing else holds the state, so mapserver is cleaned up once
+/// and only after the last render
+async fn shutdown_pool(mut state: Arc<State>) {
+    // Every request has been answered, but blocking tasks they started may still be returning
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    loop {
+        match Arc::try_unwrap(state) {
+            Ok(state) => {
+                tokio::task::spawn_blocking(move || state.map_pool.shutdown())
+                    .await
+                    .ok();
+                return;
+            }
+            Err(shared) if Instant::now() < deadline => {
+                state = shared;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Err(_) => {
+                tracing::warn!("state still in use, exiting without stopping the map pool");
+                return;
+            }
+        }
+    }
+}
+
+fn app(state: Arc<State>) -> Router {
+    // Everything is read-only, so browsers only ever need GET across origins
+    let allow_origin = if state.cors_origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(state.cors_origins.clone())
+    };
+    // The CORS layer sets Vary on every response, so it also carries the Accept
+    // the tile route negotiates its format on, for shared caches
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_origin(allow_origin)
+        .vary([
+            header::ORIGIN,
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            header::ACCEPT,
+        ]);
+
+    // gzip, deflate or br for text such as GeoJSON and capabilities documents. Images
+    // are skipped, they are compressed already, as are formats only known as binary
+    let compression = CompressionLayer::new().compress_when(
+        DefaultPredicate::new().and(NotForContentType::const_new("application/octet-stream")),
+    );
+
+    let limit_state = state.clone();
+    let rate_limit =
+        middleware::from_fn(move |request, next| rate_limit(limit_state.clone(), request, next));
+
+    Router::new()
+        .route("/", get(index))
+        .route("/map/:timestamp/:z/:x/:y", get(render_map))
+        .route("/mvt/:z/:x/:y", get(render_mvt))
+        .route("/geojson/:z/:x/:y", get(render_geojson))
+        .route("/capabilities.json", get(capabilities))
+        .route("/tile.json", get(tilejson))
+        .route("/wms", get(wms))
+        .route("/wmts/capabilities.xml", get(wmts_capabilities))
+        .route(
+            "/wmts/:layer/:tile_matrix_set/:tile_matrix/:tile_col/:tile_row",
+            get(wmts_tile),
+        )
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .layer(Extension(state))
+        .layer(rate_limit)
+        .layer(cors)
+        .layer(compression)
+}
+
+/// Turn away clients over the rate limit with 429 Too Many Requests.
+/// Health checks and metrics are never limited
+async fn rate_limit<B>(state: Arc<State>, request: Request<B>, next: Next<B>) -> Response {
+    let limiter = match &state.rate_limiter {
+        Some(limiter) => limiter,
+        None => return next.run(request).await,
+    };
+    if matches!(request.uri().path(), "/healthz" | "/readyz" | "/metrics") {
+        return next.run(request).await;
+    }
 
-    // And wait for an interupt signal
-    tokio::signal::ctrl_c().await.unwrap();
+    // Requests from an unknown address share a single bucket
+    let client = client_ip(&request, &state.trusted_proxies).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+    match limiter.check(client) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.) as u64;
+            tracing::warn!(%client, "rate limited");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                "Too many requests",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The client's address, the address of the connection unless that is a trusted proxy.
+/// Then `X-Forwarded-For` is read from the right, past any further trusted proxies, since
+/// the entries before those are whatever the client sent
+fn client_ip<B>(request: &Request<B>, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<&str> = request
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut client = peer;
+    for addr in forwarded
+        .iter()
+        .rev()
+        .map_while(|addr| addr.trim().parse().ok())
+    {
+        client = addr;
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+    }
+    Some(client)
 }
 
 async fn index() -> Html<&'static str> {
     Html(include_str!("index.html"))
 }
 
+/// A tile for the mapfile template filled with the `:timestamp` segment,
+/// an integer or an RFC3339 datetime. The `layers` and `style` parameters select the layers
+/// to draw and the class GROUP to draw them with, `resample` how rasters are resampled
+/// and `transparent` whether the background is.
+/// The image format is negotiated from the `Accept` header. Zooms outside of the configured
+/// range are a 404. A `@2x` row, e.g. `/map/:timestamp/7/26/48@2x`, is a 512px HiDPI tile of
+/// the same extent
 async fn render_map(
-    Path((timestamp, z, x, y)): Path<(i64, u32, u32, u32)>,
+    Path((timestamp, z, x, y)): Path<(String, u32, u32, String)>,
+    Query(query): Query<RenderQuery>,
+    headers: HeaderMap,
     Extension(state): Extension<Arc<State>>,
-) -> impl IntoResponse {
+) -> Response {
+    let timestamp = match parse_timestamp(&timestamp) {
+        Ok(timestamp) => timestamp,
+        Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let (y, scale) = match parse_tile_row(&y) {
+        Some(row) => row,
+        None => return (StatusCode::NOT_FOUND, format!("Invalid tile row {}", y)).into_response(),
+    };
+    if !state.zooms.contains(&z) {
+        return (
+            StatusCode::NOT_FOUND,
+            format!(
+                "Zoom {} is outside of {} to {}",
+                z,
+                state.zooms.start(),
+                state.zooms.end()
+            ),
+        )
+            .into_response();
+    }
+    let span = tracing::info_span!(
+        "render_tile",
+        timestamp,
+        z,
+        x,
+        y,
+        cache = field::Empty,
+        bytes = field::Empty,
+        render_ms = field::Empty,
+    );
+    let resample = match query.resample.as_deref().map(str::parse::<Resample>) {
+        Some(Ok(resample)) => Some(resample),
+        Some(Err(err)) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        None => None,
+    };
+    let transparent = match query.transparent.as_deref().map(str::parse::<bool>) {
+        Some(Ok(transparent)) => Some(transparent),
+        Some(Err(_)) => {
+            return (StatusCode::BAD_REQUEST, "transparent must be true or false").into_response()
+        }
+        None => None,
+    };
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(OutputFormat::accepted)
+        .unwrap_or_default();
+    let size = (scale > 1).then(|| {
+        let size = (TILE_SIZE * scale) as i32;
+        (size, size)
+    });
+    let options = RenderOptions {
+        size,
+        layers: query.layers(),
+        style: query.style,
+        resample,
+        accept,
+        transparent,
+        ..Default::default()
+    };
+    render_tile(
+        timestamp,
+        Tile::from_zxy(z, x, y),
+        options,
+        headers,
+        state,
+        span.clone(),
+    )
+    .instrument(span)
+    .await
+}
+
+/// The row and scale of a `/map` tile row, which has an optional `@2x` suffix for HiDPI tiles
+fn parse_tile_row(row: &str) -> Option<(u32, u32)> {
+    let (row, scale) = match row.strip_suffix("@2x") {
+        Some(row) => (row, 2),
+        None => (row, 1),
+    };
+    row.parse().ok().map(|row| (row, scale))
+}
+
+/// A Mapbox Vector Tile of the vector layers, `/mvt/:z/:x/:y.pbf`, for the mapfile
+/// template filled with the `timestamp` parameter, defaulting to now
+async fn render_mvt(
+    Path((z, x, y)): Path<(u32, u32, String)>,
+    Query(query): Query<MetadataQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let y = match y.strip_suffix(".pbf").unwrap_or(&y).parse::<u32>() {
+        Ok(y) => y,
+        Err(_) => {
+            return (StatusCode::NOT_FOUND, format!("Invalid tile row {}", y)).into_response()
+        }
+    };
+    let timestamp = query.timestamp.unwrap_or_else(now_timestamp);
+    let span = tracing::info_span!(
+        "render_tile",
+        timestamp,
+        z,
+        x,
+        y,
+        format = "mvt",
+        cache = field::Empty,
+        bytes = field::Empty,
+        render_ms = field::Empty,
+    );
+    render_tile(
+        timestamp,
+        Tile::from_zxy(z, x, y),
+        RenderOptions {
+            format: Some(OutputFormat::Mvt),
+            ..Default::default()
+        },
+        headers,
+        state,
+        span.clone(),
+    )
+    .instrument(span)
+    .await
+}
+
+/// The features of the queryable layers within a tile as GeoJSON, `/geojson/:z/:x/:y`,
+/// for the mapfile template filled with the `timestamp` parameter, defaulting to now
+async fn render_geojson(
+    Path((z, x, y)): Path<(u32, u32, String)>,
+    Query(query): Query<MetadataQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let y = match y.strip_suffix(".json").unwrap_or(&y).parse::<u32>() {
+        Ok(y) => y,
+        Err(_) => {
+            return (StatusCode::NOT_FOUND, format!("Invalid tile row {}", y)).into_response()
+        }
+    };
+    let timestamp = query.timestamp.unwrap_or_else(now_timestamp);
+    let span = tracing::info_span!(
+        "render_tile",
+        timestamp,
+        z,
+        x,
+        y,
+        format = "geojson",
+        cache = field::Empty,
+        bytes = field::Empty,
+        render_ms = field::Empty,
+    );
+    render_tile(
+        timestamp,
+        Tile::from_zxy(z, x, y),
+        RenderOptions {
+            format: Some(OutputFormat::GeoJson),
+            ..Default::default()
+        },
+        headers,
+        state,
+        span.clone(),
+    )
+    .instrument(span)
+    .await
+}
+
+/// Render a tile in the mapfile's default output format and layers, or with overrides
+async fn render_tile(
+    timestamp: i64,
+    tile: Tile,
+    options: RenderOptions,
+    headers: HeaderMap,
+    state: Arc<State>,
+    span: Span,
+) -> Response {
+    if let Err(err) = Tile::try_from_zxy(tile.zoom, tile.x, tile.y) {
+        return (StatusCode::NOT_FOUND, err.to_string()).into_response();
+    }
+
     // Create mapfile
-    let tile = Tile::from_zxy(z, x, y);
     let extent = Extent::from(tile.bbox_mercator());
-    let mapfile_str = make_mapfile_str(timestamp);
+    let mapfile_str = state.template.render(timestamp);
+    // Tiles in other formats or with other layers are cached apart from the defaults
+    let mut cache_key = mapfile_str.clone();
+    if let Some((width, height)) = options.size {
+        cache_key.push_str(&format!("\n# size={}x{}", width, height));
+    }
+    if let Some(format) = &options.format {
+        cache_key.push_str(&format!("\n# {}", format.name()));
+    }
+    if let Some(layers) = &options.layers {
+        cache_key.push_str(&format!("\n# layers={}", layers.join(",")));
+    }
+    if let Some(style) = &options.style {
+        cache_key.push_str(&format!("\n# style={}", style));
+    }
+    if let Some(resample) = &options.resample {
+        cache_key.push_str(&format!("\n# resample={}", resample.name()));
+    }
+    if let Some(transparent) = options.transparent {
+        cache_key.push_str(&format!("\n# transparent={}", transparent));
+    }
+    if !options.accept.is_empty() {
+        let accept: Vec<&str> = options.accept.iter().map(OutputFormat::name).collect();
+        cache_key.push_str(&format!("\n# accept={}", accept.join(",")));
+    }
+
+    let cached = state.tile_cache.get(&cache_key, &tile);
+    state.metrics.record_cache(cached.is_some());
+    let (image, cache_status) = match cached {
+        Some(image) => (image, "HIT"),
+        None => {
+            let _permit = match render_permit(&state).await {
+                Ok(permit) => permit,
+                Err(response) => return response,
+            };
+            let started = Instant::now();
 
-    // Get a renderer from the map pool
-    let renderer = {
-        let mut map_pool = state.maplock.lock().await;
-        map_pool.acquire_or_create(mapfile_str)
+            // Get a renderer from the map pool
+            let renderer = match acquire_renderer(&state, mapfile_str.clone()).await {
+                Ok(renderer) => renderer,
+                Err(err) => {
+                    state.metrics.record_render(started.elapsed(), false);
+                    tracing::error!(%err, "unable to acquire map");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+                }
+            };
+
+            // Yes, we can render concurrently on multiple threads!
+            // GDAL may lock things internally though, negating much of the benefit
+            let result = renderer.render_async(extent, options).await;
+            state
+                .metrics
+                .record_render(started.elapsed(), result.is_ok());
+            let render_ms = started.elapsed().as_millis() as u64;
+            span.record("render_ms", render_ms);
+            if started.elapsed() > SLOW_RENDER {
+                tracing::warn!(render_ms, "slow render");
+            }
+
+            match result {
+                Ok(image) => {
+                    state.tile_cache.insert(&cache_key, &tile, image.clone());
+                    (image, "MISS")
+                }
+                // The map has no output in the requested format
+                Err(err @ (RenderError::UnknownFormat(_) | RenderError::NotVector(_))) => {
+                    return (StatusCode::NOT_FOUND, err.to_string()).into_response();
+                }
+                Err(err @ (RenderError::UnknownLayer(_) | RenderError::UnknownStyle(_))) => {
+                    return (StatusCode::BAD_REQUEST, err.to_string()).into_response();
+                }
+                Err(err) => {
+                    tracing::error!(%err, "render failed");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response();
+                }
+            }
+        }
+    };
+    span.record("cache", cache_status);
+    span.record("bytes", image.bytes.len());
+
+    let etag = etag(&image.bytes);
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", TILE_MAX_AGE_SECONDS),
+        ),
+        (HeaderName::from_static("x-cache"), cache_status.to_string()),
+    ];
+
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| etag_matches(value, &etag));
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    if image.empty && state.no_content_for_empty {
+        return (StatusCode::NO_CONTENT, cache_headers).into_response();
+    }
+    (
+        cache_headers,
+        [(header::CONTENT_TYPE, image.content_type)],
+        image_body(image.bytes),
+    )
+        .into_response()
+}
+
+/// The response body for a rendered image. Small images are sent as they are, larger ones
+/// are streamed in chunks that share the rendered buffer, so bytes flush to the client as
+/// it reads them rather than being copied into one giant write
+fn image_body(bytes: Vec<u8>) -> BoxBody {
+    if bytes.len() <= STREAM_THRESHOLD_BYTES {
+        return body::boxed(hyper::Body::from(bytes));
+    }
+    let bytes = Bytes::from(bytes);
+    let (mut sender, stream) = hyper::Body::channel();
+    tokio::spawn(async move {
+        for start in (0..bytes.len()).step_by(STREAM_CHUNK_BYTES) {
+            let end = (start + STREAM_CHUNK_BYTES).min(bytes.len());
+            // The client went away
+            if sender.send_data(bytes.slice(start..end)).await.is_err() {
+                break;
+            }
+        }
+    });
+    body::boxed(stream)
+}
+
+/// Load the map for each timestamp and render a probe tile, so the first requests for them
+/// find the map thread running and its data sources open. Failures are logged, not fatal
+async fn warm_up(state: &Arc<State>, timestamps: &[i64]) {
+    let extent = Extent::from(Tile::from_zxy(0, 0, 0).bbox_mercator());
+    for &timestamp in timestamps {
+        let started = Instant::now();
+        let result = match acquire_renderer(state, state.template.render(timestamp)).await {
+            Ok(renderer) => renderer
+                .render_async(extent, RenderOptions::default())
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let warm_ms = started.elapsed().as_millis() as u64;
+        match result {
+            Ok(_) => tracing::info!(timestamp, warm_ms, "warmed map"),
+            Err(err) => tracing::warn!(timestamp, warm_ms, %err, "warm-up failed"),
+        }
+    }
+}
+
+/// Wait briefly for a render slot, shedding load with 503 Service Unavailable rather than
+/// queueing without bound. Hold the permit until the render is done
+async fn render_permit(state: &State) -> Result<SemaphorePermit<'_>, Response> {
+    match tokio::time::timeout(RENDER_PERMIT_TIMEOUT, state.render_permits.acquire()).await {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => {
+            tracing::warn!("no render permit available");
+            Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, RETRY_AFTER_SECONDS.to_string())],
+                "Too many concurrent renders",
+            )
+                .into_response())
+        }
+    }
+}
+
+/// Acquire a renderer on the blocking threadpool, since a new map is loaded before returning
+async fn acquire_renderer(
+    state: &Arc<State>,
+    mapfile_str: String,
+) -> Result<MapRenderChannel, MapError> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || state.map_pool.acquire_or_create(mapfile_str))
+        .await
+        .unwrap_or_else(|err| Err(MapError::LoadFailed(err.to_string())))
+}
+
+/// WMS GetMap. The mapfile template is filled with the vendor-specific
+/// `TIMESTAMP` parameter, defaulting to now
+async fn wms(
+    Query(params): Query<HashMap<String, String>>,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let request = match GetMapRequest::from_params(&params) {
+        Ok(request) => request,
+        Err(err) => return service_exception(err),
     };
+    let timestamp = params
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("TIMESTAMP"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or_else(now_timestamp);
+
+    // GetMap images may be far larger than tiles, so they count against the same limit
+    let _permit = match render_permit(&state).await {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+    let renderer = match acquire_renderer(&state, state.template.render(timestamp)).await {
+        Ok(renderer) => renderer,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+
+    let options = RenderOptions {
+        size: Some((request.width, request.height)),
+        format: request.format.map(OutputFormat::Named),
+        // The mapfile is in epsg:3857 like the tiles, other CRSs are reprojected
+        epsg: (request.epsg != 3857).then_some(request.epsg),
+        ..Default::default()
+    };
+    match renderer.render_async(request.extent, options).await {
+        Ok(image) => (
+            [(header::CONTENT_TYPE, image.content_type)],
+            image_body(image.bytes),
+        )
+            .into_response(),
+        Err(RenderError::UnknownFormat(format)) => {
+            service_exception(WmsError::InvalidFormat(format))
+        }
+        Err(RenderError::InvalidSize { width, height }) => {
+            service_exception(WmsError::InvalidParameter {
+                name: "WIDTH/HEIGHT",
+                value: format!("{}x{}", width, height),
+            })
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// A WMTS RESTful tile, `/wmts/{layer}/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.png`,
+/// for the mapfile template filled with the `timestamp` parameter, defaulting to now
+async fn wmts_tile(
+    Path((layer, tile_matrix_set, tile_matrix, tile_col, tile_row)): Path<(
+        String,
+        String,
+        String,
+        String,
+        String,
+    )>,
+    Query(query): Query<MetadataQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let tile = match wmts::parse_tile(&layer, &tile_matrix_set, &tile_matrix, &tile_col, &tile_row)
+    {
+        Ok(tile) => tile,
+        Err(err) => return wmts_exception(err),
+    };
+    let timestamp = query.timestamp.unwrap_or_else(now_timestamp);
+    let span = tracing::info_span!(
+        "render_tile",
+        timestamp,
+        z = tile.zoom,
+        x = tile.x,
+        y = tile.y,
+        cache = field::Empty,
+        bytes = field::Empty,
+        render_ms = field::Empty,
+    );
+    render_tile(
+        timestamp,
+        tile,
+        RenderOptions::default(),
+        headers,
+        state,
+        span.clone(),
+    )
+    .instrument(span)
+    .await
+}
+
+/// The WMTS Capabilities document, with tile URLs for the `timestamp` parameter
+async fn wmts_capabilities(
+    Query(query): Query<MetadataQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> Response {
+    let timestamp = query.timestamp.unwrap_or_else(now_timestamp);
+    let summary = match map_summary(state.template.render(timestamp)).await {
+        Ok(summary) => summary,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+    (
+        [(header::CONTENT_TYPE, "application/xml")],
+        wmts::capabilities_xml(
+            &base_url(&headers),
+            timestamp,
+            wgs84_bounds(summary.extent),
+            &summary.content_type,
+        ),
+    )
+        .into_response()
+}
+
+fn wmts_exception(err: WmtsError) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        [(header::CONTENT_TYPE, "application/xml")],
+        err.to_xml(),
+    )
+        .into_response()
+}
+
+/// Prometheus metrics
+async fn metrics(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    let live_maps = state.map_pool.live_map_count();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.to_prometheus(live_maps),
+    )
+}
+
+/// Liveness, the server is up and handling requests
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness, a map can be loaded from the pool and rendered.
+/// Catches unreachable data sources and broken GDAL configuration, and a server whose
+/// render slots are all busy
+async fn readyz(Extension(state): Extension<Arc<State>>) -> Response {
+    let _permit = match render_permit(&state).await {
+        Ok(permit) => permit,
+        Err(response) => return response,
+    };
+    let renderer = match acquire_renderer(&state, state.template.render(PROBE_TIMESTAMP)).await {
+        Ok(renderer) => renderer,
+        Err(err) => return (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response(),
+    };
+    let options = RenderOptions {
+        size: Some((1, 1)),
+        ..Default::default()
+    };
+    match renderer
+        .render_async(Extent::new(0., 0., 1., 1.), options)
+        .await
+    {
+        Ok(_) => "ok".into_response(),
+        Err(err) => (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response(),
+    }
+}
+
+fn service_exception(err: WmsError) -> Response {
+    (
+        StatusCode::BAD_REQUEST,
+        [(header::CONTENT_TYPE, "text/xml")],
+        err.to_xml(),
+    )
+        .into_response()
+}
+
+/// Optional query parameters for the tile endpoint
+#[derive(Debug, Deserialize)]
+struct RenderQuery {
+    /// Comma separated names of the layers to draw, defaults to the mapfile's STATUS
+    layers: Option<String>,
+    /// Class GROUP to draw, defaults to the mapfile's CLASSGROUPs
+    style: Option<String>,
+    /// Raster resampling, nearest, bilinear or average, defaults to the mapfile's RESAMPLE
+    resample: Option<String>,
+    /// Draw on a transparent background, e.g. for overlays, defaults to the format's TRANSPARENT
+    transparent: Option<String>,
+}
+
+impl RenderQuery {
+    fn layers(&self) -> Option<Vec<String>> {
+        self.layers.as_ref().map(|layers| {
+            layers
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+    }
+}
+
+/// Optional query parameters for the metadata and WMTS endpoints
+#[derive(Debug, Deserialize)]
+struct MetadataQuery {
+    /// Timestamp to load the mapfile template with, defaults to now
+    timestamp: Option<i64>,
+}
+
+/// A lightweight, TileJSON-ish description of the tile service for client auto-configuration
+#[derive(Debug, Deserialize, Serialize)]
+struct Capabilities {
+    /// Tile URL template with `{timestamp}`, `{z}`, `{x}` and `{y}` placeholders
+    tiles: String,
+    minzoom: u32,
+    maxzoom: u32,
+    /// MIME types of the tile images
+    formats: Vec<String>,
+    /// Extent of the layers as (minx, miny, maxx, maxy), in epsg:3857
+    extent: Extent,
+}
+
+async fn capabilities(
+    Query(query): Query<MetadataQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> impl IntoResponse {
+    let timestamp = query.timestamp.unwrap_or_else(now_timestamp);
+    let summary = match map_summary(state.template.render(timestamp)).await {
+        Ok(summary) => summary,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+
+    Json(Capabilities {
+        tiles: format!("{}/map/{{timestamp}}/{{z}}/{{x}}/{{y}}", base_url(&headers)),
+        minzoom: *state.zooms.start(),
+        maxzoom: *state.zooms.end(),
+        formats: vec![summary.content_type],
+        extent: summary.extent,
+    })
+    .into_response()
+}
+
+/// A TileJSON 3.0.0 document, https://github.com/mapbox/tilejson-spec/tree/master/3.0.0
+#[derive(Debug, Deserialize, Serialize)]
+struct TileJson {
+    tilejson: String,
+    tiles: Vec<String>,
+    minzoom: u32,
+    maxzoom: u32,
+    /// (west, south, east, north) in WGS84
+    bounds: [f64; 4],
+    /// (longitude, latitude, zoom)
+    center: [f64; 3],
+}
+
+async fn tilejson(
+    Query(query): Query<MetadataQuery>,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+) -> impl IntoResponse {
+    let timestamp = query.timestamp.unwrap_or_else(now_timestamp);
+    let summary = match map_summary(state.template.render(timestamp)).await {
+        Ok(summary) => summary,
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+
+    let extent = summary.extent;
+    let [west, south, east, north] = wgs84_bounds(extent);
+
+    // Zoom in until the extent spans about a single tile
+    let world = Extent::from(Tile::from_zxy(0, 0, 0).bbox_mercator()).width();
+    let span = extent.width().max(extent.height());
+    let zoom = if span > 0. {
+        (world / span)
+            .log2()
+            .floor()
+            .clamp(*state.zooms.start() as f64, *state.zooms.end() as f64)
+    } else {
+        *state.zooms.start() as f64
+    };
+
+    Json(TileJson {
+        tilejson: "3.0.0".to_string(),
+        tiles: vec![format!(
+            "{}/map/{}/{{z}}/{{x}}/{{y}}",
+            base_url(&headers),
+            timestamp
+        )],
+        minzoom: *state.zooms.start(),
+        maxzoom: *state.zooms.end(),
+        bounds: [west, south, east, north],
+        center: [(west + east) / 2., (south + north) / 2., zoom],
+    })
+    .into_response()
+}
+
+/// (west, south, east, north) in WGS84 of an epsg:3857 extent
+fn wgs84_bounds(extent: Extent) -> [f64; 4] {
+    let sw = Point::new(extent.minx(), extent.miny()).to_wgs84();
+    let ne = Point::new(extent.maxx(), extent.maxy()).to_wgs84();
+    [sw.x, sw.y, ne.x, ne.y]
+}
 
-    // Yes, we can render concurrently on multiple threads!
-    // GDAL may lock things internally though, negating much of the benefit
-    let image_bytes = renderer.render(extent);
+/// Properties of the mapfile needed by the metadata endpoints
+struct MapSummary {
+    /// Layer extent, or the map EXTENT if the layers have none
+    extent: Extent,
+    content_type: String,
+}
+
+/// Load the mapfile on a blocking thread and read its metadata.
+/// Map objects are not Send, so only the summary leaves the thread
+async fn map_summary(mapfile_str: String) -> Result<MapSummary, MapError> {
+    tokio::task::spawn_blocking(move || {
+        let map = Map::try_from(mapfile_str)?;
+        Ok(MapSummary {
+            extent: map.layer_extent().unwrap_or_else(|| map.extent()),
+            content_type: map.content_type(),
+        })
+    })
+    .await
+    .unwrap_or_else(|err| Err(MapError::LoadFailed(err.to_string())))
+}
+
+/// Scheme and authority for absolute URLs, from the Host header.
+/// Empty, giving root-relative URLs, if the request has none
+fn base_url(headers: &HeaderMap) -> String {
+    headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(|host| format!("http://{}", host))
+        .unwrap_or_default()
+}
+
+/// The current time in milliseconds since the epoch, the unit of TILEDB_TIMESTAMP
+fn now_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Strong ETag derived from the image bytes
+fn etag(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Check an If-None-Match header, a comma separated list of (possibly weak) ETags or `*`
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use axum::body::Body;
+    use axum::http::Request;
+    use hyper::body::HttpBody;
+    use tower::ServiceExt;
+
+    fn test_state() -> Arc<State> {
+        state_with_template("MAP NAME '{timestamp}' EXTENT -20000 -10000 20000 10000 END")
+    }
+
+    fn state_with_template(template: &str) -> Arc<State> {
+        Arc::new(base_state(template))
+    }
+
+    /// The default state for a template, for tests to override fields with `..base_state(template)`
+    fn base_state(template: &str) -> State {
+        State {
+            map_pool: MapPool::create(2),
+            tile_cache: TileCache::new(1024 * 1024),
+            template: CachedTemplate::new(MapfileTemplate::new(template.to_string())),
+            metrics: Metrics::new(),
+            render_permits: Semaphore::new(2),
+            cors_origins: Vec::new(),
+            rate_limiter: None,
+            trusted_proxies: Vec::new(),
+            zooms: DEFAULT_ZOOMS,
+            no_content_for_empty: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_map_cache() {
+        let app = app(test_state());
+        let request = || Request::get("/map/1/7/26/48").body(Body::empty()).unwrap();
+
+        let first = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()["x-cache"], "MISS");
+        assert_eq!(first.headers()[header::CONTENT_TYPE], "image/png");
+        let etag = first.headers()[header::ETAG].clone();
+
+        let second = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert_eq!(second.headers()["x-cache"], "HIT");
+        assert_eq!(second.headers()[header::ETAG], etag);
+        assert!(second.headers().contains_key(header::CACHE_CONTROL));
+
+        let conditional = Request::get("/map/1/7/26/48")
+            .header(header::IF_NONE_MATCH, etag.clone())
+            .body(Body::empty())
+            .unwrap();
+        let third = app.oneshot(conditional).await.unwrap();
+        assert_eq!(third.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(third.headers()[header::ETAG], etag);
+    }
+
+    type SpanFields = (&'static str, HashMap<String, String>);
+
+    /// Records the name and fields of every span, for asserting on instrumentation
+    #[derive(Clone, Default)]
+    struct SpanRecorder {
+        spans: Arc<Mutex<Vec<SpanFields>>>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &field::Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldVisitor(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            tracing::span::Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut FieldVisitor(fields));
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_render_span() {
+        let recorder = SpanRecorder::default();
+        let _guard = tracing::subscriber::set_default(recorder.clone());
+
+        let app = app(test_state());
+        let request = Request::get("/map/1/7/26/48").body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let spans = recorder.spans.lock().unwrap();
+        let (_, fields) = spans
+            .iter()
+            .find(|(name, _)| *name == "render_tile")
+            .expect("a render_tile span");
+        assert_eq!(fields["timestamp"], "1");
+        assert_eq!(fields["z"], "7");
+        assert_eq!(fields["x"], "26");
+        assert_eq!(fields["y"], "48");
+        assert_eq!(fields["cache"], "MISS");
+        assert!(fields["bytes"].parse::<usize>().unwrap() > 0);
+        assert!(fields.contains_key("render_ms"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let app = app(test_state());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+        let scrape = |app: Router| async move {
+            let response = app.oneshot(request("/metrics")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let before = scrape(app.clone()).await;
+        assert!(before.contains("mapserver_tiles_rendered_total 0\n"));
+        assert!(before.contains("mapserver_live_maps 0\n"));
+
+        // A miss then a hit
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request("/map/1/7/26/48"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        let after = scrape(app).await;
+        assert!(after.contains("mapserver_tiles_rendered_total 1\n"));
+        assert!(after.contains("mapserver_tile_cache_hits_total 1\n"));
+        assert!(after.contains("mapserver_tile_cache_misses_total 1\n"));
+        assert!(after.contains("mapserver_render_duration_seconds_count 1\n"));
+        assert!(after.contains("mapserver_live_maps 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_permits() {
+        let state = Arc::new(State {
+            render_permits: Semaphore::new(1),
+            ..base_state("MAP NAME '{timestamp}' END")
+        });
+        let app = app(state.clone());
+        let request = || Request::get("/map/1/7/26/48").body(Body::empty()).unwrap();
+
+        // While the only permit is held, renders are turned away once the permit timeout passes
+        let permit = state.render_permits.acquire().await.unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        // WMS GetMap waits for the same permits
+        let getmap = || {
+            let uri = "/wms?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap&LAYERS=default&STYLES=\
+                       &CRS=EPSG:3857&BBOX=0,0,1000,1000&WIDTH=512&HEIGHT=512&FORMAT=image/png";
+            Request::get(uri).body(Body::empty()).unwrap()
+        };
+        let response = app.clone().oneshot(getmap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        drop(permit);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(getmap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_acquire() {
+        let state = test_state();
+
+        // Two different timestamps, and so mapfiles, acquired from concurrent tasks
+        let tasks: Vec<_> = [1, 2]
+            .into_iter()
+            .map(|timestamp| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let mapfile_str = state.template.render(timestamp);
+                    acquire_renderer(&state, mapfile_str).await
+                })
+            })
+            .collect();
+
+        let acquired = tokio::time::timeout(Duration::from_secs(5), async {
+            for task in tasks {
+                assert!(task.await.unwrap().is_ok());
+            }
+        })
+        .await;
+        assert!(acquired.is_ok(), "deadlock acquiring maps");
+        assert_eq!(state.map_pool.live_map_count(), 2);
+    }
+
+    #[test]
+    fn test_parse_count() {
+        assert_eq!(parse_count("N", None, 24), Ok(24));
+        assert_eq!(parse_count("N", Some("8".to_string()), 24), Ok(8));
+        assert_eq!(parse_count("N", Some(" 8 ".to_string()), 24), Ok(8));
+        assert!(parse_count("N", Some("0".to_string()), 24).is_err());
+        assert!(parse_count("N", Some("-1".to_string()), 24).is_err());
+        assert!(parse_count("N", Some("many".to_string()), 24).is_err());
+    }
+
+    #[test]
+    fn test_parse_pool_size() {
+        let pool_size = |value: Option<&str>| {
+            parse_count("MAPSERVER_POOL_SIZE", value.map(String::from), POOL_SIZE)
+        };
+        assert_eq!(pool_size(None), Ok(24));
+        assert_eq!(pool_size(Some("1")), Ok(1));
+        assert_eq!(pool_size(Some("64")), Ok(64));
+        let err = pool_size(Some("0")).unwrap_err();
+        assert!(err.contains("MAPSERVER_POOL_SIZE"));
+    }
+
+    #[tokio::test]
+    async fn test_render_map_out_of_range() {
+        let state = test_state();
+        let app = app(state.clone());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("/map/0/5/999/999"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.clone().oneshot(request("/map/0/31/0/0")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(state.tile_cache.is_empty());
+
+        let response = app.oneshot(request("/map/0/5/9/9")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_render_map_timestamp() {
+        let state = test_state();
+        let app = app(state.clone());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("/map/1650000000000/7/26/48"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "MISS");
+
+        // The same mapfile, so the same cached tile
+        let response = app
+            .clone()
+            .oneshot(request("/map/2022-04-15T05:20:00Z/7/26/48"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        assert_eq!(state.template.renders(), 1);
+
+        let response = app
+            .clone()
+            .oneshot(request("/map/yesterday/7/26/48"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // A quote would end the string holding the timestamp in the mapfile
+        let response = app
+            .oneshot(request("/map/0'%20END%20LAYER%20NAME%20'x/7/26/48"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body).unwrap().contains("disallowed"));
+        assert_eq!(state.tile_cache.len(), 1);
+        assert_eq!(state.map_pool.live_map_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_render_map_layers() {
+        // Crossing lines through the tile, in web mercator
+        let state = state_with_template(
+            "MAP NAME '{timestamp}' \
+             LAYER NAME 'roads' TYPE LINE STATUS ON \
+               FEATURE POINTS -11900000 4700000 -11600000 5000000 END END \
+               CLASS STYLE COLOR 255 0 0 WIDTH 4 END END \
+             END \
+             LAYER NAME 'rivers' TYPE LINE STATUS ON \
+               FEATURE POINTS -11900000 5000000 -11600000 4700000 END END \
+               CLASS STYLE COLOR 0 0 255 WIDTH 4 END END \
+             END END",
+        );
+        let app = app(state.clone());
+        let body = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(uri).body(Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                hyper::body::to_bytes(response.into_body()).await.unwrap()
+            }
+        };
+
+        let roads = body("/map/1/7/26/48?layers=roads").await;
+        let rivers = body("/map/1/7/26/48?layers=rivers").await;
+        let both = body("/map/1/7/26/48").await;
+        assert_ne!(roads, rivers);
+        assert_ne!(roads, both);
+        assert_eq!(body("/map/1/7/26/48?layers=roads,rivers").await, both);
+        assert_eq!(state.tile_cache.len(), 4);
+
+        let request = Request::get("/map/1/7/26/48?layers=lakes")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"Unknown layer: lakes");
+    }
+
+    #[tokio::test]
+    async fn test_render_map_style() {
+        let state = state_with_template(
+            "MAP NAME '{timestamp}' \
+             LAYER NAME 'roads' TYPE LINE STATUS ON \
+               FEATURE POINTS -11900000 4700000 -11600000 5000000 END END \
+               CLASS GROUP 'day' STYLE COLOR 0 0 0 WIDTH 4 END END \
+               CLASS GROUP 'night' STYLE COLOR 255 255 255 WIDTH 4 END END \
+             END END",
+        );
+        let app = app(state.clone());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let mut bodies = Vec::new();
+        for uri in ["/map/1/7/26/48?style=day", "/map/1/7/26/48?style=night"] {
+            let response = app.clone().oneshot(request(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["x-cache"], "MISS");
+            bodies.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+        }
+        assert_ne!(bodies[0], bodies[1]);
+        // Both from the same pooled map
+        assert_eq!(state.map_pool.live_map_count(), 1);
+
+        let response = app
+            .oneshot(request("/map/1/7/26/48?style=dusk"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_render_map_resample() {
+        let state =
+            state_with_template("MAP NAME '{timestamp}' LAYER NAME 'imagery' TYPE RASTER END END");
+        let app = app(state.clone());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        for uri in [
+            "/map/1/7/26/48?resample=nearest",
+            "/map/1/7/26/48?resample=BILINEAR",
+        ] {
+            let response = app.clone().oneshot(request(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Cached separately, each drawn with its own resampling
+        assert_eq!(state.tile_cache.len(), 2);
+
+        let response = app
+            .oneshot(request("/map/1/7/26/48?resample=cubic"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_render_map_transparent() {
+        let state = test_state();
+        let app = app(state.clone());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        for uri in [
+            "/map/1/7/26/48",
+            "/map/1/7/26/48?transparent=true",
+            "/map/1/7/26/48?transparent=false",
+        ] {
+            let response = app.clone().oneshot(request(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(state.tile_cache.len(), 3);
+
+        let response = app
+            .oneshot(request("/map/1/7/26/48?transparent=maybe"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_render_map_accept() {
+        let state = state_with_template(
+            "MAP NAME '{timestamp}' \
+             OUTPUTFORMAT NAME 'webp' DRIVER 'AGG/WEBP' MIMETYPE 'image/webp' END END",
+        );
+        let app = app(state);
+        let request = |accept: &str| {
+            Request::get("/map/1/7/26/48")
+                .header(header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("image/webp,*/*"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/webp");
+        assert!(response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .any(|vary| vary.to_str().unwrap().eq_ignore_ascii_case("accept")));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(b"RIFF"));
+
+        let response = app.clone().oneshot(request("image/png")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(b"\x89PNG"));
+
+        // Nothing this map can encode, so its default
+        let response = app.oneshot(request("image/avif")).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let request = |origin| {
+            Request::get("/map/1/7/26/48")
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app(test_state())
+            .oneshot(request("https://viewer.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        let state = Arc::new(State {
+            cors_origins: cors_origins(&[], Some("https://cdn.example.com".to_string())).unwrap(),
+            ..base_state("MAP NAME '{timestamp}' END")
+        });
+        let app = app(state);
+        let response = app
+            .clone()
+            .oneshot(request("https://cdn.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://cdn.example.com"
+        );
+        let response = app
+            .oneshot(request("https://other.example.com"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_warm_up() {
+        let state = test_state();
+        warm_up(&state, &[1650000000000, 1660000000000]).await;
+        assert_eq!(state.map_pool.live_map_count(), 2);
+        // The warmed maps are reused rather than loaded again
+        warm_up(&state, &[1650000000000]).await;
+        assert_eq!(state.map_pool.live_map_count(), 2);
+    }
+
+    #[test]
+    fn test_warm_timestamps() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(warm_timestamps(&args(&[]), None), Ok(Vec::new()));
+        assert_eq!(
+            warm_timestamps(
+                &args(&["--warm", "1650000000000, 1660000000000"]),
+                Some("1".to_string())
+            ),
+            Ok(vec![1650000000000, 1660000000000])
+        );
+        assert_eq!(
+            warm_timestamps(&args(&[]), Some("1650000000000".to_string())),
+            Ok(vec![1650000000000])
+        );
+        assert!(warm_timestamps(&args(&["--warm=yesterday"]), None).is_err());
+    }
+
+    #[test]
+    fn test_cors_origins() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert_eq!(cors_origins(&args(&[]), None), Ok(Vec::new()));
+        assert_eq!(
+            cors_origins(&args(&[]), Some("*".to_string())),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            cors_origins(
+                &args(&[
+                    "--cors-origins",
+                    "https://a.example.com, https://b.example.com"
+                ]),
+                Some("*".to_string())
+            ),
+            Ok(vec![
+                HeaderValue::from_static("https://a.example.com"),
+                HeaderValue::from_static("https://b.example.com")
+            ])
+        );
+        assert!(cors_origins(&args(&[]), Some("https://a.example.com\x01".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let state = state_with_template(
+            "MAP NAME '{timestamp}' OUTPUTFORMAT NAME 'geojson' DRIVER 'OGR/GEOJSON' END \
+             LAYER NAME 'peaks' TYPE POINT TEMPLATE 'query' PROCESSING 'ITEMS=name' \
+             FEATURE POINTS -11711300 4940800 END ITEMS 'Lookout' END END END",
+        );
+        let app = app(state);
+        let request = |uri| {
+            Request::get(uri)
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("/geojson/0/0/0?timestamp=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/geo+json"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(&[0x1f, 0x8b]));
+
+        // PNG tiles are passed through as is
+        let response = app.oneshot(request("/map/1/0/0/0")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(body.starts_with(b"\x89PNG"));
+    }
+
+    #[tokio::test]
+    async fn test_render_map_no_content() {
+        let template = "MAP NAME '{timestamp}' EXTENT -20000 -10000 20000 10000 END";
+        for no_content_for_empty in [false, true] {
+            let state = Arc::new(State {
+                no_content_for_empty,
+                ..base_state(template)
+            });
+            let app = app(state);
+            let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+            // Far outside the map's EXTENT, around Colorado
+            let expected = if no_content_for_empty {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::OK
+            };
+            for cache in ["MISS", "HIT"] {
+                let response = app.clone().oneshot(get("/map/1/7/26/48")).await.unwrap();
+                assert_eq!(response.status(), expected);
+                assert_eq!(response.headers()["x-cache"], cache);
+                let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert_eq!(body.is_empty(), no_content_for_empty);
+            }
+
+            // Tiles over the map are always drawn
+            let response = app.oneshot(get("/map/1/1/0/0")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_parse_switch() {
+        assert_eq!(parse_switch("SWITCH", None), Ok(false));
+        assert_eq!(parse_switch("SWITCH", Some(" true ".to_string())), Ok(true));
+        assert_eq!(parse_switch("SWITCH", Some("1".to_string())), Ok(true));
+        assert_eq!(parse_switch("SWITCH", Some("off".to_string())), Ok(false));
+        assert!(parse_switch("SWITCH", Some("maybe".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_map_hidpi() {
+        let state = state_with_template("MAP NAME '{timestamp}' SIZE 256 256 END");
+        let app = app(state.clone());
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(get("/map/1/7/26/48")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let standard = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        // PNG IHDR stores the width and height as big-endian u32s after the signature
+        assert_eq!(&standard[16..24], &[0, 0, 1, 0, 0, 0, 1, 0]);
+
+        let response = app.clone().oneshot(get("/map/1/7/26/48@2x")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let hidpi = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&hidpi[16..20], &512u32.to_be_bytes());
+        assert_eq!(&hidpi[20..24], &512u32.to_be_bytes());
+
+        // Cached apart, and with the same extent
+        assert_eq!(state.tile_cache.len(), 2);
+        let response = app.clone().oneshot(get("/map/1/7/26/48")).await.unwrap();
+        assert_eq!(response.headers()["x-cache"], "HIT");
+
+        for uri in ["/map/1/7/26/48@3x", "/map/1/7/26/@2x", "/map/1/7/26/row"] {
+            let response = app.clone().oneshot(get(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn test_parse_tile_row() {
+        assert_eq!(parse_tile_row("48"), Some((48, 1)));
+        assert_eq!(parse_tile_row("48@2x"), Some((48, 2)));
+        assert_eq!(parse_tile_row("48@2"), None);
+        assert_eq!(parse_tile_row("@2x"), None);
+    }
+
+    #[tokio::test]
+    async fn test_render_map_zooms() {
+        let state = Arc::new(State {
+            zooms: 2..=12,
+            ..base_state("MAP NAME '{timestamp}' END")
+        });
+        let app = app(state.clone());
+        let get = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+
+        for uri in ["/map/1/13/0/0", "/map/1/28/0/0", "/map/1/1/0/0"] {
+            let response = app.clone().oneshot(get(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+        // Turned away before a map is loaded
+        assert_eq!(state.map_pool.live_map_count(), 0);
+        let response = app.clone().oneshot(get("/map/1/12/0/0")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.oneshot(get("/tile.json?timestamp=1")).await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let tilejson: TileJson = serde_json::from_slice(&body).unwrap();
+        assert_eq!((tilejson.minzoom, tilejson.maxzoom), (2, 12));
+    }
+
+    #[test]
+    fn test_zoom_range() {
+        assert_eq!(zoom_range(None, None), Ok(0..=24));
+        assert_eq!(
+            zoom_range(Some("3".to_string()), Some(" 18 ".to_string())),
+            Ok(3..=18)
+        );
+        assert_eq!(zoom_range(Some("0".to_string()), None), Ok(0..=24));
+        assert!(zoom_range(Some("10".to_string()), Some("4".to_string())).is_err());
+        assert!(zoom_range(None, Some("31".to_string())).is_err());
+        assert!(zoom_range(Some("-1".to_string()), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let state = Arc::new(State {
+            rate_limiter: Some(RateLimiter::new(0.1, 2)),
+            trusted_proxies: vec![proxy],
+            ..base_state("MAP NAME '{timestamp}' END")
+        });
+        let app = app(state);
+        let request = |uri, peer: IpAddr, forwarded_for| {
+            Request::get(uri)
+                .header("x-forwarded-for", forwarded_for)
+                .extension(ConnectInfo(SocketAddr::new(peer, 40000)))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request("/map/1/7/26/48", proxy, "203.0.113.7"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // Connecting directly and claiming another address is still the same client
+        let client = IpAddr::from([203, 0, 113, 7]);
+        let response = app
+            .clone()
+            .oneshot(request("/map/1/7/26/48", client, "198.51.100.2"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "10");
+
+        // Other clients and health checks are unaffected
+        let response = app
+            .clone()
+            .oneshot(request("/map/1/7/26/48", proxy, "198.51.100.2"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(request("/healthz", proxy, "203.0.113.7"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = [IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])];
+        let request = |peer: [u8; 4], forwarded_for| {
+            Request::get("/")
+                .header("x-forwarded-for", forwarded_for)
+                .extension(ConnectInfo(SocketAddr::from((peer, 40000))))
+                .body(())
+                .unwrap()
+        };
+        let client = |request| client_ip(&request, &proxies).unwrap().to_string();
+
+        // Untrusted peers are the client, whatever they forward
+        assert_eq!(client(request([192, 0, 2, 9], "203.0.113.7")), "192.0.2.9");
+        // The last address before the trusted proxies, not one the client prepended
+        assert_eq!(
+            client(request(
+                [10, 0, 0, 2],
+                "198.51.100.2, 203.0.113.7, 10.0.0.1"
+            )),
+            "203.0.113.7"
+        );
+        assert_eq!(client(request([10, 0, 0, 1], "unknown")), "10.0.0.1");
+        assert_eq!(client_ip(&Request::new(()), &proxies), None);
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        assert_eq!(trusted_proxies(None), Ok(Vec::new()));
+        assert_eq!(
+            trusted_proxies(Some("10.0.0.1, ::1".to_string())),
+            Ok(vec![IpAddr::from([10, 0, 0, 1]), "::1".parse().unwrap()])
+        );
+        assert!(trusted_proxies(Some("10.0.0.0/8".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("RATE", None), Ok(None));
+        assert_eq!(parse_rate("RATE", Some(" 2.5 ".to_string())), Ok(Some(2.5)));
+        assert!(parse_rate("RATE", Some("0".to_string())).is_err());
+        assert!(parse_rate("RATE", Some("fast".to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_render_mvt() {
+        let state = state_with_template(
+            "MAP NAME '{timestamp}' OUTPUTFORMAT NAME 'mvt' DRIVER MVT END \
+             LAYER NAME 'roads' TYPE LINE END END",
+        );
+        let app = app(state.clone());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("/mvt/7/26/48.pbf?timestamp=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/vnd.mapbox-vector-tile"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(!body.is_empty());
+        assert!(body.starts_with(&[0x1f, 0x8b]) || body[0] == 0x1a);
+
+        // Cached apart from the PNG tile
+        let response = app
+            .clone()
+            .oneshot(request("/map/1/7/26/48"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        assert_eq!(state.tile_cache.len(), 2);
+
+        // Raster-only maps have no vector tiles
+        let app = super::app(state_with_template(
+            "MAP OUTPUTFORMAT NAME 'mvt' DRIVER MVT END LAYER NAME 'imagery' TYPE RASTER END END",
+        ));
+        let response = app.oneshot(request("/mvt/7/26/48.pbf")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_render_geojson() {
+        let state = state_with_template(
+            "MAP NAME '{timestamp}' OUTPUTFORMAT NAME 'geojson' DRIVER 'OGR/GEOJSON' END \
+             LAYER NAME 'peaks' TYPE POINT TEMPLATE 'query' PROCESSING 'ITEMS=name' \
+             FEATURE POINTS -11711300 4940800 END ITEMS 'Lookout' END END END",
+        );
+        let app = app(state);
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(request("/geojson/0/0/0?timestamp=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/geo+json"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["type"], "FeatureCollection");
+        assert_eq!(json["features"][0]["properties"]["name"], "Lookout");
+
+        // A tile with nothing in it is still a FeatureCollection
+        let response = app
+            .clone()
+            .oneshot(request("/geojson/7/0/0.json?timestamp=1"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["type"], "FeatureCollection");
+        assert_eq!(json["features"].as_array().map(Vec::len), Some(0));
+
+        // Without a geojson OUTPUTFORMAT
+        let app = super::app(test_state());
+        let response = app.oneshot(request("/geojson/0/0/0")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_capabilities() {
+        let app = app(test_state());
+        let request = Request::get("/capabilities.json")
+            .header(header::HOST, "tiles.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let capabilities: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            capabilities["tiles"],
+            "http://tiles.example.com/map/{timestamp}/{z}/{x}/{y}"
+        );
+        assert_eq!(capabilities["formats"][0], "image/png");
+
+        let extent: Vec<f64> = serde_json::from_value(capabilities["extent"].clone()).unwrap();
+        assert_eq!(extent, vec![-20000., -10000., 20000., 10000.]);
+        assert!(extent[0] < extent[2] && extent[1] < extent[3]);
+    }
+
+    #[tokio::test]
+    async fn test_tilejson() {
+        let app = app(test_state());
+        let request = Request::get("/tile.json?timestamp=1600000000000")
+            .header(header::HOST, "tiles.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let tilejson: TileJson = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tilejson.tilejson, "3.0.0");
+        assert_eq!(
+            tilejson.tiles,
+            vec!["http://tiles.example.com/map/1600000000000/{z}/{x}/{y}"]
+        );
+        assert!(tilejson.minzoom <= tilejson.maxzoom);
+
+        let [west, south, east, north] = tilejson.bounds;
+        assert!(-180. <= west && west < east && east <= 180.);
+        assert!(-90. <= south && south < north && north <= 90.);
+
+        let [lon, lat, zoom] = tilejson.center;
+        assert!(west <= lon && lon <= east && south <= lat && lat <= north);
+        assert!(zoom >= tilejson.minzoom as f64 && zoom <= tilejson.maxzoom as f64);
+    }
+
+    /// Check that elements are balanced and properly nested, skipping the declaration
+    fn assert_well_formed(xml: &str) {
+        let mut open: Vec<&str> = Vec::new();
+        let mut rest = xml.trim_start();
+        assert!(rest.starts_with("<?xml"));
+        while let Some(start) = rest.find('<') {
+            let end = start + rest[start..].find('>').expect("unterminated tag");
+            let tag = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('?') {
+                continue;
+            }
+            if let Some(closing) = tag.strip_prefix('/') {
+                assert_eq!(
+                    open.pop(),
+                    Some(closing.trim()),
+                    "mismatched </{}>",
+                    closing
+                );
+            } else if !tag.ends_with('/') {
+                open.push(tag.split_whitespace().next().unwrap());
+            }
+        }
+        assert!(open.is_empty(), "unclosed elements {:?}", open);
+    }
+
+    #[tokio::test]
+    async fn test_wmts() {
+        let app = app(test_state());
+        let request = |uri: &str| {
+            Request::get(uri)
+                .header(header::HOST, "tiles.example.com")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("/wmts/capabilities.xml?timestamp=1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/xml");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let xml = std::str::from_utf8(&body).unwrap();
+        assert_well_formed(xml);
+        assert!(xml.contains("<TileMatrixSet>GoogleMapsCompatible</TileMatrixSet>"));
+        assert!(xml.contains("<Format>image/png</Format>"));
+
+        // The tile is the same as the one on the custom route
+        let response = app
+            .clone()
+            .oneshot(request(
+                "/wmts/default/GoogleMapsCompatible/7/26/48.png?timestamp=1",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let wmts = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let response = app
+            .clone()
+            .oneshot(request("/map/1/7/26/48"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["x-cache"], "HIT");
+        let zxy = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(wmts, zxy);
+
+        let response = app
+            .oneshot(request("/wmts/default/EPSG:4326/7/26/48.png"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_well_formed(std::str::from_utf8(&body).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wms_getmap() {
+        let app = app(test_state());
+        let uri = "/wms?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap&LAYERS=default&STYLES=\
+                   &CRS=EPSG:4326&BBOX=38,-106,40,-104&WIDTH=512&HEIGHT=384&FORMAT=image/png";
+        let response = app
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[16..20], &512u32.to_be_bytes());
+        assert_eq!(&body[20..24], &384u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_image_body() {
+        let small = image_body(vec![7; 1024]);
+        assert_eq!(hyper::body::to_bytes(small).await.unwrap(), vec![7; 1024]);
+
+        // Streamed in chunks, in order
+        let bytes: Vec<u8> = (0..STREAM_THRESHOLD_BYTES * 3 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut body = image_body(bytes.clone());
+        let mut chunks = 0;
+        let mut received = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= STREAM_CHUNK_BYTES);
+            received.extend_from_slice(&chunk);
+            chunks += 1;
+        }
+        assert!(chunks > 1);
+        assert_eq!(received, bytes);
+    }
+
+    #[tokio::test]
+    async fn test_wms_errors() {
+        let app = app(test_state());
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let uri = "/wms?REQUEST=GetMap&CRS=EPSG:3857&WIDTH=256&HEIGHT=256";
+        let response = app.clone().oneshot(request(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/xml");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<ServiceExceptionReport"));
+        assert!(body.contains("code=\"MissingParameterValue\""));
+        assert!(body.contains("BBOX"));
+
+        let uri = "/wms?REQUEST=GetMap&CRS=EPSG:3857&BBOX=0,0,1,1&WIDTH=256&HEIGHT=256\
+                   &FORMAT=image/gif";
+        let response = app.oneshot(request(uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("code=\"InvalidFormat\""));
+    }
+
+    #[tokio::test]
+    async fn test_health_probes() {
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+
+        let app = app(test_state());
+        let response = app.clone().oneshot(request("/healthz")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request("/readyz")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // A mapfile that fails to load, or loads but fails to draw because its data is missing
+        let missing_data = "MAP NAME '{timestamp}' \
+            LAYER NAME 'roads' TYPE LINE STATUS ON DATA '/nonexistent/roads.shp' END \
+            END";
+        for template in ["MAP NOT A MAPFILE END", missing_data] {
+            let app = super::app(state_with_template(template));
+            let response = app.clone().oneshot(request("/healthz")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let response = app.oneshot(request("/readyz")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        let state = Arc::new(State {
+            render_permits: Semaphore::new(1),
+            ..base_state("MAP NAME '{timestamp}' END")
+        });
+        let incoming = AddrIncoming::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = incoming.local_addr();
+        let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(incoming, state.clone(), async {
+            shutdown_receiver.await.ok();
+        }));
+
+        let request = tokio::spawn(async move {
+            let uri = format!("http://{}/map/1/7/26/48", addr).parse().unwrap();
+            hyper::Client::new().get(uri).await.unwrap()
+        });
+
+        // Hold the only render permit so the request is still in flight when shutdown starts,
+        // it has missed the cache and is waiting for the permit once the miss is counted
+        let permit = state.render_permits.acquire().await.unwrap();
+        while !state
+            .metrics
+            .to_prometheus(0)
+            .contains("mapserver_tile_cache_misses_total 1\n")
+        {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        shutdown_sender.send(()).unwrap();
+
+        // New connections are refused once shutdown has started, then the render may proceed
+        while tokio::net::TcpStream::connect(addr).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(permit);
+        // The pool is only shut down once nothing else holds the state
+        drop(state);
+
+        let response = request.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(!body.is_empty());
+        server.await.unwrap().unwrap();
+
+        // The listener is closed once the server has exited
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[test]
+    fn test_seed_options() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        let (options, output) = seed_options(&args(&[
+            "--extent",
+            "-100,-50,100,50",
+            "--minzoom",
+            "2",
+            "--maxzoom=4",
+            "--output",
+            "tiles",
+            "--timestamp",
+            "1650000000000",
+        ]))
+        .unwrap();
+        assert_eq!(
+            options,
+            SeedOptions {
+                extent: Extent::new(-100., -50., 100., 50.),
+                min_zoom: 2,
+                max_zoom: 4,
+                timestamp: 1650000000000,
+            }
+        );
+        assert_eq!(output, PathBuf::from("tiles"));
+
+        assert!(seed_options(&args(&["--minzoom", "2", "--maxzoom", "4"])).is_err());
+        assert!(seed_options(&args(&[
+            "--extent",
+            "1,2,3",
+            "--minzoom",
+            "2",
+            "--maxzoom",
+            "4",
+            "--output",
+            "tiles"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_listen_addr() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let env = Some("127.0.0.1:8080".to_string());
+
+        assert_eq!(
+            listen_addr(&args(&[]), None),
+            Ok("0.0.0.0:3000".parse().unwrap())
+        );
+        assert_eq!(
+            listen_addr(&args(&[]), env.clone()),
+            Ok("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            listen_addr(&args(&["--listen", "[::1]:9000"]), env.clone()),
+            Ok("[::1]:9000".parse().unwrap())
+        );
+        assert_eq!(
+            listen_addr(&args(&["--listen=10.0.0.1:80"]), env),
+            Ok("10.0.0.1:80".parse().unwrap())
+        );
+        assert!(listen_addr(&args(&["--listen", "localhost"]), None).is_err());
+        assert!(listen_addr(&args(&[]), Some("0.0.0.0:99999".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_mapfile_path() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let env = Some("env.map".to_string());
+
+        assert_eq!(
+            mapfile_path(&args(&["--mapfile", "cli.map"]), env.clone()),
+            Some(PathBuf::from("cli.map"))
+        );
+        assert_eq!(
+            mapfile_path(&args(&["--mapfile=cli.map"]), env.clone()),
+            Some(PathBuf::from("cli.map"))
+        );
+        assert_eq!(
+            mapfile_path(&args(&[]), env),
+            Some(PathBuf::from("env.map"))
+        );
+        assert_eq!(mapfile_path(&args(&["--mapfile"]), None), None);
+        assert_eq!(mapfile_path(&args(&[]), None), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let good = MapfileTemplate::new(
+            "MAP NAME '{timestamp}' LAYER NAME 'roads' TYPE LINE \
+               FEATURE POINTS 0 0 100 100 END END \
+               CLASS STYLE COLOR 255 0 0 END END \
+             END END"
+                .to_string(),
+        );
+        let report = validate(&good, 1);
+        assert_eq!(report, Ok("ok   mapfile\nok   layer roads\n".to_string()));
+
+        let broken = MapfileTemplate::new(
+            "MAP LAYER NAME 'roads' TYPE LINE DATA '/nonexistent/roads.shp' END END".to_string(),
+        );
+        let report = validate(&broken, 1).unwrap_err();
+        assert!(report.contains("FAIL layer roads: "));
+        assert!(report.contains("/nonexistent/roads.shp"));
+        assert_eq!(run_validate(&broken, &[]), 1);
+
+        let report = validate(
+            &MapfileTemplate::new("MAP NOT A MAPFILE END".to_string()),
+            1,
+        );
+        assert!(report
+            .unwrap_err()
+            .starts_with("FAIL mapfile: Unable to load mapfile"));
+    }
+
+    #[test]
+    fn test_proj_data_dir() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let dir = std::env::temp_dir().display().to_string();
+
+        assert_eq!(
+            proj_data_dir(&args(&["--proj-data", &dir]), Some("/env/proj".to_string())),
+            Ok(Some(PathBuf::from(&dir)))
+        );
+        assert_eq!(
+            proj_data_dir(&args(&[]), Some(dir.clone())),
+            Ok(Some(PathBuf::from(&dir)))
+        );
+        assert_eq!(proj_data_dir(&args(&[]), None), Ok(None));
+        assert!(proj_data_dir(&args(&["--proj-data=/nonexistent/proj"]), None).is_err());
+    }
+
+    #[test]
+    fn test_data_source() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let env = Some("/env/array".to_string());
+
+        assert_eq!(
+            data_source(&args(&["--data", "/cli/array"]), env.clone()),
+            Some("/cli/array".to_string())
+        );
+        assert_eq!(data_source(&args(&[]), env), Some("/env/array".to_string()));
+        assert_eq!(data_source(&args(&[]), None), None);
+
+        // The configured source lands in every rendered mapfile
+        let template = MapfileTemplate::new("MAP LAYER DATA '{data}' END END".to_string())
+            .with_data(&data_source(&args(&["--data=/cli/array"]), None).unwrap())
+            .unwrap();
+        let state = CachedTemplate::new(template);
+        assert_eq!(state.render(1), "MAP LAYER DATA '/cli/array' END END");
+    }
 
-    ([(header::CONTENT_TYPE, "image/png")], image_bytes)
+    #[test]
+    fn test_etag_matches() {
+        let etag = etag(b"tile");
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("W/{}", etag), &etag));
+        assert!(etag_matches(&format!("\"other\", {}", etag), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("\"other\"", &etag));
+    }
 }
@@ -1,98 +1,1097 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::ffi::CString;
-use std::os::raw::c_char;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::{bounded, select, Sender};
-use libc;
+use crossbeam_channel::{bounded, select, Receiver, RecvTimeoutError, SendTimeoutError, Sender};
 use threadpool::ThreadPool;
 
-use mapserver_sys::{
-    mapObj, msCleanup, msDebugCleanup, msDrawMap, msFreeImage, msFreeMap, msGDALCleanup,
-    msIO_Cleanup, msLoadMapFromString, msMapSetExtent, msOGRCleanup,
-    msProjectionContextPoolCleanup, msSaveImageBuffer, msSetPROJ_DATA,
+use super::coordinates::{Tile, TILE_SIZE};
+use super::handle::{
+    cleanup, cleanup_globals, FormatHandle, ImageHandle, LoadRetry, MapHandle, RasterPixels,
 };
-
 use super::Extent;
 
 const MAP_IDLE_TIMEOUT_SECONDS: u64 = 60 * 60;
 
+/// Largest width or height accepted by `Map::draw_sized`, matching mapserver's default MAXSIZE
+pub const MAX_IMAGE_SIZE: i32 = 4096;
+
+///
+/// Errors loading a mapfile into a Mapserver mapObj
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// msLoadMapFromString could not parse or load the mapfile, with mapserver's error message
+    LoadFailed(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::LoadFailed(message) => {
+                write_with_message(f, "Unable to load mapfile", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+///
+/// Errors rendering an image from a Map
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenderError {
+    /// msMapSetExtent rejected the extent
+    InvalidExtent,
+    /// msDrawMap failed to produce an image, with mapserver's error message
+    DrawFailed(String),
+    /// The image could not be saved, e.g. the map has no output format
+    SaveFailed(String),
+    /// msSaveImageBuffer returned no image, e.g. for a format mapserver cannot encode,
+    /// with mapserver's error message
+    Encode(String),
+    /// The map thread could not accept or complete the render before the deadline
+    Busy,
+    /// The map thread has exited, e.g. after an idle timeout. Acquire a fresh channel
+    WorkerGone,
+    /// The requested OUTPUTFORMAT is not defined in the mapfile
+    UnknownFormat(String),
+    /// The requested image dimensions are not positive or exceed MAX_IMAGE_SIZE
+    InvalidSize { width: i32, height: i32 },
+    /// msQueryByPoint failed, with mapserver's error message
+    QueryFailed(String),
+    /// A layer has no vector output, e.g. a raster layer requested as MVT
+    NotVector(String),
+    /// The requested output projection could not be loaded, with mapserver's error message
+    InvalidProjection(String),
+    /// A requested layer is not defined in the mapfile
+    UnknownLayer(String),
+    /// A requested style is not the GROUP of any CLASS in the mapfile
+    UnknownStyle(String),
+    /// A requested resampling method is not one of NEAREST, BILINEAR or AVERAGE
+    UnknownResample(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::InvalidExtent => write!(f, "Invalid map extent"),
+            RenderError::DrawFailed(message) => {
+                write_with_message(f, "Unable to render map", message)
+            }
+            RenderError::SaveFailed(message) => {
+                write_with_message(f, "Unable to save rendered image", message)
+            }
+            RenderError::Encode(message) => {
+                write_with_message(f, "Unable to encode rendered image", message)
+            }
+            RenderError::Busy => write!(f, "Map is busy, render timed out"),
+            RenderError::WorkerGone => write!(f, "Map thread is no longer running"),
+            RenderError::UnknownFormat(name) => write!(f, "Unknown output format: {}", name),
+            RenderError::InvalidSize { width, height } => {
+                write!(f, "Invalid image size {}x{}", width, height)
+            }
+            RenderError::QueryFailed(message) => {
+                write_with_message(f, "Unable to query map", message)
+            }
+            RenderError::NotVector(layer) => {
+                write!(f, "Layer {} has no vector output", layer)
+            }
+            RenderError::InvalidProjection(message) => {
+                write_with_message(f, "Invalid output projection", message)
+            }
+            RenderError::UnknownLayer(name) => write!(f, "Unknown layer: {}", name),
+            RenderError::UnknownStyle(name) => write!(f, "Unknown style: {}", name),
+            RenderError::UnknownResample(name) => write!(
+                f,
+                "Unknown resampling method {}, expected nearest, bilinear or average",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Append mapserver's error message to a description, if it reported one
+fn write_with_message(f: &mut fmt::Formatter<'_>, description: &str, message: &str) -> fmt::Result {
+    if message.is_empty() {
+        write!(f, "{}", description)
+    } else {
+        write!(f, "{}: {}", description, message)
+    }
+}
+
+///
+/// An image format to encode rendered maps with, referenced by its OUTPUTFORMAT name.
+/// Mapserver also accepts a MIME type, e.g. `Named("image/jpeg".to_string())`
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    /// Mapbox Vector Tiles of the vector layers, see `Map::draw_mvt`
+    Mvt,
+    /// A GeoJSON FeatureCollection of the queryable layers, see `Map::draw_geojson`
+    GeoJson,
+    /// Any other OUTPUTFORMAT NAME declared in the mapfile
+    Named(String),
+}
+
+///
+/// How raster layers are resampled when drawn at another resolution than their data,
+/// mapserver's RESAMPLE PROCESSING option
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resample {
+    Nearest,
+    Bilinear,
+    Average,
+}
+
+impl Resample {
+    /// The RESAMPLE value mapserver expects
+    pub fn name(&self) -> &'static str {
+        match self {
+            Resample::Nearest => "NEAREST",
+            Resample::Bilinear => "BILINEAR",
+            Resample::Average => "AVERAGE",
+        }
+    }
+}
+
+impl FromStr for Resample {
+    type Err = RenderError;
+
+    /// Parse a resampling method, ignoring case
+    ///
+    /// ```
+    /// use mapserver_rs::mappool::Resample;
+    ///
+    /// assert_eq!("bilinear".parse::<Resample>(), Ok(Resample::Bilinear));
+    /// assert!("cubic".parse::<Resample>().is_err());
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "NEAREST" => Ok(Resample::Nearest),
+            "BILINEAR" => Ok(Resample::Bilinear),
+            "AVERAGE" => Ok(Resample::Average),
+            _ => Err(RenderError::UnknownResample(s.to_string())),
+        }
+    }
+}
+
+/// CONFIG options a server may set on every map. They tune GDAL's caching, threads and
+/// HTTP reads; options that point mapserver or GDAL at other files or credentials are left
+/// to the mapfile
+pub const CONFIG_ALLOWLIST: &[&str] = &[
+    "CPL_DEBUG",
+    "CPL_VSIL_CURL_CACHE_SIZE",
+    "GDAL_CACHEMAX",
+    "GDAL_DISABLE_READDIR_ON_OPEN",
+    "GDAL_HTTP_MAX_RETRY",
+    "GDAL_HTTP_MULTIRANGE",
+    "GDAL_HTTP_RETRY_DELAY",
+    "GDAL_HTTP_TIMEOUT",
+    "GDAL_NUM_THREADS",
+    "VSI_CACHE",
+    "VSI_CACHE_SIZE",
+];
+
+/// Error parsing `ConfigOptions`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The key is not in `CONFIG_ALLOWLIST`
+    NotAllowed(String),
+    /// An option was not of the form KEY=VALUE
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotAllowed(key) => {
+                write!(f, "CONFIG option '{}' may not be set by the server", key)
+            }
+            ConfigError::Invalid(option) => {
+                write!(f, "expected a CONFIG option as KEY=VALUE, got '{}'", option)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+///
+/// CONFIG options set on each map as it is loaded, overriding the mapfile's own CONFIG
+/// lines. Only keys in `CONFIG_ALLOWLIST` are accepted
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigOptions(Vec<(String, String)>);
+
+impl ConfigOptions {
+    /// The options as (KEY, VALUE) pairs, in the order they are applied
+    pub fn options(&self) -> &[(String, String)] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for ConfigOptions {
+    type Err = ConfigError;
+
+    /// Parse options from a "KEY=VALUE,KEY=VALUE" list. Keys are upper-cased, a later
+    /// value for the same key wins
+    ///
+    /// ```
+    /// use mapserver_rs::mappool::{ConfigError, ConfigOptions};
+    ///
+    /// let options: ConfigOptions = "GDAL_CACHEMAX=256, vsi_cache=TRUE".parse().unwrap();
+    /// assert_eq!(options.options()[1], ("VSI_CACHE".to_string(), "TRUE".to_string()));
+    /// assert_eq!(
+    ///     "GDAL_DATA=/tmp".parse::<ConfigOptions>(),
+    ///     Err(ConfigError::NotAllowed("GDAL_DATA".into()))
+    /// );
+    /// ```
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options: Vec<(String, String)> = Vec::new();
+        for option in s
+            .split(',')
+            .map(str::trim)
+            .filter(|option| !option.is_empty())
+        {
+            let (key, value) = option
+                .split_once('=')
+                .map(|(key, value)| (key.trim().to_ascii_uppercase(), value.trim()))
+                .filter(|(key, value)| {
+                    !key.is_empty() && !value.is_empty() && !value.contains('\0')
+                })
+                .ok_or_else(|| ConfigError::Invalid(option.to_string()))?;
+            if !CONFIG_ALLOWLIST.contains(&key.as_str()) {
+                return Err(ConfigError::NotAllowed(key));
+            }
+            options.retain(|(existing, _)| *existing != key);
+            options.push((key, value.to_string()));
+        }
+        Ok(ConfigOptions(options))
+    }
+}
+
+///
+/// Per-request overrides of the mapfile's SIZE, default output format, layer STATUS,
+/// CLASSGROUP, raster resampling and background transparency
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Image (width, height) in pixels
+    pub size: Option<(i32, i32)>,
+    pub format: Option<OutputFormat>,
+    /// Layers to draw, switching every other layer off
+    pub layers: Option<Vec<String>>,
+    /// Class GROUP to draw the layers with, see `Map::styles`
+    pub style: Option<String>,
+    /// Resampling of raster layers, defaults to each layer's PROCESSING 'RESAMPLE=...'
+    pub resample: Option<Resample>,
+    /// Formats the client accepts, most preferred first, see `OutputFormat::accepted`.
+    /// Without a `format`, the first one the mapfile defines is used
+    pub accept: Vec<OutputFormat>,
+    /// Draw on a transparent background, or an opaque IMAGECOLOR one, defaults to the
+    /// output format's TRANSPARENT
+    pub transparent: Option<bool>,
+    /// EPSG code of the extent and image, e.g. 4326, defaults to the mapfile's PROJECTION.
+    /// Layers are reprojected from their own PROJECTION, see `Map::draw_in_crs`
+    pub epsg: Option<u32>,
+}
+
+impl OutputFormat {
+    /// The OUTPUTFORMAT name mapserver looks the format up by
+    pub fn name(&self) -> &str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Mvt => "mvt",
+            OutputFormat::GeoJson => "geojson",
+            OutputFormat::Named(name) => name,
+        }
+    }
+
+    /// The image formats an HTTP `Accept` header asks for, most preferred first by
+    /// quality value. Wildcards and other types are left to the map's default format
+    ///
+    /// ```
+    /// use mapserver_rs::mappool::OutputFormat;
+    ///
+    /// assert_eq!(
+    ///     OutputFormat::accepted("image/png;q=0.8, image/webp, */*;q=0.1"),
+    ///     vec![OutputFormat::Webp, OutputFormat::Png]
+    /// );
+    /// ```
+    pub fn accepted(accept: &str) -> Vec<OutputFormat> {
+        let mut accepted: Vec<(OutputFormat, f32)> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut params = range.split(';').map(str::trim);
+                let format = match params.next()?.to_ascii_lowercase().as_str() {
+                    "image/png" => OutputFormat::Png,
+                    "image/jpeg" | "image/jpg" => OutputFormat::Jpeg,
+                    "image/webp" => OutputFormat::Webp,
+                    _ => return None,
+                };
+                let quality = params
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.);
+                (quality > 0.).then_some((format, quality))
+            })
+            .collect();
+        // Stable, so equally preferred formats keep the client's order
+        accepted.sort_by(|a, b| b.1.total_cmp(&a.1));
+        accepted.into_iter().map(|(format, _)| format).collect()
+    }
+}
+
+///
+/// An encoded image along with the MIME type of its output format
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedImage {
+    pub bytes: Vec<u8>,
+    pub content_type: String,
+    /// Nothing was drawn, the extent is outside the map or its data. The image is transparent
+    pub empty: bool,
+}
+
+/// MIME type of Mapbox Vector Tiles
+pub const MVT_CONTENT_TYPE: &str = "application/vnd.mapbox-vector-tile";
+
+/// MIME type of GeoJSON, RFC 7946
+pub const GEOJSON_CONTENT_TYPE: &str = "application/geo+json";
+
+/// A fully transparent 256x256 PNG, a 1-bit palette image whose only colour has zero alpha
+pub const BLANK_TILE_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x66, 0xbc, 0x3a,
+    0x25, 0x00, 0x00, 0x00, 0x03, 0x50, 0x4c, 0x54, 0x45, 0x00, 0x00, 0x00, 0xa7, 0x7a, 0x3d, 0xda,
+    0x00, 0x00, 0x00, 0x01, 0x74, 0x52, 0x4e, 0x53, 0x00, 0x40, 0xe6, 0xd8, 0x66, 0x00, 0x00, 0x00,
+    0x1f, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0xed, 0xc1, 0x01, 0x0d, 0x00, 0x00, 0x00, 0xc2, 0xa0,
+    0xf7, 0x4f, 0x6d, 0x0e, 0x37, 0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xbe, 0x0d,
+    0x21, 0x00, 0x00, 0x01, 0x60, 0xe4, 0x9d, 0x97, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+    0xae, 0x42, 0x60, 0x82,
+];
+
+impl RenderedImage {
+    /// The transparent tile served when mapserver encodes an image to nothing
+    pub fn blank_tile() -> Self {
+        RenderedImage {
+            bytes: BLANK_TILE_PNG.to_vec(),
+            content_type: "image/png".to_string(),
+            empty: true,
+        }
+    }
+
+    /// An image as mapserver encoded it. Rasters with nodata or gaps can draw but save
+    /// to an empty buffer, which is served as the blank tile
+    fn encoded(bytes: Vec<u8>, content_type: String) -> Self {
+        if bytes.is_empty() {
+            return RenderedImage::blank_tile();
+        }
+        RenderedImage {
+            bytes,
+            content_type,
+            empty: false,
+        }
+    }
+}
+
+/// True if the extent lies entirely outside the mapfile's `declared` EXTENT, so there is
+/// no data to draw. Maps without an EXTENT are always drawn
+fn outside_extent(declared: &Extent, ext: &Extent) -> bool {
+    declared.width() > 0.
+        && declared.height() > 0.
+        && ext.width() > 0.
+        && ext.height() > 0.
+        && !ext.intersects(declared)
+}
+
+///
+/// The Map struct manages the Mapserver mapObj lifecycle.
 ///
-/// The Map struct manages the Mapserver mapObj lifecycle
+/// A Map is `Send` but not `Sync`: mapserver mutates the mapObj on every draw, so it may
+/// be moved to another thread but never shared between threads. The pool goes further
+/// and confines each map to a single map thread, which loads, draws and frees it.
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<mapserver_rs::mappool::Map>();
+/// ```
 ///
 pub struct Map {
-    map_obj: *mut mapObj,
+    handle: MapHandle,
+    // The mapfile EXTENT, msMapSetExtent overwrites the mapObj's copy on every draw
+    extent: Extent,
 }
 
 impl Map {
-    pub fn from(mapfile_contents: String) -> Self {
-        // Convert mapfile contents to *char
-        let mapfile_cstr = CString::new(mapfile_contents).unwrap();
-        let buffer = mapfile_cstr.as_ptr() as *mut c_char;
+    pub fn try_from(mapfile_contents: String) -> Result<Self, MapError> {
+        Self::try_from_with_retry(mapfile_contents, &LoadRetry::default())
+    }
+
+    /// Load a mapfile, retrying failures other than syntax errors, e.g. data sources
+    /// that cannot be initialized on the first try
+    pub fn try_from_with_retry(
+        mapfile_contents: String,
+        retry: &LoadRetry,
+    ) -> Result<Self, MapError> {
+        let handle = MapHandle::load_from_string_with_retry(&mapfile_contents, retry)
+            .map_err(MapError::LoadFailed)?;
+        let extent = handle.extent();
+        Ok(Map { handle, extent })
+    }
+
+    /// Load a mapfile as if it had been read from a file in the `mappath` directory,
+    /// so its INCLUDEs, SYMBOLSET, FONTSET and relative DATA paths resolve
+    pub fn try_from_with_path(mapfile_contents: String, mappath: &Path) -> Result<Self, MapError> {
+        let handle = MapHandle::load_from_string_with_path(
+            &mapfile_contents,
+            mappath,
+            &LoadRetry::default(),
+        )
+        .map_err(MapError::LoadFailed)?;
+        let extent = handle.extent();
+        Ok(Map { handle, extent })
+    }
+
+    /// Load a mapfile relative to `mappath`, panicking if it is invalid.
+    /// Prefer `try_from_with_path`
+    pub fn from_with_path(mapfile_contents: String, mappath: &Path) -> Self {
+        Self::try_from_with_path(mapfile_contents, mappath).unwrap()
+    }
 
-        let map_obj = unsafe { msLoadMapFromString(buffer, std::ptr::null_mut() as *mut c_char) };
-        if map_obj.is_null() {
-            panic!("Unable to load mapfile");
+    /// Set CONFIG options on the map, overriding the mapfile's. GDAL's options are
+    /// process-wide, so they apply to every map's data sources
+    pub fn set_config_options(&self, options: &ConfigOptions) {
+        for (key, value) in options.options() {
+            self.handle.set_config_option(key, value);
         }
-        Map { map_obj }
     }
 
-    pub fn draw(&self, ext: Extent) -> Vec<u8> {
-        let mut size = 0;
+    /// The map's value for a CONFIG option, from the mapfile or `set_config_options`
+    pub fn config_option(&self, key: &str) -> Option<String> {
+        self.handle.config_option(key)
+    }
 
-        let result_ptr = unsafe {
-            msMapSetExtent(self.map_obj, ext.0, ext.1, ext.2, ext.3);
-            // Draw map
-            let img = msDrawMap(self.map_obj, 0);
-            if img.is_null() {
-                panic!("Unable to render map");
-            }
+    /// Load a mapfile, panicking if it is invalid. Prefer `try_from`
+    pub fn from(mapfile_contents: String) -> Self {
+        Self::try_from(mapfile_contents).unwrap()
+    }
+
+    /// The EXTENT declared in the mapfile, in the map's projection
+    pub fn extent(&self) -> Extent {
+        self.extent
+    }
+
+    /// The union of the extents of all layers. Each extent is in its layer's projection,
+    /// which for most mapfiles is the map's. Layers without an EXTENT are asked for
+    /// the extent of their data source, which may open it.
+    /// None if no layer extent could be determined
+    pub fn layer_extent(&self) -> Option<Extent> {
+        self.handle
+            .layer_extents()
+            .into_iter()
+            .reduce(|union, extent| union.union(&extent))
+    }
+
+    /// Check that every layer can open its data source, returning each layer's name along
+    /// with mapserver's error message if its data could not be read
+    pub fn check_layers(&self) -> Vec<(String, Result<(), String>)> {
+        self.handle
+            .layer_names()
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| (name, self.handle.check_layer(index)))
+            .collect()
+    }
+
+    /// MIME type of the mapfile's default output format
+    pub fn content_type(&self) -> String {
+        format_content_type(self.handle.output_format())
+    }
+
+    /// Names of the output formats available to `draw_as`, from the mapfile's
+    /// OUTPUTFORMAT blocks along with any defaults mapserver provides
+    pub fn available_formats(&self) -> Vec<String> {
+        self.handle
+            .output_formats()
+            .iter()
+            .map(|format| format.name())
+            .collect()
+    }
+
+    /// The first of the accepted formats the mapfile defines, or None to use its default
+    /// format, normally PNG
+    pub fn negotiate_format(&self, accepted: &[OutputFormat]) -> Option<OutputFormat> {
+        let available = self.available_formats();
+        accepted
+            .iter()
+            .find(|format| available.iter().any(|name| name == format.name()))
+            .cloned()
+    }
+
+    /// Render the extent in the mapfile's default output format. Extents entirely outside
+    /// the mapfile's EXTENT are encoded as a transparent image without reading any data
+    pub fn draw(&self, ext: Extent) -> Result<RenderedImage, RenderError> {
+        self.draw_with(ext, None)
+    }
+
+    /// Render the extent, encoding the image with a named OUTPUTFORMAT from the mapfile
+    pub fn draw_as(&self, ext: Extent, format: OutputFormat) -> Result<RenderedImage, RenderError> {
+        self.draw_with_options(
+            ext,
+            &RenderOptions {
+                format: Some(format),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Render the extent at a specific image size, e.g. 512px retina tiles or WMS GetMap sizes.
+    /// The mapfile's SIZE is restored afterwards
+    pub fn draw_sized(
+        &self,
+        ext: Extent,
+        width: i32,
+        height: i32,
+    ) -> Result<RenderedImage, RenderError> {
+        self.draw_with_options(
+            ext,
+            &RenderOptions {
+                size: Some((width, height)),
+                ..Default::default()
+            },
+        )
+    }
 
-            // Save the image and convert to a u8 slice
-            let result_ptr = msSaveImageBuffer(img, &mut size, (*img).format);
-            msFreeImage(img);
-            result_ptr
+    /// Render the extent with any combination of size, output format, layer, style,
+    /// resampling and transparency overrides
+    pub fn draw_with_options(
+        &self,
+        ext: Extent,
+        options: &RenderOptions,
+    ) -> Result<RenderedImage, RenderError> {
+        let draw = || match &options.layers {
+            Some(layers) => self.with_layers(layers, || self.draw_formatted(ext, options)),
+            None => self.draw_formatted(ext, options),
+        };
+        let draw = || match &options.style {
+            Some(style) => self.with_style(style, draw),
+            None => draw(),
         };
+        let draw = || match options.resample {
+            Some(resample) => self
+                .handle
+                .with_raster_processing("RESAMPLE", resample.name(), draw),
+            None => draw(),
+        };
+        let draw = || match options.transparent {
+            Some(transparent) => self.handle.with_transparency(transparent, draw),
+            None => draw(),
+        };
+        match options.epsg {
+            Some(epsg) => self.with_projection(epsg, draw),
+            None => draw(),
+        }
+    }
 
-        let img_bytes = unsafe { std::slice::from_raw_parts(result_ptr, size as usize).to_owned() };
+    /// The styles a request can select, the GROUP names of the mapfile's classes
+    pub fn styles(&self) -> Vec<String> {
+        self.handle.class_groups()
+    }
 
-        unsafe {
-            // Free the image and the temporary buffer
-            libc::free(result_ptr as *mut libc::c_void);
+    /// Switch the named layers on and every other layer off, for the next draw.
+    /// Fails without changing any layer if a name is not defined in the mapfile
+    pub fn set_active_layers(&self, names: &[&str]) -> Result<(), RenderError> {
+        let defined = self.handle.layer_names();
+        if let Some(unknown) = names
+            .iter()
+            .find(|name| !defined.iter().any(|n| n == *name))
+        {
+            return Err(RenderError::UnknownLayer(unknown.to_string()));
+        }
+        self.handle.set_layers_on(names);
+        Ok(())
+    }
+
+    fn draw_formatted(
+        &self,
+        ext: Extent,
+        options: &RenderOptions,
+    ) -> Result<RenderedImage, RenderError> {
+        let format = match &options.format {
+            Some(format) => Some(format.clone()),
+            None => self.negotiate_format(&options.accept),
+        };
+        match format {
+            Some(OutputFormat::Mvt) => return self.draw_mvt(ext),
+            Some(OutputFormat::GeoJson) => return self.draw_geojson(ext),
+            _ => {}
+        }
+        let format_obj = match &format {
+            Some(format) => Some(self.select_format(format)?),
+            None => None,
+        };
+        let draw = || match options.epsg {
+            // The mapfile EXTENT is in the map's own projection, so always draw
+            Some(_) => self.encode(ext, format_obj),
+            None => self.draw_with(ext, format_obj),
         };
+        match options.size {
+            Some((width, height)) => self.with_size(width, height, draw),
+            None => draw(),
+        }
+    }
+
+    /// Encode the vector layers within the extent as a Mapbox Vector Tile, using the
+    /// `mvt` OUTPUTFORMAT. Fails with `NotVector` if a layer that is switched on is a raster,
+    /// since it would be missing from the tile
+    pub fn draw_mvt(&self, ext: Extent) -> Result<RenderedImage, RenderError> {
+        let format = self.select_format(&OutputFormat::Mvt)?;
+        if let Some(layer) = self.handle.raster_layers().into_iter().next() {
+            return Err(RenderError::NotVector(layer));
+        }
+        self.handle
+            .set_extent(ext)
+            .map_err(|_| RenderError::InvalidExtent)?;
+        let bytes = self.handle.write_mvt(format).map_err(RenderError::Encode)?;
+        Ok(RenderedImage {
+            bytes,
+            content_type: MVT_CONTENT_TYPE.to_string(),
+            empty: false,
+        })
+    }
+
+    /// The features of the queryable layers, those with a TEMPLATE, within the extent
+    /// as a GeoJSON FeatureCollection, using the `geojson` OUTPUTFORMAT
+    /// (`DRIVER 'OGR/GEOJSON'`)
+    pub fn draw_geojson(&self, ext: Extent) -> Result<RenderedImage, RenderError> {
+        let format = self.select_format(&OutputFormat::GeoJson)?;
+        self.handle
+            .set_extent(ext)
+            .map_err(|_| RenderError::InvalidExtent)?;
+        let bytes = self
+            .handle
+            .write_ogr(ext, format)
+            .map_err(RenderError::Encode)?;
+        Ok(RenderedImage {
+            bytes,
+            content_type: GEOJSON_CONTENT_TYPE.to_string(),
+            empty: false,
+        })
+    }
+
+    /// Render an extent given in another CRS, e.g. epsg:4326 for WMTS grids in degrees,
+    /// in the mapfile's default output format. Layers are reprojected from their own
+    /// PROJECTION, and the mapfile's projection is restored afterwards
+    pub fn draw_in_crs(&self, ext: Extent, epsg_code: u32) -> Result<RenderedImage, RenderError> {
+        // The mapfile EXTENT is in the map's own projection, so always draw
+        self.with_projection(epsg_code, || self.encode(ext, None))
+    }
+
+    /// Call `f` with the map's output projection set to an EPSG code, then put back the
+    /// mapfile's. A mapfile projection that cannot be restored is an error rather than
+    /// a panic, so the map thread survives it
+    fn with_projection<T>(
+        &self,
+        epsg_code: u32,
+        f: impl FnOnce() -> Result<T, RenderError>,
+    ) -> Result<T, RenderError> {
+        let previous = self.handle.projection();
+        self.handle
+            .set_projection(&format!("init=epsg:{}", epsg_code))
+            .map_err(RenderError::InvalidProjection)?;
+        let result = f();
+        // An empty definition is not parsed, a map without a PROJECTION has it removed again
+        self.handle
+            .set_projection(&previous)
+            .map_err(RenderError::InvalidProjection)?;
+        result
+    }
+
+    /// Attributes of the features at a pixel of the image `draw` would render for the extent,
+    /// e.g. for GetFeatureInfo. Only layers with a TEMPLATE are queryable, and features
+    /// within the layer's TOLERANCE of the pixel match.
+    ///
+    /// Pixels are counted from the top left of the image, so (0, 0) is the top-left pixel
+    /// and y increases downwards. Each pixel is converted to map coordinates at its centre,
+    /// the same way mapserver converts image coordinates for its own queries
+    pub fn query_point(
+        &self,
+        ext: Extent,
+        px: i32,
+        py: i32,
+    ) -> Result<Vec<HashMap<String, String>>, RenderError> {
+        self.handle
+            .set_extent(ext)
+            .map_err(|_| RenderError::InvalidExtent)?;
+        // Mapserver adjusts the extent to the image's aspect ratio
+        let adjusted = self.handle.extent();
+        let cellsize = self.handle.cellsize();
+        let x = adjusted.0 + px as f64 * cellsize;
+        let y = adjusted.3 - py as f64 * cellsize;
+        self.handle
+            .query_point(x, y)
+            .map_err(RenderError::QueryFailed)
+    }
+
+    /// Render a block of `tiles_across` x `tiles_across` tiles as a single image, so overlapping
+    /// source data is only read once. Use `metatile_extent` on a `Tile` for the extent
+    /// and `slice_metatile` to cut the result into tiles
+    pub fn draw_metatile(&self, ext: Extent, tiles_across: u32) -> Result<Metatile, RenderError> {
+        let size = tiles_across.saturating_mul(TILE_SIZE).min(i32::MAX as u32) as i32;
+        self.with_size(size, size, || {
+            let img = self.draw_image(ext)?;
+            Ok(Metatile {
+                tiles_across,
+                pixels: img.rgba_pixels().ok_or_else(|| {
+                    RenderError::DrawFailed("Renderer has no RGBA raster buffer".to_string())
+                })?,
+            })
+        })
+    }
+
+    /// Encode each tile of a metatile in the mapfile's default output format,
+    /// row by row from the top left, matching `Tile::metatile_tiles`
+    pub fn slice_metatile(&self, metatile: &Metatile) -> Result<Vec<RenderedImage>, RenderError> {
+        let pixels = &metatile.pixels;
+        let tile_size = TILE_SIZE as usize;
+        let mut tiles =
+            Vec::with_capacity((metatile.tiles_across * metatile.tiles_across) as usize);
+
+        let format = self.handle.output_format().ok_or_else(no_format)?;
+        for row in 0..metatile.tiles_across as usize {
+            for col in 0..metatile.tiles_across as usize {
+                let offset = pixels.offset(col * tile_size, row * tile_size);
+                let bytes = format
+                    .save_raster(pixels, offset, TILE_SIZE, TILE_SIZE)
+                    .map_err(RenderError::SaveFailed)?;
+                tiles.push(RenderedImage {
+                    bytes,
+                    content_type: format.content_type(),
+                    empty: false,
+                });
+            }
+        }
+        Ok(tiles)
+    }
+
+    /// Look up an OUTPUTFORMAT declared in the mapfile by name or MIME type
+    fn select_format(&self, format: &OutputFormat) -> Result<FormatHandle<'_>, RenderError> {
+        self.handle
+            .select_output_format(format.name())
+            .ok_or_else(|| RenderError::UnknownFormat(format.name().to_string()))
+    }
+
+    /// Temporarily resize the map, restoring the mapfile's SIZE once `draw` returns
+    fn with_size<T>(
+        &self,
+        width: i32,
+        height: i32,
+        draw: impl FnOnce() -> Result<T, RenderError>,
+    ) -> Result<T, RenderError> {
+        let invalid_size = RenderError::InvalidSize { width, height };
+        if width <= 0 || height <= 0 || width > MAX_IMAGE_SIZE || height > MAX_IMAGE_SIZE {
+            return Err(invalid_size);
+        }
+
+        let (prev_width, prev_height) = self.handle.size();
+        if !self.handle.set_size(width, height) {
+            return Err(invalid_size);
+        }
+        let result = draw();
+        self.handle.set_size(prev_width, prev_height);
+        result
+    }
+
+    /// Temporarily switch on only the named layers, restoring each layer's STATUS
+    /// once `draw` returns so the next request on this map sees the mapfile's layers
+    fn with_layers<T>(
+        &self,
+        layers: &[String],
+        draw: impl FnOnce() -> Result<T, RenderError>,
+    ) -> Result<T, RenderError> {
+        let names: Vec<&str> = layers.iter().map(String::as_str).collect();
+        let previous = self.handle.layer_statuses();
+        self.set_active_layers(&names)?;
+        let result = draw();
+        self.handle.set_layer_statuses(&previous);
+        result
+    }
+
+    /// Draw only the classes of a style's GROUP, restoring the mapfile's CLASSGROUPs
+    /// once `draw` returns
+    fn with_style<T>(
+        &self,
+        style: &str,
+        draw: impl FnOnce() -> Result<T, RenderError>,
+    ) -> Result<T, RenderError> {
+        if !self.styles().iter().any(|group| group == style) {
+            return Err(RenderError::UnknownStyle(style.to_string()));
+        }
+        self.handle.with_class_group(style, draw)
+    }
+
+    /// Set the extent and draw the map
+    fn draw_image(&self, ext: Extent) -> Result<ImageHandle<'_>, RenderError> {
+        self.handle
+            .set_extent(ext)
+            .map_err(|_| RenderError::InvalidExtent)?;
+        self.handle.draw().map_err(RenderError::DrawFailed)
+    }
+
+    fn draw_with(
+        &self,
+        ext: Extent,
+        format: Option<FormatHandle<'_>>,
+    ) -> Result<RenderedImage, RenderError> {
+        if outside_extent(&self.extent, &ext) {
+            return self.draw_empty(format);
+        }
+        self.encode(ext, format)
+    }
+
+    /// Draw the extent and encode the image
+    fn encode(
+        &self,
+        ext: Extent,
+        format: Option<FormatHandle<'_>>,
+    ) -> Result<RenderedImage, RenderError> {
+        let img = self.draw_image(ext)?;
+        let format = format.or_else(|| img.format()).ok_or_else(no_format)?;
+        let bytes = img.save(format).map_err(RenderError::Encode)?;
+        Ok(RenderedImage::encoded(bytes, format.content_type()))
+    }
+
+    /// Encode a transparent image at the map's size without drawing any layers
+    fn draw_empty(&self, format: Option<FormatHandle<'_>>) -> Result<RenderedImage, RenderError> {
+        let format = format
+            .or_else(|| self.handle.output_format())
+            .ok_or_else(no_format)?;
+        let (width, height) = self.handle.size();
+        let (width, height) = (width.max(0) as u32, height.max(0) as u32);
+        let bytes = format
+            .save_raster(&RasterPixels::transparent(width, height), 0, width, height)
+            .map_err(RenderError::SaveFailed)?;
+        Ok(RenderedImage {
+            bytes,
+            content_type: format.content_type(),
+            empty: true,
+        })
+    }
+}
+
+///
+/// A rendered block of tiles, held as raw pixels until it is sliced with `Map::slice_metatile`
+///
+pub struct Metatile {
+    pub tiles_across: u32,
+    pixels: RasterPixels,
+}
+
+fn no_format() -> RenderError {
+    RenderError::SaveFailed("Map has no output format".to_string())
+}
 
-        img_bytes
+/// MIME type of an output format, falling back to a generic binary type
+fn format_content_type(format: Option<FormatHandle<'_>>) -> String {
+    format.map_or_else(
+        || "application/octet-stream".to_string(),
+        |format| format.content_type(),
+    )
+}
+
+/// Load a mapfile, render one tile in its default output format and drop the map, for
+/// scripts and tests that do not need a `MapPool`. A mapfile that fails to load is
+/// reported as `RenderError::DrawFailed`.
+///
+/// Dropping the map frees it, but not mapserver's global state, such as GDAL's cache of
+/// open datasets and the PROJ contexts. A pool cleans that up once its last map is gone,
+/// this does not, since another map may be in use elsewhere in the process. Calling it
+/// repeatedly in a long running process therefore holds on to datasets as it goes,
+/// prefer a pool there
+///
+/// ```
+/// use mapserver_rs::coordinates::Tile;
+/// use mapserver_rs::mappool::render_tile;
+///
+/// let png = render_tile("MAP SIZE 256 256 END", &Tile::from_zxy(7, 26, 48)).unwrap();
+/// assert_eq!(&png[1..4], b"PNG");
+/// ```
+pub fn render_tile(mapfile: &str, tile: &Tile) -> Result<Vec<u8>, RenderError> {
+    let map = Map::try_from(mapfile.to_string())
+        .map_err(|err| RenderError::DrawFailed(err.to_string()))?;
+    let image = map.draw(Extent::from(tile.bbox_mercator()))?;
+    Ok(image.bytes)
+}
+
+///
+/// Tracks renders in flight across all maps in a pool. When the lookup table
+/// empties while renders are still in flight, the global cleanup is deferred
+/// to whichever thread finishes the last render
+///
+#[derive(Debug, Default)]
+struct RenderState {
+    in_flight: usize,
+    cleanup_pending: bool,
+}
+
+///
+/// Counts a render as in flight for as long as it is alive
+///
+#[derive(Debug)]
+struct InFlightGuard {
+    state: Arc<Mutex<RenderState>>,
+}
+
+impl InFlightGuard {
+    fn new(state: Arc<Mutex<RenderState>>) -> Self {
+        state.lock().unwrap().in_flight += 1;
+        InFlightGuard { state }
     }
 }
 
-impl Drop for Map {
+impl Drop for InFlightGuard {
     fn drop(&mut self) {
-        unsafe {
-            // We cannot do a full msCleanup() or msGDALCleanup() here
-            msFreeMap(self.map_obj);
-            msDebugCleanup();
+        // Never panic in drop, a poisoned lock just skips the deferred cleanup
+        if let Ok(mut state) = self.state.lock() {
+            state.in_flight -= 1;
+            if state.in_flight == 0 && state.cleanup_pending {
+                state.cleanup_pending = false;
+                unsafe {
+                    cleanup_globals();
+                }
+            }
         }
     }
 }
 
 ///
-/// MapRenderChannel wraps two channels, forming a bidirectional channel
-/// to receive extents and send images
+/// A single render job sent to a map thread,
+/// with its own channel to send the image back on.
+/// The render is in flight until the map thread is done with the request
+///
+#[derive(Debug)]
+struct RenderRequest {
+    extent: Extent,
+    options: RenderOptions,
+    img_sender: Sender<Result<RenderedImage, RenderError>>,
+    _in_flight: InFlightGuard,
+}
+
+///
+/// MapRenderChannel wraps a request channel to send extents to the map thread,
+/// each request carrying a one-shot channel to receive the image
 ///
 #[derive(Debug, Clone)]
 pub struct MapRenderChannel {
-    extent_sender: crossbeam_channel::Sender<Extent>,
-    img_receiver: crossbeam_channel::Receiver<Vec<u8>>,
+    request_sender: Sender<RenderRequest>,
+    render_timeout: Option<Duration>,
+    render_state: Arc<Mutex<RenderState>>,
 }
 
 impl MapRenderChannel {
-    pub fn render(&self, ext: Extent) -> Vec<u8> {
-        match self.extent_sender.send(ext) {
-            Ok(_) => self.img_receiver.recv().unwrap(),
-            Err(_) => todo!("MapRenderThread is not alive, this should never happen"),
+    pub fn render(&self, ext: Extent) -> Result<RenderedImage, RenderError> {
+        self.render_with_options(ext, RenderOptions::default())
+    }
+
+    /// Render on the map thread with size and output format overrides, see `Map::draw_with_options`
+    pub fn render_with_options(
+        &self,
+        ext: Extent,
+        options: RenderOptions,
+    ) -> Result<RenderedImage, RenderError> {
+        let (img_sender, img_receiver) = bounded(1);
+        let request = RenderRequest {
+            extent: ext,
+            options,
+            img_sender,
+            _in_flight: InFlightGuard::new(self.render_state.clone()),
+        };
+
+        let timeout = match self.render_timeout {
+            Some(timeout) => timeout,
+            None => {
+                return match self.request_sender.send(request) {
+                    // The image sender is only dropped unanswered if the map thread died
+                    Ok(_) => img_receiver.recv().unwrap_or(Err(RenderError::WorkerGone)),
+                    Err(_) => Err(RenderError::WorkerGone),
+                };
+            }
+        };
+
+        // The deadline covers both waiting in the queue and the render itself
+        let deadline = Instant::now() + timeout;
+        match self.request_sender.send_deadline(request, deadline) {
+            Ok(_) => match img_receiver.recv_deadline(deadline) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => Err(RenderError::Busy),
+                Err(RecvTimeoutError::Disconnected) => Err(RenderError::WorkerGone),
+            },
+            Err(SendTimeoutError::Timeout(_)) => Err(RenderError::Busy),
+            Err(SendTimeoutError::Disconnected(_)) => Err(RenderError::WorkerGone),
         }
     }
+
+    /// `render_with_options` for async callers. The wait for the map thread happens on
+    /// tokio's blocking threadpool, so it never stalls the runtime's workers
+    pub async fn render_async(
+        &self,
+        ext: Extent,
+        options: RenderOptions,
+    ) -> Result<RenderedImage, RenderError> {
+        let renderer = self.clone();
+        tokio::task::spawn_blocking(move || renderer.render_with_options(ext, options))
+            .await
+            .unwrap_or(Err(RenderError::WorkerGone))
+    }
+}
+
+/// Maps in a lookup table, the garbage collector tears mapserver down when none are left
+fn count_live_maps(lookup: &HashMap<u64, MapEntry>) -> usize {
+    lookup.len()
+}
+
+///
+/// Compact lookup key for a mapfile. Distinct mapfiles are assumed never to collide,
+/// with 64 bits that is astronomically unlikely for the number of live maps in a pool
+///
+pub(crate) fn mapfile_key(mapfile_str: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    mapfile_str.hash(&mut hasher);
+    hasher.finish()
+}
+
+///
+/// An entry in the MapPool lookup table. The id distinguishes successive
+/// map threads created for the same mapfile
+///
+#[derive(Debug)]
+struct MapEntry {
+    id: u64,
+    channel: MapRenderChannel,
+    last_access: Instant,
+    // Never sent on, dropping the entry disconnects it and stops the map thread
+    _evict_sender: Sender<()>,
 }
 
 ///
@@ -104,76 +1103,182 @@ impl MapRenderChannel {
 ///
 #[derive(Debug)]
 pub struct MapPool {
-    lookup: Arc<Mutex<HashMap<String, MapRenderChannel>>>,
+    lookup: Arc<Mutex<HashMap<u64, MapEntry>>>,
     threads: ThreadPool,
-    exit_sender: Sender<String>,
+    exit_sender: Sender<(u64, u64)>,
+    next_id: AtomicU64,
+    render_state: Arc<Mutex<RenderState>>,
+    // Never sent on, dropping the sender disconnects every thread's receiver
+    shutdown_sender: Option<Sender<()>>,
+    shutdown_receiver: Receiver<()>,
+    idle_timeout: Duration,
+    queue_size: usize,
+    render_timeout: Option<Duration>,
+    max_maps: usize,
+    // Map threads, excluding the GC thread
+    size: usize,
+    load_retry: LoadRetry,
+    config_options: ConfigOptions,
 }
 
 impl MapPool {
-    pub fn acquire_or_create(&mut self, mapfile_str: String) -> MapRenderChannel {
+    /// Get the render channel for a mapfile, starting a map thread for it if needed.
+    /// A new map is loaded before returning, so an invalid mapfile is an error here
+    /// rather than on the first render.
+    /// Only the lookup table is locked, so different mapfiles can be acquired concurrently
+    pub fn acquire_or_create(&self, mapfile_str: String) -> Result<MapRenderChannel, MapError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let key = mapfile_key(&mapfile_str);
         let mut lookup = self.lookup.lock().unwrap();
 
-        let result = lookup.entry(mapfile_str.clone()).or_insert_with(|| {
-            // Pair of zero-bounded "rendevous" channels mimic request-response
-            let (extent_sender, extent_receiver) = bounded(0);
-            let (img_sender, img_receiver) = bounded(0);
+        // Make room for a new map by evicting the least recently used
+        if !lookup.contains_key(&key) && lookup.len() >= self.max_maps {
+            let lru = lookup
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| *key);
+            if let Some(lru) = lru {
+                lookup.remove(&lru);
+            }
+        }
+
+        // Only the map thread keeps the full mapfile
+        let mut loaded = None;
+        let result = lookup.entry(key).or_insert_with(|| {
+            // By default a zero-bounded "rendevous" channel mimics request-response,
+            // a bounded queue lets requests wait for the map thread up to the render timeout
+            let (request_sender, request_receiver) = bounded::<RenderRequest>(self.queue_size);
+            let (evict_sender, evicted) = bounded::<()>(0);
+            let (loaded_sender, loaded_receiver) = bounded::<Result<(), MapError>>(1);
+            loaded = Some(loaded_receiver);
+
+            // A new map is about to be loaded, any deferred cleanup is no longer safe.
+            // If a deferred cleanup is running right now, wait for it to finish first
+            self.render_state.lock().unwrap().cleanup_pending = false;
 
             let threadpool = self.threads.clone();
-            let mapfile_str2 = mapfile_str.clone();
             let exit = self.exit_sender.clone();
+            let shutdown = self.shutdown_receiver.clone();
+            let idle_timeout = self.idle_timeout;
+            let load_retry = self.load_retry;
+            let config_options = self.config_options.clone();
 
+            // The map is loaded, drawn and freed on this thread only,
+            // requests reach it through the channel rather than sharing the Map
             threadpool.execute(move || {
-                let map = Map::from(mapfile_str2);
-                loop {
-                    select! {
-                      recv(extent_receiver) -> extent => {
-                          if let Ok(extent) = extent {
-                              let img = map.draw(extent);
-                              img_sender.send(img).unwrap();
-                          } else {
-                              break
-                          }
-                      },
-                      default(Duration::from_secs(MAP_IDLE_TIMEOUT_SECONDS)) => break,
+                match Map::try_from_with_retry(mapfile_str, &load_retry) {
+                    Ok(map) => {
+                        map.set_config_options(&config_options);
+                        loaded_sender.send(Ok(())).ok();
+                        loop {
+                            select! {
+                              recv(request_receiver) -> request => {
+                                  if let Ok(request) = request {
+                                      // A failed draw is reported back, the thread stays alive.
+                                      // The requester may have timed out and gone away
+                                      request
+                                        .img_sender
+                                        .send(map.draw_with_options(request.extent, &request.options))
+                                        .ok();
+                                  } else {
+                                      break
+                                  }
+                              },
+                              recv(shutdown) -> _ => break,
+                              recv(evicted) -> _ => break,
+                              default(idle_timeout) => break,
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        loaded_sender.send(Err(err)).ok();
                     }
                 }
-                exit.send(mapfile_str).unwrap();
+                // The GC thread is already gone if the pool is shutting down
+                exit.send((key, id)).ok();
             });
 
-            MapRenderChannel {
-                extent_sender,
-                img_receiver,
+            MapEntry {
+                id,
+                channel: MapRenderChannel {
+                    request_sender,
+                    render_timeout: self.render_timeout,
+                    render_state: self.render_state.clone(),
+                },
+                last_access: Instant::now(),
+                _evict_sender: evict_sender,
             }
         });
-        result.clone()
+        result.last_access = Instant::now();
+        let channel = result.channel.clone();
+        drop(lookup);
+
+        // Wait for a new map to load without holding the lookup lock.
+        // The map thread only drops the sender unanswered if it panicked
+        if let Some(loaded) = loaded {
+            if let Err(err) = loaded.recv().unwrap_or_else(|_| {
+                Err(MapError::LoadFailed(
+                    "Map thread exited while loading".to_string(),
+                ))
+            }) {
+                let mut lookup = self.lookup.lock().unwrap();
+                if lookup.get(&key).is_some_and(|entry| entry.id == id) {
+                    lookup.remove(&key);
+                }
+                return Err(err);
+            }
+        }
+        Ok(channel)
     }
 
+    /// Create a pool of `size` map threads, idle maps are dropped after one hour
     pub fn create(size: usize) -> Self {
-        let lookup = Arc::new(Mutex::new(HashMap::new()));
+        Self::with_timeout(size, Duration::from_secs(MAP_IDLE_TIMEOUT_SECONDS))
+    }
+
+    /// Create a pool of `size` map threads, idle maps are dropped after `idle`.
+    /// At most `size` maps are live at once, see `with_max_maps`
+    pub fn with_timeout(size: usize, idle: Duration) -> Self {
+        let lookup = Arc::new(Mutex::new(HashMap::<u64, MapEntry>::new()));
         let threads = ThreadPool::with_name("MapserverThreadPool".into(), size + 1);
-        let (exit_sender, exit_receiver): (
-            crossbeam_channel::Sender<String>,
-            crossbeam_channel::Receiver<String>,
-        ) = bounded(0);
+        let (exit_sender, exit_receiver) = bounded::<(u64, u64)>(0);
+
+        let (shutdown_sender, shutdown_receiver) = bounded::<()>(0);
+
+        let render_state = Arc::new(Mutex::new(RenderState::default()));
 
         let map_lookup = lookup.clone();
+        let map_render_state = render_state.clone();
+        let shutdown = shutdown_receiver.clone();
 
         // Spawn a "Garbage Collection" thread
         threads.execute(move || loop {
-            while let Ok(exited_mapfile) = exit_receiver.recv() {
-                let mut lk = map_lookup.lock().unwrap();
-                lk.remove(&exited_mapfile).unwrap();
-                if lk.len() == 0 {
-                    // All maps are dropped, only now is it safe to cleanup
+            let (exited_key, exited_id) = select! {
+              recv(exit_receiver) -> exited => match exited {
+                  Ok(exited) => exited,
+                  Err(_) => break,
+              },
+              recv(shutdown) -> _ => break,
+            };
+            let mut lk = map_lookup.lock().unwrap();
+            // The entry may already be gone, or replaced by a newer thread for the same mapfile
+            let is_current = lk
+                .get(&exited_key)
+                .is_some_and(|entry| entry.id == exited_id);
+            if !is_current {
+                continue;
+            }
+            lk.remove(&exited_key);
+            if count_live_maps(&lk) == 0 {
+                // All maps are dropped, but renders may still be in flight
+                // on channels handed out earlier. Only cleanup once those finish
+                let mut state = map_render_state.lock().unwrap();
+                if state.in_flight == 0 {
                     unsafe {
-                        // We cannot do a full msCleanup() here either :-/
-                        // What *can* we safely cleanup without fully unloading the shared library?
-                        msGDALCleanup();
-                        msOGRCleanup();
-                        msIO_Cleanup();
-                        msSetPROJ_DATA(std::ptr::null(), std::ptr::null());
-                        msProjectionContextPoolCleanup();
+                        cleanup_globals();
                     }
+                } else {
+                    state.cleanup_pending = true;
                 }
             }
         });
@@ -182,17 +1287,101 @@ impl MapPool {
             lookup,
             threads,
             exit_sender,
+            next_id: AtomicU64::new(0),
+            render_state,
+            shutdown_sender: Some(shutdown_sender),
+            shutdown_receiver,
+            idle_timeout: idle,
+            queue_size: 0,
+            render_timeout: None,
+            max_maps: size,
+            size,
+            load_retry: LoadRetry::default(),
+            config_options: ConfigOptions::default(),
         }
     }
-}
 
-impl Drop for MapPool {
-    fn drop(&mut self) {
-        unsafe {
-            msCleanup();
-        }
+    /// Render a tile and all its children down to `target_zoom`, e.g. to seed a cache.
+    /// Tiles are rendered one after another on the mapfile's own map thread,
+    /// in the order of `Tile::children`, so the parent is last.
+    /// Stops at the first tile that fails to render, a mapfile that fails to load
+    /// is reported as `RenderError::DrawFailed`
+    pub fn render_children(
+        &self,
+        parent: Tile,
+        target_zoom: u32,
+        mapfile_str: String,
+    ) -> Result<Vec<(Tile, Vec<u8>)>, RenderError> {
+        let renderer = self
+            .acquire_or_create(mapfile_str)
+            .map_err(|err| RenderError::DrawFailed(err.to_string()))?;
+        parent
+            .children(target_zoom)
+            .into_iter()
+            .map(|tile| {
+                let image = renderer.render(Extent::from(tile.bbox_mercator()))?;
+                Ok((tile, image.bytes))
+            })
+            .collect()
     }
-}
+
+    /// Number of maps currently loaded, each with its own thread. Mapserver's globals are
+    /// cleaned up when this drops to zero and no renders are in flight
+    pub fn live_map_count(&self) -> usize {
+        count_live_maps(&self.lookup.lock().unwrap())
+    }
+
+    /// Cap the number of live maps, evicting the least recently used map
+    /// when a new one is needed. Defaults to, and is capped at, the pool size,
+    /// since a map beyond that would wait for a free thread that never comes
+    pub fn with_max_maps(mut self, max_maps: usize) -> Self {
+        self.max_maps = max_maps.clamp(1, self.size.max(1));
+        self
+    }
+
+    /// Queue up to `queue_size` requests per map and fail renders with
+    /// `RenderError::Busy` if they are not completed within `render_timeout`,
+    /// instead of blocking until the map thread is free
+    pub fn with_render_queue(mut self, queue_size: usize, render_timeout: Duration) -> Self {
+        self.queue_size = queue_size;
+        self.render_timeout = Some(render_timeout);
+        self
+    }
+
+    /// Retry loading new maps whose data sources fail to initialize, see `LoadRetry`.
+    /// Mapfile syntax errors are never retried
+    pub fn with_load_retry(mut self, load_retry: LoadRetry) -> Self {
+        self.load_retry = load_retry;
+        self
+    }
+
+    /// Set these CONFIG options on every map as it is loaded, see `ConfigOptions`
+    pub fn with_config_options(mut self, config_options: ConfigOptions) -> Self {
+        self.config_options = config_options;
+        self
+    }
+
+    /// Stop every map thread and the GC thread, wait for them to exit,
+    /// then clean up mapserver.
+    /// Prefer this over dropping the pool, since `Drop` cleans up
+    /// mapserver immediately even if map threads are still mid-render.
+    /// Renders in flight are completed, any later renders on outstanding
+    /// `MapRenderChannel`s will fail
+    pub fn shutdown(mut self) {
+        // Disconnect the shutdown channel, waking every thread
+        self.shutdown_sender.take();
+        self.threads.join();
+        // Now that no threads are running, Drop can safely run msCleanup()
+    }
+}
+
+impl Drop for MapPool {
+    fn drop(&mut self) {
+        unsafe {
+            cleanup();
+        }
+    }
+}
 
 #[cfg(test)]
 mod test {
@@ -201,8 +1390,8 @@ mod test {
     #[test]
     fn test_mappool() {
         let mapfile_str = "MAP END".to_string();
-        let mut map_pool = MapPool::create(20);
-        let mapthread = map_pool.acquire_or_create(mapfile_str);
+        let map_pool = MapPool::create(20);
+        let mapthread = map_pool.acquire_or_create(mapfile_str).unwrap();
 
         let extent = Extent(
             -11711375.725741565,
@@ -210,9 +1399,994 @@ mod test {
             -11711222.851684995,
             4940889.508353792,
         );
-        let img = mapthread.render(extent);
+        let img = mapthread.render(extent).unwrap();
 
         // The resulting png-encoded image is likely > 10kb
-        assert!(img.len() >= 10_000);
+        assert!(img.bytes.len() >= 10_000);
+        assert_eq!(img.content_type, "image/png");
+    }
+
+    #[test]
+    fn test_config_options() {
+        let options: ConfigOptions = "GDAL_CACHEMAX=64, gdal_cachemax=256,CPL_DEBUG=ON"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            options.options(),
+            [
+                ("GDAL_CACHEMAX".to_string(), "256".to_string()),
+                ("CPL_DEBUG".to_string(), "ON".to_string())
+            ]
+        );
+        assert!("".parse::<ConfigOptions>().unwrap().is_empty());
+        assert_eq!(
+            "PROJ_DATA=/tmp".parse::<ConfigOptions>(),
+            Err(ConfigError::NotAllowed("PROJ_DATA".into()))
+        );
+        assert_eq!(
+            "GDAL_CACHEMAX".parse::<ConfigOptions>(),
+            Err(ConfigError::Invalid("GDAL_CACHEMAX".into()))
+        );
+        assert!("GDAL_CACHEMAX=".parse::<ConfigOptions>().is_err());
+
+        let map = Map::from("MAP END".to_string());
+        assert_eq!(map.config_option("GDAL_CACHEMAX"), None);
+        map.set_config_options(&options);
+        assert_eq!(map.config_option("GDAL_CACHEMAX"), Some("256".to_string()));
+        assert_eq!(map.config_option("CPL_DEBUG"), Some("ON".to_string()));
+
+        // Maps loaded by the pool still render with the options set
+        let map_pool = MapPool::create(1).with_config_options(options);
+        let renderer = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        assert!(renderer
+            .render(Extent::from(Tile::from_zxy(0, 0, 0).bbox_mercator()))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_render_children() {
+        let map_pool = MapPool::create(2);
+        let parent = Tile::from_zxy(7, 26, 48);
+        let tiles = map_pool
+            .render_children(parent.clone(), 8, "MAP END".to_string())
+            .unwrap();
+
+        assert_eq!(tiles.len(), 5);
+        assert!(tiles[..4].iter().all(|(tile, _)| tile.zoom == 8));
+        assert_eq!(tiles[4].0, parent);
+        assert!(tiles.iter().all(|(_, bytes)| !bytes.is_empty()));
+        // All rendered by the one map thread
+        assert_eq!(map_pool.live_map_count(), 1);
+    }
+
+    #[test]
+    fn test_live_map_count() {
+        let map_pool = MapPool::create(4);
+        assert_eq!(map_pool.live_map_count(), 0);
+        map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        map_pool
+            .acquire_or_create("MAP NAME 'other' END".to_string())
+            .unwrap();
+        assert_eq!(map_pool.live_map_count(), 2);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let map_pool = MapPool::with_timeout(2, Duration::from_millis(50));
+        let mapthread = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        assert!(mapthread.render(Extent(0., 0., 1., 1.)).is_ok());
+        assert_eq!(map_pool.lookup.lock().unwrap().len(), 1);
+
+        // Wait for the map thread to idle out and be garbage collected
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !map_pool.lookup.lock().unwrap().is_empty() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "map was never evicted"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_live_map_count_drains() {
+        let map_pool = MapPool::with_timeout(2, Duration::from_millis(50));
+        for mapfile in ["MAP END", "MAP NAME 'other' END"] {
+            let mapthread = map_pool.acquire_or_create(mapfile.to_string()).unwrap();
+            assert!(mapthread.render(Extent(0., 0., 1., 1.)).is_ok());
+        }
+        assert_eq!(map_pool.live_map_count(), 2);
+
+        // Both maps idle out, and with nothing in flight cleanup runs immediately
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while map_pool.live_map_count() > 0 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "maps were never evicted"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let state = map_pool.render_state.lock().unwrap();
+        assert_eq!(state.in_flight, 0);
+        assert!(!state.cleanup_pending);
+    }
+
+    #[test]
+    fn test_render_timeout_busy() {
+        // A map thread that never serves its queue
+        let (request_sender, _request_receiver) = bounded(1);
+        let mapthread = MapRenderChannel {
+            request_sender,
+            render_timeout: Some(Duration::from_millis(20)),
+            render_state: Arc::new(Mutex::new(RenderState::default())),
+        };
+
+        // Queued but never completed
+        assert_eq!(
+            mapthread.render(Extent(0., 0., 1., 1.)),
+            Err(RenderError::Busy)
+        );
+        // Queue is full
+        assert_eq!(
+            mapthread.render(Extent(0., 0., 1., 1.)),
+            Err(RenderError::Busy)
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_render_async() {
+        // A map thread that never answers, so every render waits out the timeout
+        let (request_sender, _request_receiver) = bounded(64);
+        let mapthread = MapRenderChannel {
+            request_sender,
+            render_timeout: Some(Duration::from_millis(500)),
+            render_state: Arc::new(Mutex::new(RenderState::default())),
+        };
+
+        let started = std::time::Instant::now();
+        let renders: Vec<_> = (0..32)
+            .map(|_| {
+                let mapthread = mapthread.clone();
+                tokio::spawn(async move {
+                    mapthread
+                        .render_async(Extent(0., 0., 1., 1.), RenderOptions::default())
+                        .await
+                })
+            })
+            .collect();
+
+        // The single runtime thread stays free for other tasks while the renders wait
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(started.elapsed() < Duration::from_millis(250));
+
+        for render in renders {
+            assert_eq!(render.await.unwrap(), Err(RenderError::Busy));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_render_async_map() {
+        let map_pool = MapPool::create(2);
+        let mapthread = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        let image = mapthread
+            .render_async(Extent(0., 0., 1., 1.), RenderOptions::default())
+            .await
+            .unwrap();
+        assert!(!image.bytes.is_empty());
+    }
+
+    #[test]
+    fn test_render_queue() {
+        let map_pool = MapPool::create(2).with_render_queue(4, Duration::from_secs(5));
+        let mapthread = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let mapthread = mapthread.clone();
+                std::thread::spawn(move || mapthread.render(Extent(0., 0., 1., 1.)))
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let map_pool = MapPool::create(2);
+        let mapthread = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        assert!(mapthread.render(Extent(0., 0., 1., 1.)).is_ok());
+
+        // Outstanding channels do not keep the map threads alive
+        let threads = map_pool.threads.clone();
+        map_pool.shutdown();
+        assert_eq!(threads.active_count(), 0);
+        assert_eq!(threads.queued_count(), 0);
+        assert_eq!(threads.panic_count(), 0);
+        drop(mapthread);
+    }
+
+    #[test]
+    fn test_reacquire_around_idle_expiry() {
+        let map_pool = MapPool::with_timeout(2, Duration::from_millis(1));
+        for i in 0..200 {
+            map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+            std::thread::sleep(Duration::from_micros(i * 10));
+        }
+
+        // The GC thread survived and still evicts maps
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !map_pool.lookup.lock().unwrap().is_empty() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "map was never evicted"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(map_pool.threads.panic_count(), 0);
+    }
+
+    #[test]
+    fn test_stale_channel_worker_gone() {
+        let map_pool = MapPool::with_timeout(2, Duration::from_millis(20));
+        let mapthread = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+        assert!(mapthread.render(Extent(0., 0., 1., 1.)).is_ok());
+
+        // Wait for the map thread to idle out and be garbage collected
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !map_pool.lookup.lock().unwrap().is_empty() {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "map was never evicted"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(
+            mapthread.render(Extent(0., 0., 1., 1.)),
+            Err(RenderError::WorkerGone)
+        );
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let map_pool = MapPool::create(4).with_max_maps(2);
+        let first = map_pool
+            .acquire_or_create("MAP NAME 'first' END".to_string())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        map_pool
+            .acquire_or_create("MAP NAME 'second' END".to_string())
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        map_pool
+            .acquire_or_create("MAP NAME 'third' END".to_string())
+            .unwrap();
+
+        {
+            let lookup = map_pool.lookup.lock().unwrap();
+            assert_eq!(lookup.len(), 2);
+            assert!(!lookup.contains_key(&mapfile_key("MAP NAME 'first' END")));
+            assert!(lookup.contains_key(&mapfile_key("MAP NAME 'second' END")));
+            assert!(lookup.contains_key(&mapfile_key("MAP NAME 'third' END")));
+        }
+
+        // The evicted map thread stops even though a channel is still held
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while first.render(Extent(0., 0., 1., 1.)) != Err(RenderError::WorkerGone) {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "map thread never stopped"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_max_maps_capped_at_size() {
+        let map_pool = MapPool::create(2).with_max_maps(5);
+        let (done_sender, done) = bounded(1);
+        std::thread::spawn(move || {
+            for name in ["first", "second", "third"] {
+                map_pool
+                    .acquire_or_create(format!("MAP NAME '{}' END", name))
+                    .unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            done_sender.send(map_pool.live_map_count()).unwrap();
+        });
+
+        // The third map evicts the first rather than waiting on a busy thread
+        assert_eq!(done.recv_timeout(Duration::from_secs(10)), Ok(2));
+    }
+
+    #[test]
+    fn test_mapfile_key() {
+        let map_pool = MapPool::create(4);
+        let a = map_pool
+            .acquire_or_create("MAP NAME 'a' END".to_string())
+            .unwrap();
+        let a2 = map_pool
+            .acquire_or_create("MAP NAME 'a' END".to_string())
+            .unwrap();
+        let b = map_pool
+            .acquire_or_create("MAP NAME 'b' END".to_string())
+            .unwrap();
+
+        assert!(a.request_sender.same_channel(&a2.request_sender));
+        assert!(!a.request_sender.same_channel(&b.request_sender));
+        assert_eq!(map_pool.lookup.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_deferred_cleanup() {
+        let state = Arc::new(Mutex::new(RenderState::default()));
+        let guard = InFlightGuard::new(state.clone());
+        assert_eq!(state.lock().unwrap().in_flight, 1);
+
+        // The GC thread found the lookup empty while a render was in flight
+        state.lock().unwrap().cleanup_pending = true;
+
+        // Finishing the last render performs the cleanup
+        drop(guard);
+        let state = state.lock().unwrap();
+        assert_eq!(state.in_flight, 0);
+        assert!(!state.cleanup_pending);
+    }
+
+    #[test]
+    fn test_concurrent_short_lived_maps() {
+        let map_pool = Arc::new(Mutex::new(MapPool::with_timeout(
+            8,
+            Duration::from_millis(50),
+        )));
+        for round in 0..4 {
+            let handles: Vec<_> = (0..16)
+                .map(|i| {
+                    let map_pool = map_pool.clone();
+                    std::thread::spawn(move || {
+                        let mapfile_str = format!("MAP NAME 'map{}' END", (round + i) % 6);
+                        let mapthread = map_pool
+                            .lock()
+                            .unwrap()
+                            .acquire_or_create(mapfile_str)
+                            .unwrap();
+                        mapthread.render(Extent(0., 0., 1., 1.))
+                    })
+                })
+                .collect();
+            for handle in handles {
+                assert!(handle.join().unwrap().is_ok());
+            }
+            // Let every map expire so the lookup table empties between rounds
+            std::thread::sleep(Duration::from_millis(150));
+        }
+
+        let map_pool = map_pool.lock().unwrap();
+        assert!(map_pool.lookup.lock().unwrap().is_empty());
+        let state = map_pool.render_state.lock().unwrap();
+        assert_eq!(state.in_flight, 0);
+        assert!(!state.cleanup_pending);
+    }
+
+    #[test]
+    fn test_map_load_error() {
+        let result = Map::try_from("MAP LAYER NOT A MAPFILE".to_string());
+        assert!(matches!(result, Err(MapError::LoadFailed(_))));
+
+        let result = Map::try_from("MAP\0END".to_string());
+        assert!(matches!(result, Err(MapError::LoadFailed(_))));
+    }
+
+    #[test]
+    fn test_map_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Map>();
+    }
+
+    #[test]
+    fn test_query_point() {
+        let map = Map::from(
+            "MAP EXTENT 0 0 100 100 SIZE 256 256
+              LAYER NAME 'peaks' TYPE POINT STATUS ON TEMPLATE 'query'
+                PROCESSING 'ITEMS=name,kind'
+                FEATURE POINTS 50 50 END ITEMS 'Lookout;peak' END
+                CLASS STYLE SIZE 8 END END
+              END
+            END"
+            .to_string(),
+        );
+        let extent = Extent(0., 0., 100., 100.);
+
+        let features = map.query_point(extent, 128, 128).unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].get("name").map(String::as_str), Some("Lookout"));
+        assert_eq!(features[0].get("kind").map(String::as_str), Some("peak"));
+
+        // Nothing in the top-left corner
+        assert!(map.query_point(extent, 0, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_render_error_message() {
+        let map = Map::from(
+            "MAP LAYER NAME 'missing' TYPE POLYGON STATUS ON DATA '/nonexistent/missing.shp' END END"
+                .to_string(),
+        );
+        let extent = Extent(
+            -11711375.725741565,
+            4940736.634297222,
+            -11711222.851684995,
+            4940889.508353792,
+        );
+        match map.draw(extent) {
+            Err(RenderError::DrawFailed(message)) => assert!(!message.is_empty()),
+            other => panic!("expected DrawFailed, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_outside_extent() {
+        let declared = Extent(0., 0., 100., 100.);
+        assert!(outside_extent(&declared, &Extent(200., 200., 300., 300.)));
+        assert!(!outside_extent(&declared, &Extent(50., 50., 150., 150.)));
+        assert!(!outside_extent(&declared, &Extent(10., 10., 20., 20.)));
+        // Touching the edge is inside
+        assert!(!outside_extent(&declared, &Extent(100., 0., 200., 100.)));
+
+        // Without an EXTENT, or for a degenerate request, there is nothing to skip
+        let undeclared = Extent(0., 0., 0., 0.);
+        assert!(!outside_extent(
+            &undeclared,
+            &Extent(200., 200., 300., 300.)
+        ));
+        assert!(!outside_extent(&declared, &Extent(200., 200., 200., 300.)));
+    }
+
+    #[test]
+    fn test_draw_outside_extent() {
+        let map = Map::from("MAP EXTENT 0 0 100 100 SIZE 256 256 END".to_string());
+        let empty = map.draw(Extent(200., 200., 300., 300.)).unwrap();
+        assert!(!empty.bytes.is_empty());
+        assert_eq!(empty.content_type, "image/png");
+        assert!(empty.empty);
+
+        // Partly or fully inside the EXTENT is drawn
+        assert!(!map.draw(Extent(50., 50., 150., 150.)).unwrap().empty);
+        assert!(!map.draw(Extent(10., 10., 20., 20.)).unwrap().empty);
+    }
+
+    #[test]
+    fn test_blank_tile() {
+        let png = &BLANK_TILE_PNG;
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR: 256x256, 1-bit palette
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..26], &[0, 0, 1, 0, 0, 0, 1, 0, 1, 3]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        let image = RenderedImage::encoded(Vec::new(), "image/jpeg".to_string());
+        assert_eq!(image, RenderedImage::blank_tile());
+        assert_eq!(image.content_type, "image/png");
+        assert!(image.empty);
+
+        let image = RenderedImage::encoded(vec![0xFF, 0xD8], "image/jpeg".to_string());
+        assert_eq!(image.bytes, [0xFF, 0xD8]);
+        assert_eq!(image.content_type, "image/jpeg");
+        assert!(!image.empty);
+    }
+
+    #[test]
+    fn test_draw_encode_error() {
+        let map = Map::from("MAP OUTPUTFORMAT NAME 'broken' END END".to_string());
+        let ext = Extent(0., 0., 100., 100.);
+        match map.draw_as(ext, OutputFormat::Named("broken".to_string())) {
+            Err(RenderError::Encode(message)) => assert!(!message.is_empty()),
+            other => panic!("expected Encode, got {:?}", other.map(|_| ())),
+        }
+        // The map is still usable afterwards
+        assert!(map.draw(ext).is_ok());
+    }
+
+    #[test]
+    fn test_mappool_load_error() {
+        let map_pool = MapPool::create(2);
+        let result = map_pool.acquire_or_create("MAP LAYER NOT A MAPFILE".to_string());
+        assert!(matches!(result, Err(MapError::LoadFailed(_))));
+        assert!(map_pool.lookup.lock().unwrap().is_empty());
+
+        // A valid map can still be acquired afterwards
+        assert!(map_pool.acquire_or_create("MAP END".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_render_error_keeps_thread_alive() {
+        let map_pool = MapPool::create(2);
+        let mapthread = map_pool.acquire_or_create("MAP END".to_string()).unwrap();
+
+        let bad_extent = Extent(f64::NAN, 0., 1., 1.);
+        assert!(mapthread.render(bad_extent).is_err());
+
+        let extent = Extent(
+            -11711375.725741565,
+            4940736.634297222,
+            -11711222.851684995,
+            4940889.508353792,
+        );
+        assert!(mapthread.render(extent).is_ok());
+    }
+
+    #[test]
+    fn test_draw_in_crs() {
+        let map = Map::from(
+            "MAP PROJECTION 'init=epsg:3857' END EXTENT 0 0 100 100 SIZE 256 256 END".to_string(),
+        );
+        // Outside the mapfile EXTENT in epsg:3857 units, but drawn in degrees
+        let image = map
+            .draw_in_crs(Extent(-105.5, 39.5, -105., 40.), 4326)
+            .unwrap();
+        assert_eq!(&image.bytes[..4], b"\x89PNG");
+        assert_eq!(image.content_type, "image/png");
+        assert_eq!(map.handle.projection(), "init=epsg:3857");
+
+        assert!(matches!(
+            map.draw_in_crs(Extent(0., 0., 1., 1.), 999999),
+            Err(RenderError::InvalidProjection(_))
+        ));
+        assert_eq!(map.handle.projection(), "init=epsg:3857");
+
+        // A map without a PROJECTION is left without one
+        let map = Map::from("MAP EXTENT 0 0 100 100 SIZE 256 256 END".to_string());
+        assert!(map
+            .draw_in_crs(Extent(-105.5, 39.5, -105., 40.), 4326)
+            .is_ok());
+        assert_eq!(map.handle.projection(), "");
+    }
+
+    #[test]
+    fn test_draw_mvt() {
+        let map = Map::from(
+            "MAP OUTPUTFORMAT NAME 'mvt' DRIVER MVT END \
+             LAYER NAME 'roads' TYPE LINE END \
+             LAYER NAME 'imagery' TYPE RASTER STATUS OFF END END"
+                .to_string(),
+        );
+        let ext = Extent(0., 0., 100., 100.);
+        let tile = map.draw_mvt(ext).unwrap();
+        assert_eq!(tile.content_type, MVT_CONTENT_TYPE);
+        assert!(!tile.bytes.is_empty());
+        // Either gzipped, or a raw protobuf starting with a layers field
+        assert!(tile.bytes.starts_with(&[0x1f, 0x8b]) || tile.bytes[0] == 0x1a);
+
+        // Through the pool's render options as well
+        let options = RenderOptions {
+            format: Some(OutputFormat::Mvt),
+            ..Default::default()
+        };
+        assert_eq!(map.draw_with_options(ext, &options).unwrap(), tile);
+
+        // The map's own format is left in place
+        assert_eq!(map.content_type(), "image/png");
+        assert!(map.draw(ext).is_ok());
+    }
+
+    #[test]
+    fn test_draw_mvt_errors() {
+        let ext = Extent(0., 0., 100., 100.);
+        let map = Map::from("MAP LAYER NAME 'roads' TYPE LINE END END".to_string());
+        assert_eq!(
+            map.draw_mvt(ext),
+            Err(RenderError::UnknownFormat("mvt".to_string()))
+        );
+
+        let map = Map::from(
+            "MAP OUTPUTFORMAT NAME 'mvt' DRIVER MVT END LAYER NAME 'imagery' TYPE RASTER END END"
+                .to_string(),
+        );
+        assert_eq!(
+            map.draw_mvt(ext),
+            Err(RenderError::NotVector("imagery".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_draw_geojson() {
+        let map = Map::from(
+            "MAP EXTENT 0 0 100 100 SIZE 256 256
+              OUTPUTFORMAT NAME 'geojson' DRIVER 'OGR/GEOJSON' END
+              LAYER NAME 'peaks' TYPE POINT STATUS ON TEMPLATE 'query'
+                PROCESSING 'ITEMS=name'
+                FEATURE POINTS 50 50 END ITEMS 'Lookout' END
+              END
+            END"
+            .to_string(),
+        );
+        let features = map.draw_geojson(Extent(0., 0., 100., 100.)).unwrap();
+        assert_eq!(features.content_type, GEOJSON_CONTENT_TYPE);
+        let json: serde_json::Value = serde_json::from_slice(&features.bytes).unwrap();
+        assert_eq!(json["type"], "FeatureCollection");
+        assert_eq!(json["features"][0]["properties"]["name"], "Lookout");
+
+        // No features, still a FeatureCollection
+        let empty = map.draw_geojson(Extent(60., 60., 100., 100.)).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&empty.bytes).unwrap();
+        assert_eq!(json["type"], "FeatureCollection");
+        assert_eq!(json["features"].as_array().map(Vec::len), Some(0));
+
+        let map = Map::from("MAP LAYER NAME 'peaks' TYPE POINT END END".to_string());
+        assert_eq!(
+            map.draw_geojson(Extent(0., 0., 100., 100.)),
+            Err(RenderError::UnknownFormat("geojson".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_active_layers() {
+        let map = Map::from(
+            "MAP LAYER NAME 'roads' TYPE LINE STATUS ON
+               FEATURE POINTS 10 10 90 90 END END
+               CLASS STYLE COLOR 255 0 0 WIDTH 4 END END
+             END
+             LAYER NAME 'rivers' TYPE LINE STATUS ON
+               FEATURE POINTS 10 90 90 10 END END
+               CLASS STYLE COLOR 0 0 255 WIDTH 4 END END
+             END END"
+                .to_string(),
+        );
+        let ext = Extent(0., 0., 100., 100.);
+        let layers = |names: &[&str]| RenderOptions {
+            layers: Some(names.iter().map(|name| name.to_string()).collect()),
+            ..Default::default()
+        };
+        let both = map.draw(ext).unwrap();
+        let roads = map.draw_with_options(ext, &layers(&["roads"])).unwrap();
+        let rivers = map.draw_with_options(ext, &layers(&["rivers"])).unwrap();
+        assert_ne!(roads, rivers);
+        assert_ne!(roads, both);
+
+        // Every layer is back on for the next draw
+        assert_eq!(map.draw(ext).unwrap(), both);
+
+        assert_eq!(
+            map.draw_with_options(ext, &layers(&["roads", "lakes"])),
+            Err(RenderError::UnknownLayer("lakes".to_string()))
+        );
+        assert_eq!(map.draw(ext).unwrap(), both);
+    }
+
+    #[test]
+    fn test_styles() {
+        let map = Map::from(
+            "MAP LAYER NAME 'roads' TYPE LINE STATUS ON CLASSGROUP 'night'
+               FEATURE POINTS 10 10 90 90 END END
+               CLASS GROUP 'day' STYLE COLOR 0 0 0 WIDTH 4 END END
+               CLASS GROUP 'night' STYLE COLOR 255 255 255 WIDTH 4 END END
+             END END"
+                .to_string(),
+        );
+        assert_eq!(map.styles(), vec!["day".to_string(), "night".to_string()]);
+
+        let ext = Extent(0., 0., 100., 100.);
+        let style = |name: &str| RenderOptions {
+            style: Some(name.to_string()),
+            ..Default::default()
+        };
+        let default = map.draw(ext).unwrap();
+        let day = map.draw_with_options(ext, &style("day")).unwrap();
+        let night = map.draw_with_options(ext, &style("night")).unwrap();
+        assert_ne!(day, night);
+        // The mapfile's own CLASSGROUP
+        assert_eq!(night, default);
+
+        // The same worker draws its mapfile's classes again afterwards
+        assert_eq!(map.draw(ext).unwrap(), default);
+        assert_eq!(map.draw_with_options(ext, &style("day")).unwrap(), day);
+
+        assert_eq!(
+            map.draw_with_options(ext, &style("dusk")),
+            Err(RenderError::UnknownStyle("dusk".to_string()))
+        );
+        assert_eq!(map.draw(ext).unwrap(), default);
+    }
+
+    #[test]
+    fn test_check_layers() {
+        let map = Map::from(
+            "MAP LAYER NAME 'roads' TYPE LINE FEATURE POINTS 0 0 100 100 END END END \
+             LAYER NAME 'missing' TYPE POLYGON DATA '/nonexistent/missing.shp' END END"
+                .to_string(),
+        );
+        let checks = map.check_layers();
+
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[0], ("roads".to_string(), Ok(())));
+        assert_eq!(checks[1].0, "missing");
+        assert!(checks[1]
+            .1
+            .as_ref()
+            .unwrap_err()
+            .contains("/nonexistent/missing.shp"));
+    }
+
+    #[test]
+    fn test_map_with_path() {
+        let mappath = std::env::temp_dir().join(format!("mappath-{}", std::process::id()));
+        std::fs::create_dir_all(&mappath).unwrap();
+        std::fs::write(
+            mappath.join("roads.inc"),
+            "LAYER NAME 'roads' TYPE LINE DATA 'roads.shp' END",
+        )
+        .unwrap();
+        std::fs::write(mappath.join("roads.shp"), b"").unwrap();
+
+        // The include and the relative DATA both resolve against the mappath
+        let mapfile = "MAP INCLUDE 'roads.inc' END".to_string();
+        let checks =
+            Map::try_from_with_path(mapfile.clone(), &mappath).map(|map| map.check_layers());
+        let without_path = Map::try_from(mapfile);
+        std::fs::remove_dir_all(&mappath).unwrap();
+
+        assert_eq!(checks, Ok(vec![("roads".to_string(), Ok(()))]));
+        assert!(matches!(without_path, Err(MapError::LoadFailed(_))));
+    }
+
+    #[test]
+    fn test_resample() {
+        let map = Map::from(
+            "MAP LAYER NAME 'imagery' TYPE RASTER PROCESSING 'RESAMPLE=AVERAGE' END \
+             LAYER NAME 'hillshade' TYPE RASTER END \
+             LAYER NAME 'roads' TYPE LINE END END"
+                .to_string(),
+        );
+        let ext = Extent(0., 0., 100., 100.);
+        let resample = |resample| RenderOptions {
+            resample: Some(resample),
+            ..Default::default()
+        };
+        let own = map.handle.layer_processing("RESAMPLE");
+        assert_eq!(own, vec![Some("AVERAGE".to_string()), None, None]);
+
+        // Each layer's own RESAMPLE is restored after every draw, or removed if it had none
+        for option in [Resample::Nearest, Resample::Bilinear, Resample::Average] {
+            assert!(map.draw_with_options(ext, &resample(option)).is_ok());
+            assert_eq!(map.handle.layer_processing("RESAMPLE"), own);
+        }
+    }
+
+    #[test]
+    fn test_draw_as() {
+        let map = Map::from("MAP OUTPUTFORMAT NAME 'jpeg' DRIVER 'AGG/JPEG' END END".to_string());
+        let extent = || {
+            Extent(
+                -11711375.725741565,
+                4940736.634297222,
+                -11711222.851684995,
+                4940889.508353792,
+            )
+        };
+
+        let png = map.draw_as(extent(), OutputFormat::Png).unwrap();
+        assert_eq!(&png.bytes[..4], &[0x89, b'P', b'N', b'G']);
+        assert_eq!(png.content_type, "image/png");
+
+        let jpeg = map.draw_as(extent(), OutputFormat::Jpeg).unwrap();
+        assert_eq!(&jpeg.bytes[..3], &[0xFF, 0xD8, 0xFF]);
+        assert_eq!(jpeg.content_type, "image/jpeg");
+
+        let missing = map.draw_as(extent(), OutputFormat::Named("nonexistent".to_string()));
+        assert_eq!(
+            missing.err(),
+            Some(RenderError::UnknownFormat("nonexistent".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_available_formats() {
+        let map = Map::from(
+            "MAP
+              OUTPUTFORMAT NAME 'png' DRIVER 'AGG/PNG' MIMETYPE 'image/png' END
+              OUTPUTFORMAT NAME 'jpeg' DRIVER 'AGG/JPEG' MIMETYPE 'image/jpeg' END
+            END"
+            .to_string(),
+        );
+        let formats = map.available_formats();
+        assert!(formats.contains(&"png".to_string()));
+        assert!(formats.contains(&"jpeg".to_string()));
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        let map = Map::from(
+            "MAP OUTPUTFORMAT NAME 'webp' DRIVER 'AGG/WEBP' MIMETYPE 'image/webp' END END"
+                .to_string(),
+        );
+        let accept = |accept| RenderOptions {
+            accept: OutputFormat::accepted(accept),
+            ..Default::default()
+        };
+        let ext = Extent(0., 0., 1., 1.);
+
+        assert_eq!(
+            map.negotiate_format(&OutputFormat::accepted("image/webp,image/*")),
+            Some(OutputFormat::Webp)
+        );
+        let img = map.draw_with_options(ext, &accept("image/webp")).unwrap();
+        assert_eq!(img.content_type, "image/webp");
+
+        // JPEG is not defined, so the default PNG
+        assert_eq!(map.negotiate_format(&[OutputFormat::Jpeg]), None);
+        let img = map.draw_with_options(ext, &accept("image/jpeg")).unwrap();
+        assert_eq!(img.content_type, "image/png");
+        let img = map
+            .draw_with_options(ext, &accept("image/webp;q=0.5, image/png"))
+            .unwrap();
+        assert_eq!(img.content_type, "image/png");
+    }
+
+    #[test]
+    fn test_draw_content_type() {
+        let map = Map::from("MAP END".to_string());
+        let img = map.draw(Extent(0., 0., 1., 1.)).unwrap();
+        assert_eq!(img.content_type, "image/png");
+    }
+
+    #[test]
+    fn test_render_tile() {
+        let tile = Tile::from_zxy(7, 26, 48);
+        let png = render_tile("MAP SIZE 256 256 END", &tile).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[16..20], &TILE_SIZE.to_be_bytes());
+
+        // The same tile as a map drawing the tile's extent
+        let map = Map::from("MAP SIZE 256 256 END".to_string());
+        let drawn = map.draw(Extent::from(tile.bbox_mercator())).unwrap();
+        assert_eq!(png, drawn.bytes);
+
+        match render_tile("NOT A MAPFILE", &tile) {
+            Err(RenderError::DrawFailed(message)) => assert!(!message.is_empty()),
+            other => panic!("expected DrawFailed, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_transparency() {
+        let map = Map::from("MAP IMAGECOLOR 255 255 255 END".to_string());
+        let ext = Extent(0., 0., 100., 100.);
+        let alpha = |transparent| {
+            map.handle.with_transparency(transparent, || {
+                let img = map.draw_image(ext).unwrap();
+                img.rgba_pixels().unwrap().pixel(0, 0).unwrap()[3]
+            })
+        };
+        assert_eq!(alpha(true), 0);
+        assert_eq!(alpha(false), 255);
+
+        // The mapfile's opaque format is restored after a transparent draw
+        alpha(true);
+        let img = map.draw_image(ext).unwrap();
+        assert_eq!(img.rgba_pixels().unwrap().pixel(0, 0).unwrap()[3], 255);
+        assert!(img.rgba_pixels().unwrap().pixel(0, 100_000).is_none());
+
+        // Through the render options
+        let options = RenderOptions {
+            transparent: Some(true),
+            ..Default::default()
+        };
+        assert!(map.draw_with_options(ext, &options).is_ok());
+        let img = map.draw_image(ext).unwrap();
+        assert_eq!(img.rgba_pixels().unwrap().pixel(0, 0).unwrap()[3], 255);
+    }
+
+    #[test]
+    fn test_draw_sized() {
+        let map = Map::from("MAP SIZE 256 256 END".to_string());
+        let img = map.draw_sized(Extent(0., 0., 1., 1.), 512, 512).unwrap();
+
+        // PNG IHDR stores the width and height as big-endian u32s after the signature
+        assert_eq!(&img.bytes[16..20], &512u32.to_be_bytes());
+        assert_eq!(&img.bytes[20..24], &512u32.to_be_bytes());
+
+        // The mapfile size is used again for plain draws
+        let img = map.draw(Extent(0., 0., 1., 1.)).unwrap();
+        assert_eq!(&img.bytes[16..20], &256u32.to_be_bytes());
+
+        for (width, height) in [(0, 256), (256, -1), (MAX_IMAGE_SIZE + 1, 256)] {
+            assert_eq!(
+                map.draw_sized(Extent(0., 0., 1., 1.), width, height).err(),
+                Some(RenderError::InvalidSize { width, height })
+            );
+        }
+    }
+
+    #[test]
+    fn test_draw_metatile() {
+        let map = Map::from("MAP SIZE 256 256 END".to_string());
+        let tile = crate::coordinates::Tile::from_zxy(7, 26, 48);
+        let metatile = map
+            .draw_metatile(Extent::from(tile.metatile_extent(2)), 2)
+            .unwrap();
+
+        let tiles = map.slice_metatile(&metatile).unwrap();
+        assert_eq!(tiles.len(), 4);
+        for (i, a) in tiles.iter().enumerate() {
+            assert_eq!(&a.bytes[16..20], &TILE_SIZE.to_be_bytes());
+            for b in &tiles[i + 1..] {
+                assert_ne!(a.bytes, b.bytes);
+            }
+        }
+
+        // Larger than MAX_IMAGE_SIZE
+        assert!(matches!(
+            map.draw_metatile(Extent(0., 0., 1., 1.), 32),
+            Err(RenderError::InvalidSize { .. })
+        ));
+    }
+
+    #[test]
+    fn test_map_extents() {
+        let map = Map::from(
+            "MAP EXTENT 0 0 100 100 LAYER NAME 'a' EXTENT 10 10 20 20 END END".to_string(),
+        );
+        assert_eq!(map.extent(), Extent(0., 0., 100., 100.));
+        assert_eq!(map.content_type(), "image/png");
+
+        // Drawing elsewhere does not change the declared extent
+        map.draw(Extent(200., 200., 300., 300.)).unwrap();
+        assert_eq!(map.extent(), Extent(0., 0., 100., 100.));
+
+        let layer_extent = map.layer_extent().unwrap();
+        assert!(layer_extent.width() > 0. && layer_extent.height() > 0.);
+
+        assert!(Map::from("MAP END".to_string()).layer_extent().is_none());
+    }
+
+    /// The (width, height) in a JPEG's SOF0 segment
+    fn jpeg_size(bytes: &[u8]) -> Option<(u32, u32)> {
+        let be16 = |at: usize| Some(u16::from_be_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]));
+        // Segments follow the SOI marker, each a 0xFF marker and a length including itself
+        let mut at = 2;
+        while bytes.get(at) == Some(&0xFF) {
+            if bytes.get(at + 1) == Some(&0xC0) {
+                return Some((be16(at + 7)?.into(), be16(at + 5)?.into()));
+            }
+            at += 2 + usize::from(be16(at + 2)?);
+        }
+        None
+    }
+
+    #[test]
+    fn test_render_with_options() {
+        let map_pool = MapPool::create(2);
+        let mapthread = map_pool
+            .acquire_or_create("MAP OUTPUTFORMAT NAME 'jpeg' DRIVER 'AGG/JPEG' END END".to_string())
+            .unwrap();
+
+        let options = RenderOptions {
+            size: Some((300, 200)),
+            format: Some(OutputFormat::Named("image/jpeg".to_string())),
+            ..Default::default()
+        };
+        let img = mapthread
+            .render_with_options(Extent(0., 0., 3., 2.), options)
+            .unwrap();
+        assert_eq!(img.content_type, "image/jpeg");
+        assert_eq!(jpeg_size(&img.bytes), Some((300, 200)));
+
+        // Drawn in degrees, then back in the mapfile's projection
+        let options = RenderOptions {
+            size: Some((512, 256)),
+            epsg: Some(4326),
+            ..Default::default()
+        };
+        let img = mapthread
+            .render_with_options(Extent(-106., 38., -104., 40.), options)
+            .unwrap();
+        assert_eq!(&img.bytes[16..20], &512u32.to_be_bytes());
+
+        // Overrides do not leak into later default renders
+        let img = mapthread.render(Extent(0., 0., 1., 1.)).unwrap();
+        assert_eq!(img.content_type, "image/png");
+        assert_eq!(&img.bytes[16..20], &256u32.to_be_bytes());
     }
 }
@@ -0,0 +1,184 @@
+//! Seeded tiles in an MBTiles archive, a single SQLite file following the
+//! [MBTiles 1.3 spec](https://github.com/mapbox/mbtiles-spec/blob/master/1.3/spec.md).
+//!
+//! Rows are stored in the TMS scheme, so the y of a `Tile` is flipped on the way in.
+
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::coordinates::{Point, Tile};
+use super::seed::{SeedError, SeedOptions, TileStore};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS metadata (name TEXT, value TEXT);
+    CREATE UNIQUE INDEX IF NOT EXISTS name ON metadata (name);
+    CREATE TABLE IF NOT EXISTS tiles (
+        zoom_level INTEGER,
+        tile_column INTEGER,
+        tile_row INTEGER,
+        tile_data BLOB
+    );
+    CREATE UNIQUE INDEX IF NOT EXISTS tile_index ON tiles (zoom_level, tile_column, tile_row);
+";
+
+///
+/// Writes PNG tiles into an MBTiles file, creating the schema if needed.
+/// Writes are batched into one transaction per metatile
+///
+#[derive(Debug)]
+pub struct MbtilesWriter {
+    path: PathBuf,
+    connection: Connection,
+}
+
+impl MbtilesWriter {
+    /// Open or create an MBTiles file. Tiles from an earlier seed are kept
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, SeedError> {
+        let path = path.as_ref().to_path_buf();
+        let connection = Connection::open(&path).map_err(|err| sqlite_error(&path, err))?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|err| sqlite_error(&path, err))?;
+        Ok(MbtilesWriter { path, connection })
+    }
+
+    /// Record the name, format, bounds and zoom range of a seed
+    pub fn write_metadata(&self, name: &str, options: &SeedOptions) -> Result<(), SeedError> {
+        let extent = options.extent;
+        let southwest = Point::new(extent.minx(), extent.miny()).to_wgs84();
+        let northeast = Point::new(extent.maxx(), extent.maxy()).to_wgs84();
+        let bounds = format!(
+            "{},{},{},{}",
+            southwest.x, southwest.y, northeast.x, northeast.y
+        );
+        let metadata = [
+            ("name", name.to_string()),
+            ("format", "png".to_string()),
+            ("bounds", bounds),
+            ("minzoom", options.min_zoom.to_string()),
+            ("maxzoom", options.max_zoom.to_string()),
+        ];
+        for (key, value) in metadata {
+            self.connection
+                .execute(
+                    "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                    params![key, value],
+                )
+                .map_err(|err| sqlite_error(&self.path, err))?;
+        }
+        Ok(())
+    }
+}
+
+impl TileStore for MbtilesWriter {
+    fn contains(&self, tile: &Tile) -> Result<bool, SeedError> {
+        let tms = tile.to_tms();
+        self.connection
+            .query_row(
+                "SELECT 1 FROM tiles WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![tms.zoom, tms.x, tms.y],
+                |_| Ok(()),
+            )
+            .optional()
+            .map(|row| row.is_some())
+            .map_err(|err| sqlite_error(&self.path, err))
+    }
+
+    fn write(&mut self, tile: &Tile, bytes: &[u8]) -> Result<(), SeedError> {
+        // Open a transaction with the first tile of each batch, `flush` commits it
+        if self.connection.is_autocommit() {
+            self.connection
+                .execute_batch("BEGIN")
+                .map_err(|err| sqlite_error(&self.path, err))?;
+        }
+        let tms = tile.to_tms();
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![tms.zoom, tms.x, tms.y, bytes],
+            )
+            .map_err(|err| sqlite_error(&self.path, err))?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), SeedError> {
+        if !self.connection.is_autocommit() {
+            self.connection
+                .execute_batch("COMMIT")
+                .map_err(|err| sqlite_error(&self.path, err))?;
+        }
+        Ok(())
+    }
+}
+
+fn sqlite_error(path: &Path, err: rusqlite::Error) -> SeedError {
+    SeedError::Io {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::seed::seed;
+    use crate::template::MapfileTemplate;
+    use crate::Extent;
+
+    #[test]
+    fn test_seed_mbtiles() {
+        let path = std::env::temp_dir().join(format!("seed-{}.mbtiles", std::process::id()));
+        let options = SeedOptions {
+            extent: Extent::new(100., 100., 200., 200.),
+            min_zoom: 1,
+            max_zoom: 2,
+            timestamp: 0,
+        };
+        let template = MapfileTemplate::new("MAP NAME '{timestamp}' END".to_string());
+
+        let mut writer = MbtilesWriter::create(&path).unwrap();
+        writer.write_metadata("test", &options).unwrap();
+        let seeded = seed(&template, &options, &mut writer, |_| {}).unwrap();
+        assert_eq!(seeded.rendered, 2);
+        drop(writer);
+
+        // Read back with plain SQL, rows are in the TMS scheme
+        let connection = Connection::open(&path).unwrap();
+        let count: i64 = connection
+            .query_row("SELECT COUNT(*) FROM tiles", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        let tile_data: Vec<u8> = connection
+            .query_row(
+                "SELECT tile_data FROM tiles
+                 WHERE zoom_level = 2 AND tile_column = 2 AND tile_row = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!tile_data.is_empty());
+
+        let metadata = |name: &str| -> String {
+            connection
+                .query_row(
+                    "SELECT value FROM metadata WHERE name = ?1",
+                    params![name],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(metadata("format"), "png");
+        assert_eq!(metadata("minzoom"), "1");
+        assert_eq!(metadata("maxzoom"), "2");
+        assert_eq!(metadata("bounds").split(',').count(), 4);
+
+        // Seeding again finds the tiles already in the archive
+        let mut writer = MbtilesWriter::create(&path).unwrap();
+        let seeded = seed(&template, &options, &mut writer, |_| {}).unwrap();
+        assert_eq!((seeded.rendered, seeded.skipped), (0, 2));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
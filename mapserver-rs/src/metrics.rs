@@ -0,0 +1,180 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the render duration histogram buckets
+const RENDER_DURATION_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.];
+
+///
+/// A cumulative histogram of durations, in the Prometheus style
+///
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    // Observations at or below each bound, not yet cumulative
+    counts: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            counts: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(out, "{}_sum {}", name, sum).unwrap();
+        writeln!(out, "{}_count {}", name, count).unwrap();
+    }
+}
+
+///
+/// Server metrics, exposed in the Prometheus text format.
+/// Safe to update from any thread
+///
+#[derive(Debug)]
+pub struct Metrics {
+    tiles_rendered: AtomicU64,
+    render_errors: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    render_duration: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            tiles_rendered: AtomicU64::new(0),
+            render_errors: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            render_duration: Histogram::new(&RENDER_DURATION_BUCKETS),
+        }
+    }
+
+    /// Record a completed render, successful or not, and how long it took
+    pub fn record_render(&self, duration: Duration, success: bool) {
+        if success {
+            self.tiles_rendered.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.render_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.render_duration.observe(duration);
+    }
+
+    /// Record a tile cache lookup
+    pub fn record_cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the Prometheus text exposition format,
+    /// along with the current number of live maps in the pool
+    pub fn to_prometheus(&self, live_maps: usize) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "mapserver_tiles_rendered_total",
+                "Tiles rendered successfully",
+                &self.tiles_rendered,
+            ),
+            (
+                "mapserver_render_errors_total",
+                "Renders that failed",
+                &self.render_errors,
+            ),
+            (
+                "mapserver_tile_cache_hits_total",
+                "Tiles served from the tile cache",
+                &self.cache_hits,
+            ),
+            (
+                "mapserver_tile_cache_misses_total",
+                "Tiles not found in the tile cache",
+                &self.cache_misses,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        let name = "mapserver_render_duration_seconds";
+        writeln!(out, "# HELP {} Time to acquire a map and render", name).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        self.render_duration.write(&mut out, name);
+
+        let name = "mapserver_live_maps";
+        writeln!(out, "# HELP {} Maps loaded in the pool", name).unwrap();
+        writeln!(out, "# TYPE {} gauge", name).unwrap();
+        writeln!(out, "{} {}", name, live_maps).unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.record_render(Duration::from_millis(20), true);
+        metrics.record_render(Duration::from_millis(300), true);
+        metrics.record_render(Duration::from_secs(60), false);
+        metrics.record_cache(true);
+        metrics.record_cache(false);
+        metrics.record_cache(false);
+
+        let text = metrics.to_prometheus(3);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"mapserver_tiles_rendered_total 2"));
+        assert!(lines.contains(&"mapserver_render_errors_total 1"));
+        assert!(lines.contains(&"mapserver_tile_cache_hits_total 1"));
+        assert!(lines.contains(&"mapserver_tile_cache_misses_total 2"));
+        assert!(lines.contains(&"mapserver_live_maps 3"));
+
+        // Buckets are cumulative, the 60s render only lands in +Inf
+        assert!(lines.contains(&"mapserver_render_duration_seconds_bucket{le=\"0.01\"} 0"));
+        assert!(lines.contains(&"mapserver_render_duration_seconds_bucket{le=\"0.025\"} 1"));
+        assert!(lines.contains(&"mapserver_render_duration_seconds_bucket{le=\"0.5\"} 2"));
+        assert!(lines.contains(&"mapserver_render_duration_seconds_bucket{le=\"10\"} 2"));
+        assert!(lines.contains(&"mapserver_render_duration_seconds_bucket{le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"mapserver_render_duration_seconds_count 3"));
+        assert!(lines.contains(&"mapserver_render_duration_seconds_sum 60.32"));
+    }
+}
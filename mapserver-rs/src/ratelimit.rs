@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients tracked at once. Buckets that have refilled are dropped to make room, beyond
+/// that new clients share a single overflow bucket
+pub const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// How often a full limiter may look for refilled buckets to drop
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+///
+/// The tokens left to a client as of the last time it was seen
+///
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+///
+/// A token bucket per client IP. Each client may make `burst` requests at once,
+/// refilled at `per_second` requests a second. Safe to share between threads
+///
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    // Shared by the clients that arrive while `clients` is full
+    overflow: Bucket,
+    swept: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            per_second,
+            burst,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                overflow: Bucket {
+                    tokens: burst,
+                    updated: Instant::now(),
+                },
+                swept: None,
+            }),
+        }
+    }
+
+    /// Take a token for a request from the client, or the time until the next one is available
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = &mut *buckets;
+        let full = buckets.clients.len() >= MAX_TRACKED_CLIENTS;
+        if full && !buckets.clients.contains_key(&client) {
+            // A scan per new client would let many addresses make every request slow
+            let recent = matches!(buckets.swept,
+                Some(swept) if now.saturating_duration_since(swept) < SWEEP_INTERVAL);
+            if !recent {
+                let (per_second, burst) = (self.per_second, self.burst);
+                buckets
+                    .clients
+                    .retain(|_, bucket| refill(bucket, now, per_second, burst) < burst);
+                buckets.swept = Some(now);
+            }
+        }
+
+        let bucket = if buckets.clients.len() < MAX_TRACKED_CLIENTS {
+            buckets.clients.entry(client).or_insert(Bucket {
+                tokens: self.burst,
+                updated: now,
+            })
+        } else {
+            buckets
+                .clients
+                .get_mut(&client)
+                .unwrap_or(&mut buckets.overflow)
+        };
+        bucket.tokens = refill(bucket, now, self.per_second, self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1. - bucket.tokens) / self.per_second,
+        ))
+    }
+}
+
+/// The tokens in a bucket at `now`, capped at the burst size
+fn refill(bucket: &Bucket, now: Instant, per_second: f64, burst: f64) -> f64 {
+    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+    (bucket.tokens + elapsed * per_second).min(burst)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(2., 3);
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(client, start), Ok(()));
+        }
+        assert_eq!(
+            limiter.check_at(client, start),
+            Err(Duration::from_millis(500))
+        );
+
+        // Other clients have their own bucket
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(limiter.check_at(other, start), Ok(()));
+
+        // One token back after half a second at 2 per second
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(client, later), Ok(()));
+        assert!(limiter.check_at(client, later).is_err());
+
+        // Never more than the burst, however long the client was away
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(client, much_later), Ok(()));
+        }
+        assert!(limiter.check_at(client, much_later).is_err());
+    }
+
+    #[test]
+    fn test_forgets_idle_clients() {
+        let limiter = RateLimiter::new(1., 1);
+        let start = Instant::now();
+        for i in 0..MAX_TRACKED_CLIENTS as u32 {
+            limiter
+                .check_at(IpAddr::from(i.to_be_bytes()), start)
+                .unwrap();
+        }
+        let tracked = || limiter.buckets.lock().unwrap().clients.len();
+        assert_eq!(tracked(), MAX_TRACKED_CLIENTS);
+
+        // Every bucket has refilled, so all but the new client are dropped
+        let later = start + Duration::from_secs(1);
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        limiter.check_at(client, later).unwrap();
+        assert_eq!(tracked(), 1);
+    }
+
+    #[test]
+    fn test_tracked_clients_capped() {
+        let limiter = RateLimiter::new(1., 2);
+        let start = Instant::now();
+        for i in 0..MAX_TRACKED_CLIENTS as u32 {
+            limiter
+                .check_at(IpAddr::from(i.to_be_bytes()), start)
+                .unwrap();
+        }
+
+        // No bucket has refilled yet, so new clients share the overflow bucket
+        let now = start + Duration::from_millis(10);
+        let client = |i: u16| IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, i]);
+        assert_eq!(limiter.check_at(client(1), now), Ok(()));
+        assert_eq!(limiter.check_at(client(2), now), Ok(()));
+        assert!(limiter.check_at(client(3), now).is_err());
+        assert_eq!(
+            limiter.buckets.lock().unwrap().clients.len(),
+            MAX_TRACKED_CLIENTS
+        );
+
+        // Tracked clients keep their own buckets
+        assert_eq!(limiter.check_at(IpAddr::from([0, 0, 0, 1]), now), Ok(()));
+    }
+}
@@ -0,0 +1,211 @@
+//!
+//! Mapfile templates fetched from `https://`, `http://` or `s3://` URLs at startup,
+//! for deployments that keep them in object storage rather than on disk
+//!
+//! ```
+//! use mapserver_rs::remote::{is_remote, object_url};
+//!
+//! assert!(is_remote("s3://maps/naip.map"));
+//! assert!(!is_remote("mapfiles/naip.map"));
+//! assert_eq!(
+//!     object_url("s3://maps/naip.map", None, Some("us-west-2")).unwrap(),
+//!     "https://maps.s3.us-west-2.amazonaws.com/naip.map"
+//! );
+//! ```
+//!
+
+use std::fmt;
+use std::time::Duration;
+
+use hyper::body::HttpBody;
+use hyper::{Body, Client, Uri};
+
+/// How long to wait for a mapfile to download before giving up
+pub const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Larger responses are refused, mapfiles are a few kilobytes
+pub const MAX_MAPFILE_BYTES: usize = 16 * 1024 * 1024;
+
+/// Errors fetching a mapfile template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    /// The URL could not be parsed, or its scheme is not supported
+    InvalidUrl(String),
+    /// The server could not be reached
+    Request(String),
+    /// The server answered with something other than 200 OK
+    Status(u16),
+    /// No complete response within `FETCH_TIMEOUT`
+    Timeout,
+    /// The response was larger than `MAX_MAPFILE_BYTES`
+    TooLarge,
+    /// The response was not UTF-8 text
+    NotUtf8,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchError::InvalidUrl(url) => write!(f, "Invalid mapfile URL '{}'", url),
+            FetchError::Request(message) => write!(f, "Request failed: {}", message),
+            FetchError::Status(status) => write!(f, "Server responded with status {}", status),
+            FetchError::Timeout => write!(f, "No response within {:?}", FETCH_TIMEOUT),
+            FetchError::TooLarge => {
+                write!(f, "Mapfile is larger than {} bytes", MAX_MAPFILE_BYTES)
+            }
+            FetchError::NotUtf8 => write!(f, "Mapfile is not UTF-8 text"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+/// Whether a mapfile source is a URL to fetch rather than a path
+pub fn is_remote(source: &str) -> bool {
+    ["s3://", "https://", "http://"]
+        .iter()
+        .any(|scheme| source.starts_with(scheme))
+}
+
+/// The HTTP URL of a mapfile source. `s3://<bucket>/<key>` is fetched from `endpoint`
+/// as `<endpoint>/<bucket>/<key>` when given, e.g. for MinIO, or else from the bucket's
+/// virtual-hosted AWS endpoint in `region`. Other URLs are fetched as they are
+pub fn object_url(
+    source: &str,
+    endpoint: Option<&str>,
+    region: Option<&str>,
+) -> Result<String, FetchError> {
+    let invalid = || FetchError::InvalidUrl(source.to_string());
+    let object = match source.strip_prefix("s3://") {
+        Some(object) => object,
+        None if is_remote(source) => return Ok(source.to_string()),
+        None => return Err(invalid()),
+    };
+    let (bucket, key) = object.split_once('/').ok_or_else(invalid)?;
+    if bucket.is_empty() || key.is_empty() {
+        return Err(invalid());
+    }
+    Ok(match (endpoint, region) {
+        (Some(endpoint), _) => format!("{}/{}/{}", endpoint.trim_end_matches('/'), bucket, key),
+        (None, Some(region)) => format!("https://{}.s3.{}.amazonaws.com/{}", bucket, region, key),
+        (None, None) => format!("https://{}.s3.amazonaws.com/{}", bucket, key),
+    })
+}
+
+/// Download a mapfile template from a URL, see `object_url`. Requests are not signed,
+/// so objects in S3 must be readable without credentials, e.g. by a bucket policy
+pub async fn fetch_mapfile(
+    source: &str,
+    endpoint: Option<&str>,
+    region: Option<&str>,
+) -> Result<String, FetchError> {
+    let url = object_url(source, endpoint, region)?;
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| FetchError::InvalidUrl(source.to_string()))?;
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_, Body> = Client::builder().build(https);
+
+    let download = async {
+        let response = client
+            .get(uri)
+            .await
+            .map_err(|err| FetchError::Request(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(FetchError::Status(response.status().as_u16()));
+        }
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|err| FetchError::Request(err.to_string()))?;
+            if bytes.len() + chunk.len() > MAX_MAPFILE_BYTES {
+                return Err(FetchError::TooLarge);
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        String::from_utf8(bytes).map_err(|_| FetchError::NotUtf8)
+    };
+    tokio::time::timeout(FETCH_TIMEOUT, download)
+        .await
+        .unwrap_or(Err(FetchError::Timeout))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mappool::Map;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Request, Response, Server, StatusCode};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    /// Serve `/maps/naip.map` from a local server, returning its address
+    fn serve_mapfile(mapfile: &'static str) -> SocketAddr {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+                let response = match request.uri().path() {
+                    "/maps/naip.map" => Response::new(Body::from(mapfile)),
+                    _ => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[test]
+    fn test_object_url() {
+        assert_eq!(
+            object_url("s3://maps/templates/naip.map", None, None),
+            Ok("https://maps.s3.amazonaws.com/templates/naip.map".to_string())
+        );
+        assert_eq!(
+            object_url(
+                "s3://maps/naip.map",
+                Some("http://localhost:9000/"),
+                Some("us-west-2")
+            ),
+            Ok("http://localhost:9000/maps/naip.map".to_string())
+        );
+        assert_eq!(
+            object_url("https://example.com/naip.map", Some("http://minio"), None),
+            Ok("https://example.com/naip.map".to_string())
+        );
+        assert!(object_url("s3://maps", None, None).is_err());
+        assert!(object_url("s3:///naip.map", None, None).is_err());
+        assert!(object_url("ftp://example.com/naip.map", None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_mapfile() {
+        let addr = serve_mapfile("MAP NAME '{timestamp}' END");
+
+        let url = format!("http://{}/maps/naip.map", addr);
+        let mapfile = fetch_mapfile(&url, None, None).await.unwrap();
+        assert_eq!(mapfile, "MAP NAME '{timestamp}' END");
+        assert!(Map::try_from(mapfile.replace("{timestamp}", "1")).is_ok());
+
+        let endpoint = format!("http://{}", addr);
+        let mapfile = fetch_mapfile("s3://maps/naip.map", Some(&endpoint), None)
+            .await
+            .unwrap();
+        assert_eq!(mapfile, "MAP NAME '{timestamp}' END");
+
+        let missing = format!("http://{}/maps/missing.map", addr);
+        assert_eq!(
+            fetch_mapfile(&missing, None, None).await,
+            Err(FetchError::Status(404))
+        );
+    }
+}
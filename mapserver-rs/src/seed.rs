@@ -0,0 +1,298 @@
+//! Pre-render the tiles covering a region for offline and edge deployments,
+//! into a `{z}/{x}/{y}.png` directory tree or, with the `mbtiles` feature, an MBTiles archive.
+//!
+//! Tiles are rendered a metatile at a time and tiles already stored are skipped,
+//! so an interrupted seed can be resumed by running it again.
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use super::coordinates::{Tile, MAX_ZOOM};
+use super::mappool::{Map, MapError, RenderError};
+use super::template::MapfileTemplate;
+use super::Extent;
+
+/// Tiles across each metatile rendered while seeding
+pub const SEED_METATILE_SIZE: u32 = 4;
+
+///
+/// What to seed
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedOptions {
+    /// Region to cover, in epsg:3857
+    pub extent: Extent,
+    pub min_zoom: u32,
+    pub max_zoom: u32,
+    /// Substituted for `{timestamp}` in the mapfile template
+    pub timestamp: i64,
+}
+
+///
+/// Tiles seeded so far, out of the total covering the region
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeedProgress {
+    pub rendered: usize,
+    /// Tiles that were already stored
+    pub skipped: usize,
+    pub total: usize,
+}
+
+///
+/// Errors seeding tiles
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SeedError {
+    /// The zoom range is empty or deeper than MAX_ZOOM
+    InvalidZoom { min_zoom: u32, max_zoom: u32 },
+    /// The rendered mapfile could not be loaded
+    Load(MapError),
+    /// A metatile could not be rendered
+    Render(RenderError),
+    /// A tile could not be read from or written to the store
+    Io { path: PathBuf, message: String },
+}
+
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedError::InvalidZoom { min_zoom, max_zoom } => {
+                write!(f, "Invalid zoom range {}..{}", min_zoom, max_zoom)
+            }
+            SeedError::Load(err) => write!(f, "{}", err),
+            SeedError::Render(err) => write!(f, "{}", err),
+            SeedError::Io { path, message } => {
+                write!(
+                    f,
+                    "Unable to store tiles in {}: {}",
+                    path.display(),
+                    message
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SeedError {}
+
+///
+/// Where seeded tiles are kept
+///
+pub trait TileStore {
+    /// True if the tile was stored by an earlier seed
+    fn contains(&self, tile: &Tile) -> Result<bool, SeedError>;
+
+    fn write(&mut self, tile: &Tile, bytes: &[u8]) -> Result<(), SeedError>;
+
+    /// Persist the tiles written so far, called after each metatile
+    fn flush(&mut self) -> Result<(), SeedError> {
+        Ok(())
+    }
+}
+
+///
+/// Tiles as `{z}/{x}/{y}.png` files under a directory
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryStore {
+    dir: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        DirectoryStore { dir: dir.into() }
+    }
+
+    /// Path of a tile under the directory
+    pub fn tile_path(&self, tile: &Tile) -> PathBuf {
+        self.dir
+            .join(tile.zoom.to_string())
+            .join(tile.x.to_string())
+            .join(format!("{}.png", tile.y))
+    }
+}
+
+impl TileStore for DirectoryStore {
+    fn contains(&self, tile: &Tile) -> Result<bool, SeedError> {
+        Ok(self.tile_path(tile).exists())
+    }
+
+    /// Write through a temporary file, so an interrupted seed never leaves
+    /// a truncated tile behind to be skipped on the next run
+    fn write(&mut self, tile: &Tile, bytes: &[u8]) -> Result<(), SeedError> {
+        let path = self.tile_path(tile);
+        let io_error = |err: std::io::Error| SeedError::Io {
+            path: path.clone(),
+            message: err.to_string(),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_error)?;
+        }
+        let partial = path.with_extension("png.partial");
+        fs::write(&partial, bytes).map_err(io_error)?;
+        fs::rename(&partial, &path).map_err(io_error)
+    }
+}
+
+///
+/// Render every tile covering the extent from `min_zoom` to `max_zoom` into the store,
+/// reporting progress after each metatile. The mapfile's default output format should be PNG
+///
+pub fn seed(
+    template: &MapfileTemplate,
+    options: &SeedOptions,
+    store: &mut impl TileStore,
+    mut progress: impl FnMut(&SeedProgress),
+) -> Result<SeedProgress, SeedError> {
+    if options.min_zoom > options.max_zoom || options.max_zoom > MAX_ZOOM {
+        return Err(SeedError::InvalidZoom {
+            min_zoom: options.min_zoom,
+            max_zoom: options.max_zoom,
+        });
+    }
+    let map = Map::try_from(template.render(options.timestamp)).map_err(SeedError::Load)?;
+
+    let mut seeded = SeedProgress {
+        total: options
+            .extent
+            .tile_count(options.min_zoom, options.max_zoom) as usize,
+        ..Default::default()
+    };
+    for zoom in options.min_zoom..=options.max_zoom {
+        let ranges = options.extent.tile_ranges(zoom);
+        let wanted = |tile: &Tile| {
+            ranges
+                .iter()
+                .any(|(xs, ys)| xs.contains(&tile.x) && ys.contains(&tile.y))
+        };
+
+        // Each metatile is identified by its top left tile
+        let across = Tile::from_zxy(zoom, 0, 0).metatile_size(SEED_METATILE_SIZE);
+        let mut metatiles: Vec<(u32, u32)> = ranges
+            .iter()
+            .flat_map(|(xs, ys)| {
+                let columns = xs.start() / across..=xs.end() / across;
+                (ys.start() / across..=ys.end() / across)
+                    .flat_map(move |y| columns.clone().map(move |x| (y * across, x * across)))
+            })
+            .collect();
+        metatiles.sort_unstable();
+        metatiles.dedup();
+
+        for (y, x) in metatiles {
+            let tile = Tile::from_zxy(zoom, x, y);
+            let members = tile.metatile_tiles(SEED_METATILE_SIZE);
+
+            let mut missing = Vec::new();
+            for member in members.iter().filter(|member| wanted(member)) {
+                if store.contains(member)? {
+                    seeded.skipped += 1;
+                } else {
+                    missing.push(member);
+                }
+            }
+            if !missing.is_empty() {
+                let extent = Extent::from(tile.metatile_extent(SEED_METATILE_SIZE));
+                let metatile = map
+                    .draw_metatile(extent, tile.metatile_size(SEED_METATILE_SIZE))
+                    .map_err(SeedError::Render)?;
+                let images = map.slice_metatile(&metatile).map_err(SeedError::Render)?;
+                for (member, image) in members.iter().zip(images) {
+                    if missing.contains(&member) {
+                        store.write(member, &image.bytes)?;
+                        seeded.rendered += 1;
+                    }
+                }
+                store.flush()?;
+            }
+            progress(&seeded);
+        }
+    }
+    Ok(seeded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seed() {
+        let output_dir = std::env::temp_dir().join(format!("seed-{}", std::process::id()));
+        let mut store = DirectoryStore::new(&output_dir);
+        let options = SeedOptions {
+            extent: Extent::new(100., 100., 200., 200.),
+            min_zoom: 0,
+            max_zoom: 2,
+            timestamp: 0,
+        };
+        let template = MapfileTemplate::new("MAP NAME '{timestamp}' END".to_string());
+
+        let mut reports = 0;
+        let seeded = seed(&template, &options, &mut store, |_| reports += 1).unwrap();
+        assert_eq!(
+            seeded,
+            SeedProgress {
+                rendered: 3,
+                skipped: 0,
+                total: 3
+            }
+        );
+        assert_eq!(reports, 3);
+        for (z, x, y) in [(0, 0, 0), (1, 1, 0), (2, 2, 1)] {
+            let path = output_dir.join(format!("{}/{}/{}.png", z, x, y));
+            assert!(!fs::read(path).unwrap().is_empty());
+        }
+
+        // Seeding again skips the tiles on disk
+        let seeded = seed(&template, &options, &mut store, |_| {}).unwrap();
+        assert_eq!((seeded.rendered, seeded.skipped), (0, 3));
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_seed_antimeridian() {
+        let output_dir = std::env::temp_dir().join(format!("seed-am-{}", std::process::id()));
+        let mut store = DirectoryStore::new(&output_dir);
+        // Just north of the equator, from 19,000km east round to 19,000km west
+        let options = SeedOptions {
+            extent: Extent::new(19_000_000., 100., -19_000_000., 200.),
+            min_zoom: 0,
+            max_zoom: 3,
+            timestamp: 0,
+        };
+        let template = MapfileTemplate::new("MAP NAME '{timestamp}' END".to_string());
+
+        let seeded = seed(&template, &options, &mut store, |_| {}).unwrap();
+        // One tile at zoom 0, then the first and last columns
+        assert_eq!(seeded.total, 1 + 2 + 2 + 2);
+        assert_eq!(seeded.rendered, seeded.total);
+        for (z, x, y) in [(1, 0, 0), (1, 1, 0), (3, 0, 3), (3, 7, 3)] {
+            assert!(output_dir.join(format!("{}/{}/{}.png", z, x, y)).exists());
+        }
+        assert!(!output_dir.join("3/3/3.png").exists());
+
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn test_seed_invalid_zoom() {
+        let options = SeedOptions {
+            extent: Extent::new(100., 100., 200., 200.),
+            min_zoom: 5,
+            max_zoom: 2,
+            timestamp: 0,
+        };
+        let template = MapfileTemplate::new("MAP END".to_string());
+        let mut store = DirectoryStore::new("unused");
+        assert_eq!(
+            seed(&template, &options, &mut store, |_| {}),
+            Err(SeedError::InvalidZoom {
+                min_zoom: 5,
+                max_zoom: 2
+            })
+        );
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Placeholder in a mapfile template replaced with the requested timestamp
+pub const TIMESTAMP_TOKEN: &str = "{timestamp}";
+
+/// Placeholder in a mapfile template replaced with the data source configured at startup
+pub const DATA_TOKEN: &str = "{data}";
+
+/// Rendered mapfiles kept by a `CachedTemplate`, requests mostly share a few timestamps
+pub const CACHED_MAPFILES: usize = 64;
+
+///
+/// A mapfile read from disk, rendered into a concrete mapfile per request
+/// by substituting the `{timestamp}` token. A `{data}` token is filled in once,
+/// with `with_data`, before serving
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapfileTemplate {
+    source: String,
+}
+
+impl MapfileTemplate {
+    pub fn new(source: String) -> Self {
+        MapfileTemplate { source }
+    }
+
+    /// Read a template from a mapfile on disk
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::read_to_string(path).map(MapfileTemplate::new)
+    }
+
+    /// Whether the template has a `{data}` token still to be filled in
+    pub fn uses_data(&self) -> bool {
+        self.source.contains(DATA_TOKEN)
+    }
+
+    /// Substitute a data source, e.g. a TileDB array path, for every `{data}` token.
+    /// The source may itself contain `{timestamp}`, which is then filled in per request
+    ///
+    /// ```
+    /// use mapserver_rs::template::MapfileTemplate;
+    ///
+    /// let template = MapfileTemplate::new("DATA '{data}' NAME '{timestamp}'".to_string())
+    ///     .with_data("s3://arrays/naip-2017")
+    ///     .unwrap();
+    /// assert_eq!(template.render(42), "DATA 's3://arrays/naip-2017' NAME '42'");
+    /// ```
+    pub fn with_data(self, data: &str) -> Result<Self, DataSourceError> {
+        let data = sanitize_data_source(data)?;
+        Ok(MapfileTemplate {
+            source: self.source.replace(DATA_TOKEN, data),
+        })
+    }
+
+    /// Substitute the timestamp into the template
+    ///
+    /// ```
+    /// use mapserver_rs::template::MapfileTemplate;
+    ///
+    /// let template = MapfileTemplate::new("MAP NAME '{timestamp}' END".to_string());
+    /// assert_eq!(template.render(42), "MAP NAME '42' END");
+    /// ```
+    pub fn render(&self, timestamp: i64) -> String {
+        self.source
+            .replace(TIMESTAMP_TOKEN, timestamp.to_string().as_ref())
+    }
+}
+
+///
+/// A template that remembers the mapfiles it rendered, so repeated requests for a
+/// timestamp reuse the string instead of substituting the whole template again.
+/// Once `CACHED_MAPFILES` timestamps are held the cache starts over
+///
+#[derive(Debug)]
+pub struct CachedTemplate {
+    template: MapfileTemplate,
+    rendered: Mutex<HashMap<i64, String>>,
+    renders: AtomicUsize,
+}
+
+impl CachedTemplate {
+    pub fn new(template: MapfileTemplate) -> Self {
+        CachedTemplate {
+            template,
+            rendered: Mutex::new(HashMap::new()),
+            renders: AtomicUsize::new(0),
+        }
+    }
+
+    /// The mapfile for a timestamp, rendering the template only on the first request
+    pub fn render(&self, timestamp: i64) -> String {
+        let mut rendered = self.rendered.lock().unwrap();
+        if let Some(mapfile) = rendered.get(&timestamp) {
+            return mapfile.clone();
+        }
+        if rendered.len() >= CACHED_MAPFILES {
+            rendered.clear();
+        }
+        self.renders.fetch_add(1, Ordering::Relaxed);
+        let mapfile = self.template.render(timestamp);
+        rendered.insert(timestamp, mapfile.clone());
+        mapfile
+    }
+
+    /// How many times the template has been rendered, i.e. cache misses
+    pub fn renders(&self) -> usize {
+        self.renders.load(Ordering::Relaxed)
+    }
+}
+
+// Characters with meaning in a mapfile, which must never reach the template
+const MAPFILE_SIGNIFICANT: &[char] = &['\'', '"', '#', '\\'];
+
+/// Error parsing a request timestamp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimestampParseError {
+    /// Neither an integer nor an RFC3339 datetime
+    Invalid(String),
+    /// Contains quotes, comment markers or control characters such as newlines,
+    /// which could alter the mapfile
+    Unsafe(String),
+}
+
+impl fmt::Display for TimestampParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampParseError::Invalid(value) => write!(
+                f,
+                "Invalid timestamp '{}', expected an integer or an RFC3339 datetime",
+                value
+            ),
+            TimestampParseError::Unsafe(value) => {
+                write!(f, "Timestamp {:?} contains disallowed characters", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TimestampParseError {}
+
+/// Error validating the configured data source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DataSourceError {
+    /// No data source was given
+    Empty,
+    /// Contains quotes, comment markers or control characters such as newlines,
+    /// which could alter the mapfile
+    Unsafe(String),
+}
+
+impl fmt::Display for DataSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataSourceError::Empty => write!(f, "Data source is empty"),
+            DataSourceError::Unsafe(value) => {
+                write!(f, "Data source {:?} contains disallowed characters", value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DataSourceError {}
+
+/// Reject a data source that is empty or could break out of the mapfile string it is
+/// substituted into, with the same characters disallowed as in timestamps
+pub fn sanitize_data_source(value: &str) -> Result<&str, DataSourceError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(DataSourceError::Empty);
+    }
+    if value
+        .chars()
+        .any(|c| c.is_control() || MAPFILE_SIGNIFICANT.contains(&c))
+    {
+        return Err(DataSourceError::Unsafe(value.to_string()));
+    }
+    Ok(value)
+}
+
+/// Parse a request timestamp, either a bare integer used as is or an RFC3339 datetime
+/// converted to milliseconds since the epoch, the unit of TILEDB_TIMESTAMP
+///
+/// ```
+/// use mapserver_rs::template::parse_timestamp;
+///
+/// assert_eq!(parse_timestamp("1650000000000"), Ok(1650000000000));
+/// assert_eq!(parse_timestamp("2022-04-15T05:20:00Z"), Ok(1650000000000));
+/// assert!(parse_timestamp("yesterday").is_err());
+/// ```
+pub fn parse_timestamp(value: &str) -> Result<i64, TimestampParseError> {
+    let value = sanitize_timestamp(value)?.trim();
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    parse_rfc3339(value).ok_or_else(|| TimestampParseError::Invalid(value.to_string()))
+}
+
+/// Reject a raw timestamp with characters that could break out of a mapfile string,
+/// before it is parsed or substituted
+///
+/// ```
+/// use mapserver_rs::template::sanitize_timestamp;
+///
+/// assert!(sanitize_timestamp("2022-04-15T05:20:00Z").is_ok());
+/// assert!(sanitize_timestamp("0' END").is_err());
+/// ```
+pub fn sanitize_timestamp(value: &str) -> Result<&str, TimestampParseError> {
+    if value
+        .chars()
+        .any(|c| c.is_control() || MAPFILE_SIGNIFICANT.contains(&c))
+    {
+        return Err(TimestampParseError::Unsafe(value.to_string()));
+    }
+    Ok(value)
+}
+
+/// Milliseconds since the epoch of `YYYY-MM-DDTHH:MM:SS[.fff](Z|+HH:MM|-HH:MM)`.
+/// Digits of the fraction beyond milliseconds are truncated
+fn parse_rfc3339(value: &str) -> Option<i64> {
+    let digits = |range: std::ops::Range<usize>| {
+        let part = value.get(range)?;
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse::<i64>().ok()
+    };
+    let separator = |index: usize, allowed: &[u8]| {
+        value
+            .as_bytes()
+            .get(index)
+            .filter(|b| allowed.contains(b))
+            .map(|_| ())
+    };
+
+    let (year, month, day) = (digits(0..4)?, digits(5..7)?, digits(8..10)?);
+    separator(4, b"-")?;
+    separator(7, b"-")?;
+    separator(10, b"Tt ")?;
+    let (hour, minute, second) = (digits(11..13)?, digits(14..16)?, digits(17..19)?);
+    separator(13, b":")?;
+    separator(16, b":")?;
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Optional fraction of a second
+    let mut rest = value.get(19..)?;
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        let padded = format!("{:0<3}", &fraction[..len.min(3)]);
+        millis = padded.parse::<i64>().ok()?;
+        rest = &fraction[len..];
+    }
+
+    let offset_minutes = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            let offset = rest.get(1..)?;
+            if offset.len() != 5 || offset.as_bytes()[2] != b':' {
+                return None;
+            }
+            let (hours, minutes) = (
+                offset[..2].parse::<i64>().ok()?,
+                offset[3..].parse::<i64>().ok()?,
+            );
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 60 + minutes)
+        }
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+        - offset_minutes * 60;
+    Some(seconds * 1000 + millis)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date,
+/// from http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_template_from_path() {
+        let path = std::env::temp_dir().join(format!("template-{}.map", std::process::id()));
+        fs::write(&path, "MAP\n  'TILEDB_TIMESTAMP' '{timestamp}'\nEND\n").unwrap();
+
+        let template = MapfileTemplate::from_path(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            template.render(1_600_000_000_000),
+            "MAP\n  'TILEDB_TIMESTAMP' '1600000000000'\nEND\n"
+        );
+        assert_eq!(template.render(-1), "MAP\n  'TILEDB_TIMESTAMP' '-1'\nEND\n");
+
+        assert!(MapfileTemplate::from_path(&path).is_err());
+    }
+
+    #[test]
+    fn test_with_data() {
+        let template = MapfileTemplate::new(
+            "MAP LAYER DATA '{data}' CONNECTIONOPTIONS 'TILEDB_TIMESTAMP' '{timestamp}' END END END"
+                .to_string(),
+        );
+        assert!(template.uses_data());
+
+        let template = template.with_data(" /data/tiledb/naip-2019 ").unwrap();
+        assert!(!template.uses_data());
+        assert_eq!(
+            template.render(7),
+            "MAP LAYER DATA '/data/tiledb/naip-2019' \
+             CONNECTIONOPTIONS 'TILEDB_TIMESTAMP' '7' END END END"
+        );
+
+        let template = MapfileTemplate::new("DATA '{data}'".to_string());
+        assert_eq!(template.clone().with_data(""), Err(DataSourceError::Empty));
+        for data in ["/data/naip' END", "/data/naip\nEND", "/data/#naip"] {
+            assert_eq!(
+                template.clone().with_data(data),
+                Err(DataSourceError::Unsafe(data.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_cached_template() {
+        let cached = CachedTemplate::new(MapfileTemplate::new(
+            "MAP NAME '{timestamp}' END".to_string(),
+        ));
+        for _ in 0..3 {
+            assert_eq!(cached.render(1), "MAP NAME '1' END");
+            assert_eq!(cached.render(2), "MAP NAME '2' END");
+        }
+        assert_eq!(cached.renders(), 2);
+
+        // Starts over once full
+        for timestamp in 0..CACHED_MAPFILES as i64 + 1 {
+            cached.render(timestamp);
+        }
+        assert_eq!(cached.renders(), CACHED_MAPFILES + 1);
+        assert_eq!(cached.rendered.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("2022-04-15T05:20:00Z"), Ok(1650000000000));
+        assert_eq!(
+            parse_timestamp("2022-04-14T23:20:00.250-06:00"),
+            Ok(1650000000250)
+        );
+        assert_eq!(
+            parse_timestamp("2024-02-29T12:00:00+05:30"),
+            Ok(1709188200000)
+        );
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Ok(-1000));
+        assert_eq!(parse_timestamp(" -1 "), Ok(-1));
+
+        for invalid in [
+            "",
+            "now",
+            "2022-04-15",
+            "2022-04-15T05:20:00",
+            "2022-13-15T05:20:00Z",
+            "2023-02-29T05:20:00Z",
+            "2022-04-15T05:20:00.Z",
+            "2022-04-15T05:20:00+0600",
+            "+022-04-15T05:20:00Z",
+        ] {
+            assert!(parse_timestamp(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_sanitize_timestamp() {
+        for malicious in [
+            "0' END LAYER DATA '/etc/passwd' END '",
+            "0\"",
+            "0\nEND",
+            "0\r",
+            "0 # END",
+            "0\\'",
+        ] {
+            assert_eq!(
+                parse_timestamp(malicious),
+                Err(TimestampParseError::Unsafe(malicious.to_string()))
+            );
+        }
+        assert!(sanitize_timestamp("2022-04-14T23:20:00.250-06:00").is_ok());
+    }
+
+    #[test]
+    fn test_epoch_and_rfc3339_render_the_same() {
+        let template = MapfileTemplate::new("MAP 'TILEDB_TIMESTAMP' '{timestamp}' END".to_string());
+        assert_eq!(
+            template.render(parse_timestamp("1650000000000").unwrap()),
+            template.render(parse_timestamp("2022-04-15T05:20:00Z").unwrap())
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::coordinates::Tile;
+use super::mappool::{mapfile_key, RenderedImage};
+
+///
+/// A cached tile along with a logical timestamp of its last use
+///
+#[derive(Debug)]
+struct CacheEntry {
+    image: RenderedImage,
+    last_access: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<(u64, Tile), CacheEntry>,
+    size_bytes: usize,
+    // Incremented on every access, orders entries from least to most recently used
+    clock: u64,
+}
+
+///
+/// An in-memory cache of rendered tiles keyed by mapfile and z/x/y.
+/// Bounded by the total size of the cached images, the least recently used
+/// tiles are evicted first. Safe to share between threads
+///
+#[derive(Debug)]
+pub struct TileCache {
+    state: Mutex<CacheState>,
+    capacity_bytes: usize,
+}
+
+impl TileCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        TileCache {
+            state: Mutex::new(CacheState::default()),
+            capacity_bytes,
+        }
+    }
+
+    /// Look up a cached tile, marking it as recently used
+    pub fn get(&self, mapfile_str: &str, tile: &Tile) -> Option<RenderedImage> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state
+            .entries
+            .get_mut(&(mapfile_key(mapfile_str), tile.clone()))?;
+        entry.last_access = clock;
+        Some(entry.image.clone())
+    }
+
+    /// Cache a tile, evicting least recently used tiles until it fits.
+    /// Images larger than the whole cache are not stored
+    pub fn insert(&self, mapfile_str: &str, tile: &Tile, image: RenderedImage) {
+        let size = image.bytes.len();
+        if size > self.capacity_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let key = (mapfile_key(mapfile_str), tile.clone());
+        if let Some(previous) = state.entries.remove(&key) {
+            state.size_bytes -= previous.image.bytes.len();
+        }
+
+        while state.size_bytes + size > self.capacity_bytes {
+            let lru = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| key.clone());
+            match lru.and_then(|key| state.entries.remove(&key)) {
+                Some(evicted) => state.size_bytes -= evicted.image.bytes.len(),
+                None => break,
+            }
+        }
+
+        state.clock += 1;
+        let last_access = state.clock;
+        state.size_bytes += size;
+        state.entries.insert(key, CacheEntry { image, last_access });
+    }
+
+    /// Return the cached tile, or render, cache and return it on a miss
+    pub fn get_or_render<E>(
+        &self,
+        mapfile_str: &str,
+        tile: &Tile,
+        render: impl FnOnce() -> Result<RenderedImage, E>,
+    ) -> Result<RenderedImage, E> {
+        if let Some(image) = self.get(mapfile_str, tile) {
+            return Ok(image);
+        }
+        let image = render()?;
+        self.insert(mapfile_str, tile, image.clone());
+        Ok(image)
+    }
+
+    /// Number of cached tiles
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total bytes of the cached images
+    pub fn size_bytes(&self) -> usize {
+        self.state.lock().unwrap().size_bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(size: usize) -> RenderedImage {
+        RenderedImage {
+            bytes: vec![0; size],
+            content_type: "image/png".to_string(),
+            empty: false,
+        }
+    }
+
+    #[test]
+    fn test_hit_and_miss() {
+        let cache = TileCache::new(1000);
+        let tile = Tile::from_zxy(7, 26, 48);
+        assert!(cache.get("MAP END", &tile).is_none());
+
+        let mut renders = 0;
+        for _ in 0..2 {
+            let result: Result<_, ()> = cache.get_or_render("MAP END", &tile, || {
+                renders += 1;
+                Ok(image(100))
+            });
+            assert_eq!(result.unwrap().bytes.len(), 100);
+        }
+        assert_eq!(renders, 1);
+
+        // The same tile from a different mapfile is a miss
+        assert!(cache.get("MAP NAME 'other' END", &tile).is_none());
+        assert!(cache.get("MAP END", &Tile::from_zxy(7, 26, 49)).is_none());
+
+        // Render errors are passed through and not cached
+        let result = cache.get_or_render("MAP NAME 'other' END", &tile, || Err("failed"));
+        assert_eq!(result.err(), Some("failed"));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_byte_budget_eviction() {
+        let cache = TileCache::new(300);
+        let (a, b, c) = (
+            Tile::from_zxy(1, 0, 0),
+            Tile::from_zxy(1, 1, 0),
+            Tile::from_zxy(1, 0, 1),
+        );
+        cache.insert("MAP END", &a, image(100));
+        cache.insert("MAP END", &b, image(100));
+        cache.insert("MAP END", &c, image(100));
+        assert_eq!(cache.size_bytes(), 300);
+
+        // Touch a so b is the least recently used
+        assert!(cache.get("MAP END", &a).is_some());
+        cache.insert("MAP END", &Tile::from_zxy(1, 1, 1), image(100));
+        assert!(cache.get("MAP END", &b).is_none());
+        assert!(cache.get("MAP END", &a).is_some());
+        assert!(cache.get("MAP END", &c).is_some());
+        assert_eq!(cache.size_bytes(), 300);
+
+        // A large image evicts as many tiles as needed
+        cache.insert("MAP END", &b, image(250));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.size_bytes(), 250);
+
+        // An image bigger than the whole cache is never stored
+        cache.insert("MAP END", &a, image(301));
+        assert!(cache.get("MAP END", &a).is_none());
+        assert_eq!(cache.size_bytes(), 250);
+    }
+}
@@ -0,0 +1,281 @@
+//!
+//! Parsing of OGC WMS GetMap requests into an extent in epsg:3857 or epsg:4326, image size
+//! and format
+//!
+//! ```
+//! use std::collections::HashMap;
+//! use mapserver_rs::wms::GetMapRequest;
+//!
+//! let params: HashMap<String, String> = [
+//!     ("SERVICE", "WMS"),
+//!     ("REQUEST", "GetMap"),
+//!     ("CRS", "EPSG:3857"),
+//!     ("BBOX", "0,0,1000,1000"),
+//!     ("WIDTH", "256"),
+//!     ("HEIGHT", "256"),
+//!     ("FORMAT", "image/png"),
+//! ]
+//! .iter()
+//! .map(|(k, v)| (k.to_string(), v.to_string()))
+//! .collect();
+//!
+//! let request = GetMapRequest::from_params(&params).unwrap();
+//! assert_eq!((request.width, request.height), (256, 256));
+//! ```
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+
+use super::coordinates::WmsVersion;
+use super::Extent;
+
+/// Errors in a GetMap request, reported to clients as a WMS ServiceException
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WmsError {
+    /// A required parameter is absent
+    MissingParameter(&'static str),
+    /// A parameter could not be parsed
+    InvalidParameter { name: &'static str, value: String },
+    /// The CRS/SRS is not one of EPSG:3857 or EPSG:4326
+    InvalidCrs(String),
+    /// The REQUEST is something other than GetMap
+    OperationNotSupported(String),
+    /// The FORMAT is not an OUTPUTFORMAT of the mapfile
+    InvalidFormat(String),
+}
+
+impl WmsError {
+    /// The ServiceException code from the WMS specification
+    pub fn code(&self) -> &'static str {
+        match self {
+            WmsError::MissingParameter(_) => "MissingParameterValue",
+            WmsError::InvalidParameter { .. } => "InvalidParameterValue",
+            WmsError::InvalidCrs(_) => "InvalidCRS",
+            WmsError::OperationNotSupported(_) => "OperationNotSupported",
+            WmsError::InvalidFormat(_) => "InvalidFormat",
+        }
+    }
+
+    /// A ServiceExceptionReport XML document describing the error
+    pub fn to_xml(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <ServiceExceptionReport version=\"1.3.0\" xmlns=\"http://www.opengis.net/ogc\">\n  \
+             <ServiceException code=\"{}\">{}</ServiceException>\n\
+             </ServiceExceptionReport>\n",
+            self.code(),
+            xml_escape(&self.to_string())
+        )
+    }
+}
+
+impl fmt::Display for WmsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WmsError::MissingParameter(name) => write!(f, "Missing parameter {}", name),
+            WmsError::InvalidParameter { name, value } => {
+                write!(f, "Invalid value for {}: '{}'", name, value)
+            }
+            WmsError::InvalidCrs(crs) => write!(f, "Unsupported CRS {}", crs),
+            WmsError::OperationNotSupported(request) => {
+                write!(f, "Unsupported request {}", request)
+            }
+            WmsError::InvalidFormat(format) => write!(f, "Unsupported format {}", format),
+        }
+    }
+}
+
+impl std::error::Error for WmsError {}
+
+pub(crate) fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// A parsed GetMap request
+#[derive(Debug, Clone, PartialEq)]
+pub struct GetMapRequest {
+    /// The requested bbox in `epsg`, as lon,lat for epsg:4326 whatever the WMS version
+    pub extent: Extent,
+    /// The EPSG code of the requested CRS, 3857 or 4326. Images are drawn in it,
+    /// so a 4326 request gets an equirectangular image
+    pub epsg: u32,
+    pub width: i32,
+    pub height: i32,
+    /// The FORMAT parameter, a MIME type such as `image/png`
+    pub format: Option<String>,
+}
+
+impl GetMapRequest {
+    /// Parse GetMap query parameters. Parameter names are case-insensitive.
+    /// EPSG:4326 bboxes are lat,lon for WMS 1.3.0 `CRS` and lon,lat for 1.1.1 `SRS`
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, WmsError> {
+        let params: HashMap<String, &str> = params
+            .iter()
+            .map(|(k, v)| (k.to_uppercase(), v.as_str()))
+            .collect();
+        let param = |name: &'static str| {
+            params
+                .get(name)
+                .copied()
+                .ok_or(WmsError::MissingParameter(name))
+        };
+
+        if let Some(request) = params.get("REQUEST") {
+            if !request.eq_ignore_ascii_case("GetMap") {
+                return Err(WmsError::OperationNotSupported(request.to_string()));
+            }
+        }
+
+        // 1.3.0 renamed SRS to CRS along with flipping the geographic axis order
+        let (crs, version) = match (params.get("CRS"), params.get("SRS")) {
+            (Some(crs), _) => (*crs, WmsVersion::V1_3_0),
+            (None, Some(srs)) => (*srs, WmsVersion::V1_1_1),
+            (None, None) => return Err(WmsError::MissingParameter("CRS")),
+        };
+
+        let bbox = param("BBOX")?;
+        let coords: Vec<f64> = bbox
+            .split(',')
+            .map(|c| c.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid("BBOX", bbox))?;
+        if coords.len() != 4 {
+            return Err(invalid("BBOX", bbox));
+        }
+
+        let crs_code = crs.to_uppercase();
+        let (extent, epsg) = match crs_code.as_str() {
+            "EPSG:3857" | "EPSG:900913" => (
+                Extent::new(coords[0], coords[1], coords[2], coords[3]),
+                3857,
+            ),
+            "EPSG:4326" | "CRS:84" => {
+                let extent = match (crs_code.as_str(), version) {
+                    ("EPSG:4326", WmsVersion::V1_3_0) => {
+                        Extent::new(coords[1], coords[0], coords[3], coords[2])
+                    }
+                    _ => Extent::new(coords[0], coords[1], coords[2], coords[3]),
+                };
+                (extent, 4326)
+            }
+            _ => return Err(WmsError::InvalidCrs(crs.to_string())),
+        };
+        if !(extent.minx() < extent.maxx() && extent.miny() < extent.maxy()) {
+            return Err(invalid("BBOX", bbox));
+        }
+
+        let size = |name: &'static str| {
+            let value = param(name)?;
+            value
+                .parse::<i32>()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| invalid(name, value))
+        };
+
+        Ok(GetMapRequest {
+            extent,
+            epsg,
+            width: size("WIDTH")?,
+            height: size("HEIGHT")?,
+            format: params.get("FORMAT").map(|f| f.to_string()),
+        })
+    }
+}
+
+fn invalid(name: &'static str, value: &str) -> WmsError {
+    WmsError::InvalidParameter {
+        name,
+        value: value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_getmap_4326_axis_order() {
+        let v130 = GetMapRequest::from_params(&params(&[
+            ("crs", "EPSG:4326"),
+            ("bbox", "38,-106,40,-104"),
+            ("width", "512"),
+            ("height", "256"),
+        ]))
+        .unwrap();
+        let v111 = GetMapRequest::from_params(&params(&[
+            ("SRS", "EPSG:4326"),
+            ("BBOX", "-106,38,-104,40"),
+            ("WIDTH", "512"),
+            ("HEIGHT", "256"),
+        ]))
+        .unwrap();
+        assert_eq!(v130.extent, v111.extent);
+        assert_eq!(v130.extent, Extent::new(-106., 38., -104., 40.));
+        assert_eq!((v130.epsg, v111.epsg), (4326, 4326));
+        assert_eq!((v130.width, v130.height), (512, 256));
+
+        let mercator = GetMapRequest::from_params(&params(&[
+            ("CRS", "EPSG:3857"),
+            ("BBOX", "0,0,1000,1000"),
+            ("WIDTH", "256"),
+            ("HEIGHT", "256"),
+        ]))
+        .unwrap();
+        assert_eq!(mercator.epsg, 3857);
+    }
+
+    #[test]
+    fn test_getmap_errors() {
+        let base = [("CRS", "EPSG:3857"), ("WIDTH", "256"), ("HEIGHT", "256")];
+        assert_eq!(
+            GetMapRequest::from_params(&params(&base)).err(),
+            Some(WmsError::MissingParameter("BBOX"))
+        );
+
+        let mut bad_bbox = base.to_vec();
+        bad_bbox.push(("BBOX", "1,2,3"));
+        assert_eq!(
+            GetMapRequest::from_params(&params(&bad_bbox))
+                .unwrap_err()
+                .code(),
+            "InvalidParameterValue"
+        );
+
+        let mut bad_crs = base.to_vec();
+        bad_crs[0] = ("CRS", "EPSG:2263");
+        bad_crs.push(("BBOX", "0,0,1,1"));
+        assert_eq!(
+            GetMapRequest::from_params(&params(&bad_crs)).err(),
+            Some(WmsError::InvalidCrs("EPSG:2263".to_string()))
+        );
+
+        let mut bad_size = base.to_vec();
+        bad_size[1] = ("WIDTH", "-5");
+        bad_size.push(("BBOX", "0,0,1,1"));
+        assert!(GetMapRequest::from_params(&params(&bad_size)).is_err());
+    }
+
+    #[test]
+    fn test_service_exception_xml() {
+        let xml = WmsError::InvalidParameter {
+            name: "BBOX",
+            value: "<1,2>".to_string(),
+        }
+        .to_xml();
+        assert!(xml.contains("<ServiceException code=\"InvalidParameterValue\">"));
+        assert!(xml.contains("&lt;1,2&gt;"));
+    }
+}
@@ -0,0 +1,268 @@
+//!
+//! OGC WMTS RESTful tiles and the Capabilities document describing them, so desktop
+//! clients such as QGIS and ArcGIS can add the service as a standard WMTS.
+//! Only the `GoogleMapsCompatible` (epsg:3857) tile matrix set is offered
+//!
+//! ```
+//! use mapserver_rs::coordinates::Tile;
+//! use mapserver_rs::wmts::parse_tile;
+//!
+//! let tile = parse_tile("default", "GoogleMapsCompatible", "7", "26", "48.png").unwrap();
+//! assert_eq!(tile, Tile::from_zxy(7, 26, 48));
+//! ```
+//!
+
+use std::fmt;
+
+use super::coordinates::{Tile, TILE_SIZE};
+use super::wms::xml_escape;
+
+/// Identifier of the single layer, the whole map
+pub const LAYER_NAME: &str = "default";
+
+/// Identifier of the epsg:3857 tile matrix set
+pub const GOOGLE_MAPS_COMPATIBLE: &str = "GoogleMapsCompatible";
+
+/// The deepest TileMatrix of the GoogleMapsCompatible well-known scale set
+pub const GOOGLE_MAPS_COMPATIBLE_MAX_ZOOM: u32 = 18;
+
+// The standardized rendering pixel size of 0.28mm, used to turn resolutions into scales
+const PIXEL_SIZE_METERS: f64 = 0.00028;
+
+/// Errors in a WMTS tile request, reported to clients as an OWS ExceptionReport
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WmtsError {
+    /// The layer is not the one this service offers
+    UnknownLayer(String),
+    /// The tile matrix set is not GoogleMapsCompatible
+    UnknownTileMatrixSet(String),
+    /// A TileMatrix, TileCol or TileRow could not be parsed
+    InvalidParameter { name: &'static str, value: String },
+    /// The tile is outside of the tile matrix set
+    TileOutOfRange { zoom: u32, x: u32, y: u32 },
+}
+
+impl WmtsError {
+    /// The exception code from the WMTS specification
+    pub fn code(&self) -> &'static str {
+        match self {
+            WmtsError::TileOutOfRange { .. } => "TileOutOfRange",
+            _ => "InvalidParameterValue",
+        }
+    }
+
+    /// An ExceptionReport XML document describing the error
+    pub fn to_xml(&self) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <ows:ExceptionReport xmlns:ows=\"http://www.opengis.net/ows/1.1\" version=\"1.1.0\">\n  \
+             <ows:Exception exceptionCode=\"{}\">\n    \
+             <ows:ExceptionText>{}</ows:ExceptionText>\n  \
+             </ows:Exception>\n\
+             </ows:ExceptionReport>\n",
+            self.code(),
+            xml_escape(&self.to_string())
+        )
+    }
+}
+
+impl fmt::Display for WmtsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WmtsError::UnknownLayer(layer) => write!(f, "Unknown layer {}", layer),
+            WmtsError::UnknownTileMatrixSet(set) => write!(f, "Unknown TileMatrixSet {}", set),
+            WmtsError::InvalidParameter { name, value } => {
+                write!(f, "Invalid value for {}: '{}'", name, value)
+            }
+            WmtsError::TileOutOfRange { zoom, x, y } => {
+                write!(f, "Tile {}/{}/{} is out of range", zoom, x, y)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WmtsError {}
+
+/// Parse the segments of a `/wmts/{layer}/{TileMatrixSet}/{TileMatrix}/{TileCol}/{TileRow}.png`
+/// path into a tile
+pub fn parse_tile(
+    layer: &str,
+    tile_matrix_set: &str,
+    tile_matrix: &str,
+    tile_col: &str,
+    tile_row: &str,
+) -> Result<Tile, WmtsError> {
+    if layer != LAYER_NAME {
+        return Err(WmtsError::UnknownLayer(layer.to_string()));
+    }
+    if tile_matrix_set != GOOGLE_MAPS_COMPATIBLE {
+        return Err(WmtsError::UnknownTileMatrixSet(tile_matrix_set.to_string()));
+    }
+
+    let index = |name: &'static str, value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| WmtsError::InvalidParameter {
+                name,
+                value: value.to_string(),
+            })
+    };
+    let zoom = index("TileMatrix", tile_matrix)?;
+    let x = index("TileCol", tile_col)?;
+    let y = index("TileRow", tile_row.strip_suffix(".png").unwrap_or(tile_row))?;
+
+    let out_of_range = WmtsError::TileOutOfRange { zoom, x, y };
+    if zoom > GOOGLE_MAPS_COMPATIBLE_MAX_ZOOM {
+        return Err(out_of_range);
+    }
+    Tile::try_from_zxy(zoom, x, y).map_err(|_| out_of_range)
+}
+
+/// A WMTS 1.0.0 Capabilities document for the map, with tile URLs under `base_url`
+/// for a mapfile timestamp. Bounds are (west, south, east, north) in WGS84
+pub fn capabilities_xml(base_url: &str, timestamp: i64, bounds: [f64; 4], format: &str) -> String {
+    let template = format!(
+        "{}/wmts/{}/{{TileMatrixSet}}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png?timestamp={}",
+        base_url, LAYER_NAME, timestamp
+    );
+    let [west, south, east, north] = bounds;
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+  <ows:ServiceIdentification>
+    <ows:Title>mapserver-rs</ows:Title>
+    <ows:ServiceType>OGC WMTS</ows:ServiceType>
+    <ows:ServiceTypeVersion>1.0.0</ows:ServiceTypeVersion>
+  </ows:ServiceIdentification>
+  <Contents>
+    <Layer>
+      <ows:Title>{layer}</ows:Title>
+      <ows:WGS84BoundingBox>
+        <ows:LowerCorner>{west} {south}</ows:LowerCorner>
+        <ows:UpperCorner>{east} {north}</ows:UpperCorner>
+      </ows:WGS84BoundingBox>
+      <ows:Identifier>{layer}</ows:Identifier>
+      <Style isDefault="true">
+        <ows:Identifier>default</ows:Identifier>
+      </Style>
+      <Format>{format}</Format>
+      <TileMatrixSetLink>
+        <TileMatrixSet>{set}</TileMatrixSet>
+      </TileMatrixSetLink>
+      <ResourceURL format="{format}" resourceType="tile" template="{template}"/>
+    </Layer>
+{tile_matrix_set}  </Contents>
+  <ServiceMetadataURL xlink:href="{capabilities}"/>
+</Capabilities>
+"#,
+        layer = LAYER_NAME,
+        set = GOOGLE_MAPS_COMPATIBLE,
+        format = xml_escape(format),
+        template = xml_escape(&template),
+        tile_matrix_set = google_maps_compatible(),
+        capabilities = xml_escape(&format!("{}/wmts/capabilities.xml", base_url)),
+    )
+}
+
+/// The TileMatrixSet element, one TileMatrix per zoom level
+fn google_maps_compatible() -> String {
+    let (minx, _, _, maxy) = Tile::from_zxy(0, 0, 0).bbox_mercator();
+    let mut xml = format!(
+        "    <TileMatrixSet>\n      \
+         <ows:Identifier>{}</ows:Identifier>\n      \
+         <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>\n      \
+         <WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>\n",
+        GOOGLE_MAPS_COMPATIBLE
+    );
+    for zoom in 0..=GOOGLE_MAPS_COMPATIBLE_MAX_ZOOM {
+        let scale = Tile::from_zxy(zoom, 0, 0).resolution(TILE_SIZE) / PIXEL_SIZE_METERS;
+        let across = 1u32 << zoom;
+        xml.push_str(&format!(
+            "      <TileMatrix>\n        \
+             <ows:Identifier>{zoom}</ows:Identifier>\n        \
+             <ScaleDenominator>{scale}</ScaleDenominator>\n        \
+             <TopLeftCorner>{minx} {maxy}</TopLeftCorner>\n        \
+             <TileWidth>{size}</TileWidth>\n        \
+             <TileHeight>{size}</TileHeight>\n        \
+             <MatrixWidth>{across}</MatrixWidth>\n        \
+             <MatrixHeight>{across}</MatrixHeight>\n      \
+             </TileMatrix>\n",
+            zoom = zoom,
+            scale = scale,
+            minx = minx,
+            maxy = maxy,
+            size = TILE_SIZE,
+            across = across,
+        ));
+    }
+    xml.push_str("    </TileMatrixSet>\n");
+    xml
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_tile() {
+        assert_eq!(
+            parse_tile("default", "GoogleMapsCompatible", "0", "0", "0.png"),
+            Ok(Tile::from_zxy(0, 0, 0))
+        );
+        assert_eq!(
+            parse_tile("roads", "GoogleMapsCompatible", "0", "0", "0.png"),
+            Err(WmtsError::UnknownLayer("roads".to_string()))
+        );
+        assert_eq!(
+            parse_tile("default", "EPSG:4326", "0", "0", "0.png"),
+            Err(WmtsError::UnknownTileMatrixSet("EPSG:4326".to_string()))
+        );
+        assert_eq!(
+            parse_tile("default", "GoogleMapsCompatible", "7", "x", "48.png")
+                .unwrap_err()
+                .code(),
+            "InvalidParameterValue"
+        );
+        assert_eq!(
+            parse_tile("default", "GoogleMapsCompatible", "2", "4", "0.png")
+                .unwrap_err()
+                .code(),
+            "TileOutOfRange"
+        );
+        assert_eq!(
+            parse_tile("default", "GoogleMapsCompatible", "19", "0", "0.png")
+                .unwrap_err()
+                .code(),
+            "TileOutOfRange"
+        );
+    }
+
+    #[test]
+    fn test_capabilities_xml() {
+        let xml = capabilities_xml(
+            "http://tiles.example.com",
+            1650000000000,
+            [-106., 38., -104., 40.],
+            "image/png",
+        );
+        assert!(xml.contains(
+            "template=\"http://tiles.example.com/wmts/default/{TileMatrixSet}/{TileMatrix}\
+             /{TileCol}/{TileRow}.png?timestamp=1650000000000\""
+        ));
+        assert!(xml.contains("<ows:LowerCorner>-106 38</ows:LowerCorner>"));
+        assert_eq!(
+            xml.matches("<TileMatrix>").count(),
+            GOOGLE_MAPS_COMPATIBLE_MAX_ZOOM as usize + 1
+        );
+        // The well-known scale of zoom 0
+        assert!(xml.contains("<ScaleDenominator>559082264.02"));
+        assert!(xml.contains("<MatrixWidth>262144</MatrixWidth>"));
+    }
+
+    #[test]
+    fn test_exception_xml() {
+        let xml = WmtsError::UnknownLayer("<roads>".to_string()).to_xml();
+        assert!(xml.contains("exceptionCode=\"InvalidParameterValue\""));
+        assert!(xml.contains("&lt;roads&gt;"));
+    }
+}
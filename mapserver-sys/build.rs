@@ -3,13 +3,90 @@ extern crate fs_extra;
 use std::env;
 use std::path::PathBuf;
 
+// Functions mapserver-rs calls. The structs they take, e.g. mapObj, are generated along with them.
+// Enable the `full-bindings` feature to generate the whole ms* API instead
+const CORE_FUNCTIONS: &[&str] = &[
+    "msSetup",
+    "msLoadMapFromString",
+    "msFreeMap",
+    "msMapSetExtent",
+    "msMapSetSize",
+    "msSelectOutputFormat",
+    "msLoadProjectionString",
+    "msGetProjectionString",
+    "msFreeProjection",
+    "msLayerGetExtent",
+    "msInitQuery",
+    "msQueryByPoint",
+    "msQueryByRect",
+    "msLayerOpen",
+    "msLayerClose",
+    "msLayerGetItems",
+    "msLayerGetShape",
+    "msLayerGetProcessingKey",
+    "msLayerSetProcessingKey",
+    "msSetConfigOption",
+    "msGetConfigOption",
+    "msApplyMapConfigOptions",
+    "msInitShape",
+    "msFreeShape",
+    "msDrawMap",
+    "msFreeImage",
+    "msSaveImageBuffer",
+    "msSaveRasterBufferToBuffer",
+    "msMVTWriteTile",
+    "msOGRWriteFromQuery",
+    "msIO_installStdoutToBuffer",
+    "msIO_getStdoutBufferBytes",
+    "msIO_resetHandlers",
+    "msBufferFree",
+    "msFree",
+    "msGetErrorObj",
+    "msGetErrorString",
+    "msResetErrorList",
+    "msCleanup",
+    "msDebugCleanup",
+    "msGDALCleanup",
+    "msOGRCleanup",
+    "msIO_Cleanup",
+    "msSetPROJ_DATA",
+    "msProjectionContextPoolCleanup",
+];
+
+// A small margin of functions likely to be needed next, cheap to generate
+const EXTRA_FUNCTIONS: &[&str] = &[
+    "msLoadMap",
+    "msGetVersion",
+    "msGetVersionInt",
+    "msProjectRect",
+    "msOGCWKT2ProjectionObj",
+];
+
+// Not ms-prefixed, so listed even with `full-bindings`
+const UNPREFIXED_FUNCTIONS: &[&str] = &["GetMapserverUnitUsingProj"];
+
+// Query results are reached through layerObj, list them so they stay generated in full
+const CORE_TYPES: &[&str] = &["resultCacheObj", "resultObj", "shapeObj"];
+
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
 
     println!("cargo:rustc-link-lib=dylib=mapserver");
-    let bindings = bindgen::Builder::default()
-        .header("wrapper.hpp")
-        .allowlist_function("ms.*")
+    let mut builder = bindgen::Builder::default().header("wrapper.hpp");
+    for function in UNPREFIXED_FUNCTIONS {
+        builder = builder.allowlist_function(function);
+    }
+    if env::var_os("CARGO_FEATURE_FULL_BINDINGS").is_some() {
+        builder = builder.allowlist_function("ms.*");
+    } else {
+        for function in CORE_FUNCTIONS.iter().chain(EXTRA_FUNCTIONS) {
+            builder = builder.allowlist_function(function);
+        }
+        for type_name in CORE_TYPES {
+            builder = builder.allowlist_type(type_name);
+        }
+    }
+    let bindings = builder
         .clang_args(vec![format!("-I{}/dist/include", &out_dir)])
         .generate()
         .expect("Unable to generate bindings");
@@ -9,9 +9,9 @@ include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 #[cfg(test)]
 mod test {
-    use super::msLoadMapFromString;
+    use super::{msFree, msGetErrorObj, msGetErrorString, msLoadMapFromString, msResetErrorList};
 
-    use std::ffi::CString;
+    use std::ffi::{CStr, CString};
     use std::os::raw::c_char;
 
     #[test]
@@ -26,4 +26,57 @@ mod test {
             let map = msLoadMapFromString(buffer, new_mappath);
         }
     }
+
+    #[test]
+    fn core_symbols() {
+        // Fails to compile if a function mapserver-rs relies on drops out of the allowlist
+        let symbols: &[*const ()] = &[
+            super::msSetup as *const (),
+            super::msLoadMapFromString as *const (),
+            super::msFreeMap as *const (),
+            super::msMapSetExtent as *const (),
+            super::msMapSetSize as *const (),
+            super::msSelectOutputFormat as *const (),
+            super::msLoadProjectionString as *const (),
+            super::GetMapserverUnitUsingProj as *const (),
+            super::msLayerGetExtent as *const (),
+            super::msQueryByPoint as *const (),
+            super::msQueryByRect as *const (),
+            super::msLayerGetShape as *const (),
+            super::msLayerSetProcessingKey as *const (),
+            super::msSetConfigOption as *const (),
+            super::msApplyMapConfigOptions as *const (),
+            super::msDrawMap as *const (),
+            super::msFreeImage as *const (),
+            super::msSaveImageBuffer as *const (),
+            super::msSaveRasterBufferToBuffer as *const (),
+            super::msMVTWriteTile as *const (),
+            super::msOGRWriteFromQuery as *const (),
+            super::msIO_getStdoutBufferBytes as *const (),
+            super::msBufferFree as *const (),
+            super::msGetErrorString as *const (),
+            super::msCleanup as *const (),
+        ];
+        assert!(symbols.iter().all(|symbol| !symbol.is_null()));
+    }
+
+    #[test]
+    fn error_string() {
+        unsafe {
+            msResetErrorList();
+            let mapfile_cstr = CString::new("NOT A MAPFILE").unwrap();
+            let buffer = mapfile_cstr.as_ptr() as *mut c_char;
+            let map = msLoadMapFromString(buffer, std::ptr::null_mut() as *mut c_char);
+            assert!(map.is_null());
+            assert_ne!((*msGetErrorObj()).code, 0);
+
+            let delimiter = CString::new("; ").unwrap();
+            let error_ptr = msGetErrorString(delimiter.as_ptr());
+            assert!(!CStr::from_ptr(error_ptr).to_bytes().is_empty());
+            msFree(error_ptr as *mut libc::c_void);
+
+            msResetErrorList();
+            assert_eq!((*msGetErrorObj()).code, 0);
+        }
+    }
 }